@@ -0,0 +1,620 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use cpal::SupportedBufferSize;
+
+use crate::config::{InstrumentConfig, ConfigError};
+use crate::instrument::Instrument;
+use crate::ui::Application;
+use crate::project::{Project, ProjectError, Problem, ProblemKind};
+use crate::sequencer::{
+    Sequencer, SequencerController, SequencerControlMessage, QueueFullError, Playhead, PlayheadState,
+    PlayheadDirection, JunctionType, MAX_SCENES
+};
+use crate::sound::{
+    Sound, SoundBank, Float, MasterBus, MasterBusConfig, MasterBusMeter, BounceConfig, BounceFormat,
+    BounceLength, OutputConfigError, bounce_to_wav_file, output_devices
+};
+
+
+// Renders that run until the patch stops are cut off here, a patch can loop
+// forever
+const DEFAULT_MAX_SECONDS: f64 = 600.0;
+
+pub const USAGE: &str = "\
+usage:
+    state_machine [--config PATH]            open the instrument
+    state_machine render PROJECT -o OUTPUT [options]
+    state_machine info PROJECT
+    state_machine validate PROJECT
+    state_machine devices                    list output devices
+
+render options:
+    -o, --output PATH           WAV file to write
+    --play CHANNEL[:LOCATION]   start a playhead, LOCATION in frames (repeatable)
+    --play-left CHANNEL[:LOCATION]
+                                the same, playing right to left
+    --scene INDEX               start from a scene saved in the project
+    --clock                     start the control loop so that triggers fire
+    --seed SEED                 seed for random jumps
+    --frames FRAMES             render exactly this many frames
+    --seconds SECONDS           render exactly this many seconds
+    --max-seconds SECONDS       otherwise render until every playhead stops, but
+                                no longer than this (default 600)
+    --format FORMAT             int16, int24, int32 or float32 (default int24)
+    --sample-rate RATE          render at this rate instead of the project's,
+                                everything in the project is moved to it and
+                                --play locations are at this rate
+
+Channels, scenes and sounds count from 0, as in the project file.
+
+Without --config the instrument reads state_machine/config.toml from
+$XDG_CONFIG_HOME (~/.config) or $XDG_CONFIG_DIRS (/etc/xdg), if there is one.";
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub project_path: PathBuf,
+    pub output_path: PathBuf,
+    pub playheads: Vec<(usize, Playhead)>,
+    pub scene: Option<usize>,
+    pub clock: bool,
+    pub seed: Option<u64>,
+    pub length: RenderLength,
+    pub format: BounceFormat,
+    // The project's own rate when not given
+    pub sample_rate: Option<usize>
+}
+
+// Seconds are converted once the project's sample rate is known
+#[derive(Debug, Clone, Copy)]
+pub enum RenderLength {
+    Frames(u64),
+    Seconds(f64),
+    UntilStopped {
+        max_seconds: f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    // Opens the window, the configuration file is searched for when no path
+    // is given
+    Instrument {
+        config_path: Option<PathBuf>
+    },
+    Render(RenderOptions),
+    Info {
+        project_path: PathBuf
+    },
+    Validate {
+        project_path: PathBuf
+    },
+    Devices,
+    Help
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Config(ConfigError),
+    Output(OutputConfigError),
+    Project(ProjectError),
+    Render(hound::Error),
+    Queue(Box<QueueFullError>),
+    // Validation found this many problems, they have already been printed
+    Invalid(usize)
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            CliError::Config(error) => write!(f, "{}", error),
+            CliError::Output(error) => write!(f, "{}", error),
+            CliError::Project(error) => write!(f, "{}", error),
+            CliError::Render(error) => write!(f, "could not write output: {}", error),
+            CliError::Queue(error) => write!(f, "could not set up the render: {}", error),
+            CliError::Invalid(count) => write!(f, "{} problem{} found", count, if *count == 1 { "" } else { "s" })
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<ConfigError> for CliError {
+    fn from(error: ConfigError) -> Self {
+        CliError::Config(error)
+    }
+}
+
+impl From<ProjectError> for CliError {
+    fn from(error: ProjectError) -> Self {
+        CliError::Project(error)
+    }
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 2,
+            _ => 1
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(args: &[String]) -> Result<Command, CliError> {
+        let Some((command, args)) = args.split_first() else {
+            return Ok(Command::Instrument { config_path: None });
+        };
+        let command = match command.as_str() {
+            "--config" => Command::Instrument { config_path: Some(parse_config_path(args)?) },
+            "render" => Command::Render(parse_render_options(args)?),
+            "info" => Command::Info { project_path: parse_project_path(args)? },
+            "validate" => Command::Validate { project_path: parse_project_path(args)? },
+            "devices" if args.is_empty() => Command::Devices,
+            "devices" => return Err(CliError::Usage(String::from("devices takes no arguments"))),
+            "help" | "-h" | "--help" => Command::Help,
+            command => return Err(CliError::Usage(format!("unknown command {}", command)))
+        };
+        Ok(command)
+    }
+
+    pub fn run(&self) -> Result<(), CliError> {
+        match self {
+            Command::Instrument { config_path } => {
+                Instrument::run(InstrumentConfig::load(config_path.as_deref())?);
+                Ok(())
+            },
+            Command::Render(options) => render(options),
+            Command::Info { project_path } => info(project_path),
+            Command::Validate { project_path } => validate(project_path),
+            Command::Devices => devices(),
+            Command::Help => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn parse_project_path(args: &[String]) -> Result<PathBuf, CliError> {
+    match args {
+        [path] => Ok(PathBuf::from(path)),
+        [] => Err(CliError::Usage(String::from("missing project file"))),
+        _ => Err(CliError::Usage(String::from("expected only a project file")))
+    }
+}
+
+fn parse_config_path(args: &[String]) -> Result<PathBuf, CliError> {
+    match args {
+        [path] => Ok(PathBuf::from(path)),
+        [] => Err(CliError::Usage(String::from("--config needs a value"))),
+        _ => Err(CliError::Usage(String::from("expected only a configuration file")))
+    }
+}
+
+fn parse_render_options(args: &[String]) -> Result<RenderOptions, CliError> {
+    let mut project_path = None;
+    let mut output_path = None;
+    let mut playheads = Vec::new();
+    let mut scene = None;
+    let mut clock = false;
+    let mut seed = None;
+    let mut length = None;
+    let mut max_seconds = DEFAULT_MAX_SECONDS;
+    let mut format = BounceFormat::Int24;
+    let mut sample_rate = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().ok_or_else(|| CliError::Usage(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => output_path = Some(PathBuf::from(value()?)),
+            "--play" => playheads.push(parse_playhead(value()?, PlayheadDirection::Right)?),
+            "--play-left" => playheads.push(parse_playhead(value()?, PlayheadDirection::Left)?),
+            "--scene" => {
+                let index = parse_number(arg, value()?)?;
+                if index >= MAX_SCENES {
+                    return Err(CliError::Usage(format!("scene {} is past the last slot ({})", index, MAX_SCENES - 1)));
+                }
+                scene = Some(index);
+            },
+            "--clock" => clock = true,
+            "--seed" => seed = Some(parse_number(arg, value()?)?),
+            "--frames" => length = Some(RenderLength::Frames(parse_number(arg, value()?)?)),
+            "--seconds" => length = Some(RenderLength::Seconds(parse_number(arg, value()?)?)),
+            "--max-seconds" => max_seconds = parse_number(arg, value()?)?,
+            "--format" => format = parse_format(value()?)?,
+            "--sample-rate" => {
+                let rate = parse_number(arg, value()?)?;
+                if rate == 0 {
+                    return Err(CliError::Usage(String::from("--sample-rate must be above 0")));
+                }
+                sample_rate = Some(rate);
+            },
+            option if option.starts_with('-') => {
+                return Err(CliError::Usage(format!("unknown option {}", option)));
+            },
+            path if project_path.is_none() => project_path = Some(PathBuf::from(path)),
+            path => return Err(CliError::Usage(format!("unexpected argument {}", path)))
+        }
+    }
+
+    if playheads.is_empty() && scene.is_none() && !clock {
+        return Err(CliError::Usage(String::from("nothing to play, give --play, --scene or --clock")));
+    }
+    Ok(RenderOptions {
+        project_path: project_path.ok_or_else(|| CliError::Usage(String::from("missing project file")))?,
+        output_path: output_path.ok_or_else(|| CliError::Usage(String::from("missing --output")))?,
+        playheads,
+        scene,
+        clock,
+        seed,
+        length: length.unwrap_or(RenderLength::UntilStopped { max_seconds }),
+        format,
+        sample_rate
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, CliError> {
+    value.parse().map_err(|_| CliError::Usage(format!("{} expects a number, got {}", option, value)))
+}
+
+fn parse_playhead(value: &str, direction: PlayheadDirection) -> Result<(usize, Playhead), CliError> {
+    let (channel_index, location) = match value.split_once(':') {
+        Some((channel_index, location)) => (channel_index, parse_number("--play", location)?),
+        None => (value, 0)
+    };
+    let playhead = Playhead {
+        state: PlayheadState::Playing,
+        location,
+        direction
+    };
+    Ok((parse_number("--play", channel_index)?, playhead))
+}
+
+fn parse_format(value: &str) -> Result<BounceFormat, CliError> {
+    match value {
+        "int16" => Ok(BounceFormat::Int16),
+        "int24" => Ok(BounceFormat::Int24),
+        "int32" => Ok(BounceFormat::Int32),
+        "float32" => Ok(BounceFormat::Float32),
+        _ => Err(CliError::Usage(format!("unknown format {}", value)))
+    }
+}
+
+fn render(options: &RenderOptions) -> Result<(), CliError> {
+    // Anything validate rejects could take the engine down
+    let (project, sounds) = match Project::load_for_playback(&options.project_path, options.sample_rate) {
+        Ok(loaded) => loaded,
+        Err(ProjectError::Invalid(problems)) => {
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            return Err(CliError::Invalid(problems.len()));
+        },
+        Err(error) => return Err(error.into())
+    };
+    let sample_rate = project.sample_rate;
+    let num_channels = project.channels.len();
+    for (channel_index, playhead) in &options.playheads {
+        match project.channels.get(*channel_index) {
+            None => return Err(CliError::Usage(format!(
+                "--play channel {} is not in the project ({} channels)",
+                channel_index,
+                num_channels
+            ))),
+            Some(channel) if playhead.location >= channel.length => return Err(CliError::Usage(format!(
+                "--play location {} is past the end of channel {}",
+                playhead.location,
+                channel_index
+            ))),
+            _ => {}
+        }
+    }
+    if let Some(index) = options.scene {
+        if !project.scenes.iter().any(|scene| scene.index == index) {
+            return Err(CliError::Usage(format!("scene {} is not in the project", index)));
+        }
+    }
+
+    // Sounds keep their slots, clips refer to them by index
+    let (mut sound_bank_controller, sound_bank) = SoundBank::new(Vec::new());
+    for (index, sound) in sounds.into_iter().enumerate() {
+        if sound.is_some() {
+            sound_bank_controller.set_sound(index, sound);
+        }
+    }
+    let (mut controller, mut sequencer) = Sequencer::new(sound_bank, sample_rate);
+    sequencer.load_project(&project);
+
+    // Everything below, and the sounds, is applied on the first frame
+    if let Some(seed) = options.seed {
+        send(&mut controller, SequencerControlMessage::SetRandomSeed { seed })?;
+    }
+    if let Some(index) = options.scene {
+        send(&mut controller, SequencerControlMessage::RecallScene { index, quantize: false })?;
+    }
+    for (channel_index, playhead) in &options.playheads {
+        send(&mut controller, SequencerControlMessage::LaunchPlayhead {
+            channel_index: *channel_index,
+            playhead: *playhead
+        })?;
+    }
+    if options.clock {
+        send(&mut controller, SequencerControlMessage::SetClockPlaying { playing: true })?;
+    }
+
+    let seconds_to_frames = |seconds: f64| (seconds.max(0.0) * sample_rate as f64).round() as u64;
+    let length = match options.length {
+        RenderLength::Frames(frames) => BounceLength::Frames(frames),
+        RenderLength::Seconds(seconds) => BounceLength::Frames(seconds_to_frames(seconds)),
+        RenderLength::UntilStopped { max_seconds } => BounceLength::UntilFinished {
+            max_frames: seconds_to_frames(max_seconds)
+        }
+    };
+    let config = BounceConfig {
+        sample_rate,
+        format: options.format,
+        length
+    };
+    let mut master_bus = MasterBus::new(
+        sequencer,
+        &MasterBusConfig::default(),
+        sample_rate,
+        MasterBusMeter::default()
+    );
+    let frames = bounce_to_wav_file(&mut master_bus, &options.output_path, &config)
+        .map_err(CliError::Render)?;
+    println!(
+        "rendered {} frames ({:.2} s) to {}",
+        frames,
+        frames as f64 / sample_rate as f64,
+        options.output_path.display()
+    );
+    Ok(())
+}
+
+// Only a handful of messages are sent before the first frame, far fewer than
+// the ring buffer holds
+fn send(controller: &mut SequencerController, message: SequencerControlMessage) -> Result<(), CliError> {
+    controller.send(message).map_err(|error| CliError::Queue(Box::new(error)))
+}
+
+fn info(project_path: &Path) -> Result<(), CliError> {
+    let project = Project::load(project_path)?;
+    let seconds = |frames: u64| frames as f64 / project.sample_rate.max(1) as f64;
+
+    println!("version {}", project.version);
+    println!("sample rate {} Hz", project.sample_rate);
+    println!("tempo {} bpm, loop of {} beats", project.bpm, project.loop_length);
+
+    println!("{} sounds", project.sounds.len());
+    for sound in &project.sounds {
+        let path = sound.resolve(project_path);
+        match Sound::<Float>::from_wav_file(&path, project.sample_rate) {
+            Ok(loaded) => println!(
+                "    {}: {} ({:.2} s)",
+                sound.index,
+                sound.path.display(),
+                seconds(loaded.metadata.length as u64)
+            ),
+            Err(error) => println!("    {}: {} ({})", sound.index, sound.path.display(), error)
+        }
+    }
+
+    println!("{} channels", project.channels.len());
+    for (channel_index, channel) in project.channels.iter().enumerate() {
+        let random_jumps = channel.junctions.iter()
+            .filter(|junction| matches!(junction.junction_type, JunctionType::RandomJump { .. }))
+            .count();
+        println!(
+            "    {}: {:.2} s, {:?}, {} voices, {} clips, {} junctions ({} random)",
+            channel_index,
+            seconds(channel.length),
+            channel.boundary_mode,
+            channel.voice_config.limit,
+            channel.clips.len(),
+            channel.junctions.len(),
+            random_jumps
+        );
+    }
+
+    println!("{} triggers", project.triggers.len());
+    println!("{} scenes", project.scenes.len());
+    for scene in &project.scenes {
+        let playheads: usize = scene.channels.iter().map(Vec::len).sum();
+        println!("    {}: {} ({} playheads)", scene.index, scene.name, playheads);
+    }
+    Ok(())
+}
+
+fn validate(project_path: &Path) -> Result<(), CliError> {
+    let project = Project::load(project_path)?;
+    let mut problems = project.problems();
+    // Sounds are loaded one at a time so that every missing file is reported
+    for sound in &project.sounds {
+        let path = sound.resolve(project_path);
+        if let Err(error) = Sound::<Float>::from_wav_file(&path, project.sample_rate) {
+            problems.push(Problem::new(
+                ProblemKind::UnreadableSound,
+                format!("sound {} could not be loaded from {}: {}", sound.index, path.display(), error)
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{} is valid", project_path.display());
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(CliError::Invalid(problems.len()))
+}
+
+fn devices() -> Result<(), CliError> {
+    let devices = output_devices().map_err(CliError::Output)?;
+    if devices.is_empty() {
+        println!("no output devices, the instrument will run without sound");
+    }
+    for device in &devices {
+        println!("{}{}", device.name, if device.is_default { " (default)" } else { "" });
+        if let Some(config) = &device.default_config {
+            println!(
+                "    default: {} channels, {} Hz, {:?}",
+                config.channels(),
+                config.sample_rate().0,
+                config.sample_format()
+            );
+        }
+        for config in &device.configs {
+            let buffer_size = match *config.buffer_size() {
+                SupportedBufferSize::Range { min, max } => format!("buffer {} to {} frames", min, max),
+                SupportedBufferSize::Unknown => String::from("buffer size unknown")
+            };
+            println!(
+                "    {} channels, {} to {} Hz, {:?}, {}",
+                config.channels(),
+                config.min_sample_rate().0,
+                config.max_sample_rate().0,
+                config.sample_format(),
+                buffer_size
+            );
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    fn usage_error(result: Result<RenderOptions, CliError>) -> String {
+        match result {
+            Err(CliError::Usage(message)) => message,
+            Err(error) => panic!("expected a usage error, got {}", error),
+            Ok(options) => panic!("expected a usage error, got {:?}", options)
+        }
+    }
+
+    #[test]
+    fn render_options() {
+        let options = parse_render_options(&args(
+            "song.toml -o out.wav --play 2:480 --play-left 0 --seed 7 --seconds 1.5 --format float32"
+        )).unwrap();
+        assert_eq!(options.project_path, PathBuf::from("song.toml"));
+        assert_eq!(options.output_path, PathBuf::from("out.wav"));
+        assert_eq!(options.playheads.len(), 2);
+        assert_eq!(options.playheads[0].0, 2);
+        assert_eq!(options.playheads[0].1.location, 480);
+        assert_eq!(options.playheads[0].1.direction, PlayheadDirection::Right);
+        assert_eq!(options.playheads[1].0, 0);
+        assert_eq!(options.playheads[1].1.location, 0);
+        assert_eq!(options.playheads[1].1.direction, PlayheadDirection::Left);
+        assert_eq!(options.seed, Some(7));
+        assert!(matches!(options.length, RenderLength::Seconds(seconds) if seconds == 1.5));
+        assert!(matches!(options.format, BounceFormat::Float32));
+    }
+
+    #[test]
+    fn render_runs_until_stopped_by_default() {
+        let options = parse_render_options(&args("song.toml -o out.wav --clock --max-seconds 30")).unwrap();
+        assert!(options.clock);
+        assert!(matches!(
+            options.length,
+            RenderLength::UntilStopped { max_seconds } if max_seconds == 30.0
+        ));
+        assert!(matches!(options.format, BounceFormat::Int24));
+        assert_eq!(options.sample_rate, None);
+
+        let options = parse_render_options(&args("song.toml -o out.wav --clock --sample-rate 44100")).unwrap();
+        assert_eq!(options.sample_rate, Some(44100));
+    }
+
+    #[test]
+    fn render_rejects_bad_values() {
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml -o out.wav --play x"))),
+            "--play expects a number, got x"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml -o out.wav --play 1:y"))),
+            "--play expects a number, got y"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml -o out.wav --clock --frames -1"))),
+            "--frames expects a number, got -1"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml -o out.wav --clock --format mp3"))),
+            "unknown format mp3"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args(&format!("song.toml -o out.wav --scene {}", MAX_SCENES)))),
+            format!("scene {} is past the last slot ({})", MAX_SCENES, MAX_SCENES - 1)
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml -o out.wav --clock --sample-rate 0"))),
+            "--sample-rate must be above 0"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml -o out.wav --clock --loud"))),
+            "unknown option --loud"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml other.toml -o out.wav --clock"))),
+            "unexpected argument other.toml"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml --clock -o"))),
+            "-o needs a value"
+        );
+    }
+
+    #[test]
+    fn render_needs_paths() {
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml --clock"))),
+            "missing --output"
+        );
+        assert_eq!(
+            usage_error(parse_render_options(&args("-o out.wav --clock"))),
+            "missing project file"
+        );
+    }
+
+    #[test]
+    fn render_needs_something_to_play() {
+        assert_eq!(
+            usage_error(parse_render_options(&args("song.toml -o out.wav --seconds 2"))),
+            "nothing to play, give --play, --scene or --clock"
+        );
+    }
+
+    #[test]
+    fn playheads() {
+        let (channel_index, playhead) = parse_playhead("3:100", PlayheadDirection::Right).unwrap();
+        assert_eq!(channel_index, 3);
+        assert_eq!(playhead.location, 100);
+        assert!(matches!(playhead.state, PlayheadState::Playing));
+
+        let (channel_index, playhead) = parse_playhead("5", PlayheadDirection::Left).unwrap();
+        assert_eq!(channel_index, 5);
+        assert_eq!(playhead.location, 0);
+        assert_eq!(playhead.direction, PlayheadDirection::Left);
+
+        assert!(parse_playhead("", PlayheadDirection::Right).is_err());
+        assert!(parse_playhead(":10", PlayheadDirection::Right).is_err());
+        assert!(parse_playhead("1:", PlayheadDirection::Right).is_err());
+        assert!(parse_playhead("-1:10", PlayheadDirection::Right).is_err());
+    }
+}
@@ -1,6 +1,14 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
 use wgpu::Color;
 
-use crate::{sound::{Sound, OutputConfig, Float}, ui::{ApplicationConfig, Style}, instrument::{InstrumentState, Instrument}};
+use crate::{sound::{Sound, OutputConfig, OutputConfigError, OutputPreferences, Float, MAX_SOUNDS}, ui::{ApplicationConfig, Style}, instrument::{InstrumentState, Instrument}};
+use crate::sequencer::interface::Theme;
 
 
 pub const TITLE: &str = "state_machine";
@@ -12,11 +20,35 @@ pub const CLEAR_COLOR: Color = Color {
     a: 1.0
 };
 
+// The configuration file is looked up as <config dir>/state_machine/config.toml
+pub const CONFIG_DIRECTORY: &str = "state_machine";
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+// Loaded when the configuration file names no sample directories
+const DEFAULT_SOUNDS: [&str; 4] = [
+    "assets/samples/kick.wav",
+    "assets/samples/snare.wav",
+    "assets/samples/hihat.wav",
+    "assets/samples/flute.wav"
+];
+
+// Proportion of the window height above the bottom panel
+const TOP_PANEL_DIVIDE: f32 = 0.8;
+
+// Proportion of the window width taken by the side panel
+const SIDE_PANEL_WIDTH: f32 = 0.3;
+
+// Proportion of the side panel taken by the transport, the scene list gets
+// the rest
+const SIDE_PANEL_DIVIDE: f32 = 0.35;
+
 
 #[derive(Default)]
 pub struct InstrumentConfig {
     pub output: OutputConfig,
-    pub sounds: Vec<Sound<Float>>
+    pub sounds: Vec<Sound<Float>>,
+    pub theme: Theme,
+    pub layout: LayoutConfig
 }
 
 impl ApplicationConfig<Instrument> for InstrumentConfig where {
@@ -30,7 +62,348 @@ impl ApplicationConfig<Instrument> for InstrumentConfig where {
 
     fn style(&self) -> Style {
         Style {
-            clear_color: CLEAR_COLOR
+            clear_color: self.theme.background
+        }
+    }
+}
+
+impl InstrumentConfig {
+    // Reads the given configuration file, or the first one found in the XDG
+    // config directories.  Without either everything is left at its default.
+    pub fn load(config_path: Option<&Path>) -> Result<Self, ConfigError> {
+        let user_config = match config_path {
+            Some(path) => UserConfig::load(path)?,
+            None => match config_search_paths().into_iter().find(|path| path.is_file()) {
+                Some(path) => UserConfig::load(&path)?,
+                None => UserConfig::default()
+            }
+        };
+        Self::new(&user_config)
+    }
+
+    pub fn new(user_config: &UserConfig) -> Result<Self, ConfigError> {
+        let theme = user_config.theme.to_theme()?;
+        let layout = user_config.layout;
+        layout.validate()?;
+        let output = OutputConfig::new(&user_config.output)?;
+        let sounds = load_sounds(&user_config.sample_directories, output.sample_rate)?;
+        Ok(Self {
+            output,
+            sounds,
+            theme,
+            layout
+        })
+    }
+}
+
+// The configuration file as written.  Every section and key can be left out,
+// unknown keys are rejected so that typos don't pass silently.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    // Every WAV file in these directories is loaded into the sound bank, in
+    // name order.  Relative paths here and in output.wav_path start at the
+    // configuration file.
+    pub sample_directories: Vec<PathBuf>,
+    pub output: OutputPreferences,
+    pub theme: ThemeConfig,
+    pub layout: LayoutConfig
+}
+
+// Colours as "#rrggbb" or "#rrggbbaa"
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub background: Option<String>,
+    pub clip: Option<String>,
+    pub random_jump: Option<String>,
+    pub junction_flash: Option<String>,
+    pub trigger: Option<String>,
+    pub grid_bar: Option<String>,
+    pub grid_beat: Option<String>,
+    pub grid_subdivision: Option<String>,
+    pub channel_end: Option<String>
+}
+
+// Panel split ratios, each strictly between 0 and 1
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutConfig {
+    pub top_height: f32,
+    pub side_width: f32,
+    pub transport_height: f32
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        Self {
+            top_height: TOP_PANEL_DIVIDE,
+            side_width: SIDE_PANEL_WIDTH,
+            transport_height: SIDE_PANEL_DIVIDE
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        error: io::Error
+    },
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error
+    },
+    Invalid {
+        key: &'static str,
+        message: String
+    },
+    Output(OutputConfigError)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "could not read {}: {}", path.display(), error),
+            ConfigError::Parse { path, error } => write!(f, "could not read configuration file {}: {}", path.display(), error),
+            ConfigError::Invalid { key, message } => write!(f, "invalid configuration value for {}: {}", key, message),
+            ConfigError::Output(error) => write!(f, "invalid output configuration: {}", error)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<OutputConfigError> for ConfigError {
+    fn from(error: OutputConfigError) -> Self {
+        ConfigError::Output(error)
+    }
+}
+
+impl UserConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error
+        })?;
+        let mut user_config: UserConfig = toml::from_str(&contents).map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error
+        })?;
+        if let Some(directory) = path.parent() {
+            for sample_directory in user_config.sample_directories.iter_mut() {
+                *sample_directory = directory.join(&*sample_directory);
+            }
+            if let Some(wav_path) = &mut user_config.output.wav_path {
+                *wav_path = directory.join(&*wav_path);
+            }
+        }
+        Ok(user_config)
+    }
+}
+
+impl ThemeConfig {
+    fn to_theme(&self) -> Result<Theme, ConfigError> {
+        let default = Theme::default();
+        let color = |key: &'static str, value: &Option<String>, default: Color| {
+            value.as_deref().map_or(Ok(default), |value| parse_color(key, value))
+        };
+        Ok(Theme {
+            background: color("theme.background", &self.background, default.background)?,
+            clip: color("theme.clip", &self.clip, default.clip)?,
+            random_jump: color("theme.random_jump", &self.random_jump, default.random_jump)?,
+            junction_flash: color("theme.junction_flash", &self.junction_flash, default.junction_flash)?,
+            trigger: color("theme.trigger", &self.trigger, default.trigger)?,
+            grid_bar: color("theme.grid_bar", &self.grid_bar, default.grid_bar)?,
+            grid_beat: color("theme.grid_beat", &self.grid_beat, default.grid_beat)?,
+            grid_subdivision: color("theme.grid_subdivision", &self.grid_subdivision, default.grid_subdivision)?,
+            channel_end: color("theme.channel_end", &self.channel_end, default.channel_end)?
+        })
+    }
+}
+
+impl LayoutConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (key, value) in [
+            ("layout.top_height", self.top_height),
+            ("layout.side_width", self.side_width),
+            ("layout.transport_height", self.transport_height)
+        ] {
+            if !(value > 0.0 && value < 1.0) {
+                return Err(ConfigError::Invalid {
+                    key,
+                    message: format!("{} is not strictly between 0 and 1", value)
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// $XDG_CONFIG_HOME (or ~/.config) first, then each of $XDG_CONFIG_DIRS (or
+// /etc/xdg)
+pub fn config_search_paths() -> Vec<PathBuf> {
+    let mut directories = Vec::new();
+    match env::var_os("XDG_CONFIG_HOME").filter(|directory| !directory.is_empty()) {
+        Some(directory) => directories.push(PathBuf::from(directory)),
+        None => if let Some(home) = env::var_os("HOME") {
+            directories.push(PathBuf::from(home).join(".config"));
+        }
+    }
+    let config_dirs = env::var_os("XDG_CONFIG_DIRS")
+        .filter(|directories| !directories.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".into());
+    directories.extend(env::split_paths(&config_dirs));
+    // Relative entries are invalid according to the spec and are ignored
+    directories.into_iter()
+        .filter(|directory| directory.is_absolute())
+        .map(|directory| directory.join(CONFIG_DIRECTORY).join(CONFIG_FILE_NAME))
+        .collect()
+}
+
+fn parse_color(key: &'static str, value: &str) -> Result<Color, ConfigError> {
+    let invalid = || ConfigError::Invalid {
+        key,
+        message: format!("\"{}\" is not a colour, expected #rrggbb or #rrggbbaa", value)
+    };
+    let hex = value.strip_prefix('#').ok_or_else(invalid)?;
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let component = |i: usize| {
+        hex.get(i..i + 2).map_or(1.0, |digits| u8::from_str_radix(digits, 16).unwrap() as f64 / 255.0)
+    };
+    Ok(Color {
+        r: component(0),
+        g: component(2),
+        b: component(4),
+        a: component(6)
+    })
+}
+
+fn load_sounds(sample_directories: &[PathBuf], sample_rate: usize) -> Result<Vec<Sound<Float>>, ConfigError> {
+    let paths = if sample_directories.is_empty() {
+        DEFAULT_SOUNDS.iter().map(PathBuf::from).collect()
+    } else {
+        let mut paths = Vec::new();
+        for directory in sample_directories {
+            let io_error = |error| ConfigError::Io {
+                path: directory.clone(),
+                error
+            };
+            let mut directory_paths: Vec<PathBuf> = fs::read_dir(directory)
+                .map_err(io_error)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()
+                .map_err(io_error)?;
+            directory_paths.retain(|path| {
+                path.is_file() && path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("wav"))
+            });
+            directory_paths.sort();
+            paths.extend(directory_paths);
+        }
+        paths
+    };
+
+    // A sound that can't be read is skipped rather than keeping the others
+    // from loading
+    let mut sounds = Vec::new();
+    for path in &paths {
+        if sounds.len() == MAX_SOUNDS {
+            println!("Found {} sounds, only the first {} are loaded", paths.len(), MAX_SOUNDS);
+            break;
+        }
+        match Sound::from_wav_file(path, sample_rate) {
+            Ok(sound) => sounds.push(sound),
+            Err(error) => println!("Skipping sound {}: {}", path.display(), error)
+        }
+    }
+    Ok(sounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_directory() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/config")
+    }
+
+    #[test]
+    fn colours_are_read_as_hex() {
+        let color = parse_color("theme.clip", "#ff0080").unwrap();
+        assert_eq!(color, Color { r: 1.0, g: 0.0, b: 128.0 / 255.0, a: 1.0 });
+        let color = parse_color("theme.clip", "#FFFFFF33").unwrap();
+        assert_eq!(color, Color { r: 1.0, g: 1.0, b: 1.0, a: 0.2 });
+    }
+
+    #[test]
+    fn malformed_colours_are_invalid() {
+        for value in ["ff0080", "#ff008", "#ff00800", "#ff00800000", "#gg0080", "#ff 080", ""] {
+            assert!(
+                matches!(parse_color("theme.clip", value), Err(ConfigError::Invalid { key: "theme.clip", .. })),
+                "{:?}",
+                value
+            );
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn layout_ratios_must_be_strictly_between_0_and_1() {
+        assert!(LayoutConfig::default().validate().is_ok());
+        for (layout, expected_key) in [
+            (LayoutConfig { top_height: 0.0, ..Default::default() }, "layout.top_height"),
+            (LayoutConfig { side_width: 1.0, ..Default::default() }, "layout.side_width"),
+            (LayoutConfig { transport_height: f32::NAN, ..Default::default() }, "layout.transport_height"),
+            (LayoutConfig { top_height: -0.5, ..Default::default() }, "layout.top_height")
+        ] {
+            assert!(
+                matches!(layout.validate(), Err(ConfigError::Invalid { key, .. }) if key == expected_key),
+                "{:?}",
+                layout
+            );
+        }
+    }
+
+    // Every case in one test, the environment is shared between threads
+    #[test]
+    fn search_paths_follow_xdg() {
+        let file = |directory: &str| Path::new(directory).join(CONFIG_DIRECTORY).join(CONFIG_FILE_NAME);
+
+        env::set_var("XDG_CONFIG_HOME", "/home/user/.xdg");
+        env::set_var("XDG_CONFIG_DIRS", "/etc/one:relative:/etc/two");
+        assert_eq!(config_search_paths(), [file("/home/user/.xdg"), file("/etc/one"), file("/etc/two")]);
+
+        // Empty is the same as unset
+        env::set_var("XDG_CONFIG_HOME", "");
+        env::set_var("XDG_CONFIG_DIRS", "");
+        env::set_var("HOME", "/home/user");
+        assert_eq!(config_search_paths(), [file("/home/user/.config"), file("/etc/xdg")]);
+
+        env::remove_var("XDG_CONFIG_HOME");
+        env::remove_var("XDG_CONFIG_DIRS");
+        env::remove_var("HOME");
+        assert_eq!(config_search_paths(), [file("/etc/xdg")]);
+    }
+
+    #[test]
+    fn paths_start_at_the_config_file() {
+        let directory = fixture_directory();
+        let user_config = UserConfig::load(&directory.join(CONFIG_FILE_NAME)).unwrap();
+        assert_eq!(user_config.sample_directories, [directory.join("samples")]);
+        assert_eq!(user_config.output.wav_path, Some(directory.join("render.wav")));
+    }
+
+    #[test]
+    fn unreadable_sounds_are_skipped() {
+        let sounds = load_sounds(&[fixture_directory().join("samples")], 48000).unwrap();
+        let names: Vec<&str> = sounds.iter().map(|sound| sound.metadata.name.as_str()).collect();
+        assert_eq!(names, ["kick", "snare"]);
+    }
+
+    #[test]
+    fn missing_sample_directories_are_an_error() {
+        let result = load_sounds(&[fixture_directory().join("missing")], 48000);
+        assert!(matches!(result, Err(ConfigError::Io { .. })));
+    }
+}
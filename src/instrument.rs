@@ -1,17 +1,26 @@
+use std::path::{Path, PathBuf};
+
+use rfd::FileDialog;
 use wgpu::Color;
 use winit::dpi::PhysicalSize;
 use winit::window::{Window, CursorIcon};
-use winit::event::{WindowEvent, MouseButton, ElementState};
+use winit::event::{WindowEvent, MouseButton, ElementState, KeyboardInput, VirtualKeyCode, ModifiersState};
 
-use crate::ui::layout::{ThreePanelLayout, ThreePanelPosition};
-use crate::ui::primitive::{Draw, Line, Quad, Text, Drawable};
+use crate::ui::layout::{ThreePanelLayout, ThreePanelPosition, VerticalLayout, VerticalPosition};
+use crate::ui::primitive::{Draw, Line, Quad, Text, Drawable, Primitive};
 use crate::ui::input::{MousePosition, Input, InputHandler};
 use crate::ui::{Application, Transform, Depth, Position, Transformable};
 use crate::config::InstrumentConfig;
 use crate::sequencer::{SequencerController, Sequencer, SequencerEvent, Clip, self};
-use crate::sequencer::{interface::{SequencerInterface}};
-use crate::sound::{Output, SoundBankController, Float, SoundBank};
+use crate::sequencer::{interface::{SequencerInterface, TransportPanel, ScenePanel}};
+use crate::sound::{Output, OutputStatus, SoundBankController, Float, SoundBank, SoundMetadata};
+use crate::project::{Project, ProjectSound, ProjectError, PROJECT_EXTENSION};
+
 
+const GAIN_REDUCTION_DECAY_DB: Float = 0.2;  // per update
+
+// Right of the limiter reading in the bottom panel
+const OUTPUT_STATUS_X: f32 = 0.2;
 
 #[derive(Debug, Default, Clone, Copy)]
 pub enum InstrumentState {
@@ -23,9 +32,16 @@ pub struct Instrument {
     global_layout: ThreePanelLayout,
     sequencer_interface: SequencerInterface,
     sequencer_transform: Transform,
+    side_layout: VerticalLayout,
+    transport_panel: TransportPanel,
+    scene_panel: ScenePanel,
     sound_bank_controller: SoundBankController<Float>,
-    _output: Output,
+    output: Output,
+    gain_reduction_db: Float,
     mouse_position: MousePosition,
+    modifiers: ModifiersState,
+    // Where the project was last saved or opened from
+    project_path: Option<PathBuf>,
 }
 
 impl Application for Instrument {
@@ -40,69 +56,53 @@ impl Application for Instrument {
         let (
             sequencer_controller,
             sequencer
-        ) = Sequencer::new(sound_bank);
+        ) = Sequencer::new(sound_bank, config.output.sample_rate);
         
+        let sample_rate = config.output.sample_rate;
         let mut output = Output::new(config.output);
         output.start(sequencer);
 
-        let mut sequencer_interface = SequencerInterface::init(sequencer_controller);
-
-        let source_index: usize = 3;
-        let metadata = sound_bank_controller.get(source_index).unwrap();
-        sequencer_interface.add_clip(1, Clip {
-            enabled: true,
-            source_index,
-            channel_location_start: 0,
-            channel_location_end: metadata.length as u64,
-            source_scale: 1.0,
-            source_shift: 0,
-        });
-
-        let source_index: usize = 0;
-        let metadata = sound_bank_controller.get(source_index).unwrap();
-        sequencer_interface.add_clip(2, Clip {
-            enabled: true,
-            source_index,
-            channel_location_start: 0,
-            channel_location_end: metadata.length as u64,
-            source_scale: 1.0,
-            source_shift: 0,
-        });
-
-        let source_index: usize = 2;
-        let metadata = sound_bank_controller.get(source_index).unwrap();
-        sequencer_interface.add_clip(3, Clip {
-            enabled: true,
-            source_index,
-            channel_location_start: 0,
-            channel_location_end: metadata.length as u64,
-            source_scale: 1.0,
-            source_shift: 0,
-        });
+        let mut sequencer_interface = SequencerInterface::init(sequencer_controller, sample_rate, config.theme);
+        for (index, metadata) in sound_bank_controller.metadata.iter().enumerate() {
+            sequencer_interface.set_source_length(index, metadata.as_ref().map(|metadata| metadata.length));
+        }
 
-        let source_index: usize = 1;
-        let metadata = sound_bank_controller.get(source_index).unwrap();
-        sequencer_interface.add_clip(0, Clip {
-            enabled: true,
-            source_index,
-            channel_location_start: 0,
-            channel_location_end: metadata.length as u64,
-            source_scale: 1.0,
-            source_shift: 0,
-        });
+        // Whichever sounds were loaded into these slots
+        for (channel_index, source_index) in [(1, 3), (2, 0), (3, 2), (0, 1)] {
+            if let Some(metadata) = sound_bank_controller.get(source_index) {
+                sequencer_interface.add_clip(channel_index, clip_for_source(source_index, metadata)).unwrap();
+            }
+        }
+        sequencer_interface.clear_history();
 
-        let global_layout = ThreePanelLayout::new(0.8, 0.3);
+        let layout = config.layout;
+        let global_layout = ThreePanelLayout::new(layout.top_height, layout.side_width);
         
         let sequencer_transform = global_layout.get(ThreePanelPosition::Main);
         sequencer_interface.set_transform(sequencer_transform);
 
+        let side_transform = global_layout.get(ThreePanelPosition::Side);
+        let side_layout = VerticalLayout::new(layout.transport_height);
+        let transport_panel = TransportPanel::new(
+            side_layout.get(VerticalPosition::Top).then(side_transform)
+        );
+        let scene_panel = ScenePanel::new(
+            side_layout.get(VerticalPosition::Bottom).then(side_transform)
+        );
+
         Self {
             global_layout,
             sequencer_interface,
             sequencer_transform,
+            side_layout,
+            transport_panel,
+            scene_panel,
             sound_bank_controller,
-            _output: output,
+            output,
+            gain_reduction_db: 0.0,
             mouse_position: MousePosition::default(),
+            modifiers: ModifiersState::default(),
+            project_path: None,
         }
     }
 
@@ -112,14 +112,90 @@ impl Application for Instrument {
 
     fn update(&mut self, state: InstrumentState) -> InstrumentState {
         self.sequencer_interface.update();
+        self.sound_bank_controller.update();
+        self.output.update();
+        self.transport_panel.set_state(self.sequencer_interface.transport());
+        self.scene_panel.set_scenes(self.sequencer_interface.scenes(), self.sequencer_interface.scene_name_edit());
+        // Hold the reading for a moment so that short peaks stay readable
+        self.gain_reduction_db = self.output.master_bus_meter()
+            .take_gain_reduction_db()
+            .max(self.gain_reduction_db - GAIN_REDUCTION_DECAY_DB);
         state
     }
 }
 
+impl Instrument {
+    // Saves to the current project file, asking for one if there is none yet
+    // or if save_as is set
+    fn save_project(&mut self, save_as: bool) {
+        let path = match &self.project_path {
+            Some(path) if !save_as => path.clone(),
+            _ => match project_file_dialog().save_file() {
+                Some(path) => path,
+                None => return
+            }
+        };
+        let mut project = self.sequencer_interface.save_project();
+        project.sounds = self.sound_bank_controller.metadata.iter()
+            .enumerate()
+            .filter_map(|(index, metadata)| {
+                Some(ProjectSound::new(index, &metadata.as_ref()?.path, &path))
+            })
+            .collect();
+        match project.save(&path) {
+            Ok(()) => self.project_path = Some(path),
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    fn open_project(&mut self) {
+        if let Some(path) = project_file_dialog().pick_file() {
+            if let Err(err) = self.load_project(&path) {
+                println!("{}", err);
+            }
+        }
+    }
+
+    // The project is checked and every sound loaded before anything is
+    // replaced, so a file that fails to load leaves the current patch as it
+    // was
+    pub fn load_project(&mut self, path: &Path) -> Result<(), ProjectError> {
+        // Projects saved at another rate are moved to the output's rate
+        let sample_rate = self.output.config().sample_rate;
+        let (project, sounds) = Project::load_for_playback(path, Some(sample_rate))?;
+
+        for (index, sound) in sounds.iter().enumerate() {
+            self.sequencer_interface.set_source_length(index, sound.as_ref().map(|sound| sound.metadata.length));
+        }
+        self.sequencer_interface.load_project(&project);
+        for (index, sound) in sounds.into_iter().enumerate() {
+            if sound.is_some() || self.sound_bank_controller.get(index).is_some() {
+                self.sound_bank_controller.set_sound(index, sound);
+            }
+        }
+        self.project_path = Some(path.to_path_buf());
+        Ok(())
+    }
+}
+
 impl InputHandler<Instrument> for Instrument {
     fn handle(&mut self, input: Input, state: InstrumentState) -> InstrumentState {
         let window = input.window;
         let event = input.event;
+        // While a scene is being renamed typing goes to the name, not to the
+        // shortcuts
+        if self.sequencer_interface.scene_name_edit().is_some() {
+            match event {
+                WindowEvent::ReceivedCharacter(_) | WindowEvent::KeyboardInput { .. } => {
+                    self.sequencer_interface.handle_scene_name_event(event);
+                    return state;
+                },
+                WindowEvent::MouseInput { state: ElementState::Pressed, .. } => {
+                    self.sequencer_interface.finish_scene_name_edit(true);
+                },
+                _ => {}
+            }
+        }
         match event {
             WindowEvent::CursorMoved { position, .. } => {
                 self.mouse_position = MousePosition::from_physical(position, window.inner_size());
@@ -129,6 +205,59 @@ impl InputHandler<Instrument> for Instrument {
                     self.mouse_position.transform(self.sequencer_transform.inverse())
                 )
             },
+            // The side panel holds the transport controls, presses there do
+            // not reach the sequencer (releases do, to end any drag)
+            WindowEvent::MouseInput { state: ElementState::Pressed, button, .. } if matches!(
+                self.global_layout.select(Position(self.mouse_position.x, self.mouse_position.y)),
+                ThreePanelPosition::Side
+            ) => {
+                if !matches!(button, MouseButton::Left | MouseButton::Right) {
+                    return state;
+                }
+                let side_transform = self.global_layout.get(ThreePanelPosition::Side);
+                let side_position = self.mouse_position.transform(side_transform.inverse());
+                match self.side_layout.select(Position(side_position.x, side_position.y)) {
+                    VerticalPosition::Top if *button == MouseButton::Left => {
+                        let transform = self.transport_panel.transform();
+                        if let Some(button) = self.transport_panel.button_at(
+                            self.mouse_position.transform(transform.inverse())
+                        ) {
+                            self.sequencer_interface.handle_transport_button(button);
+                        }
+                    },
+                    VerticalPosition::Bottom => {
+                        let transform = self.scene_panel.transform();
+                        if let Some(index) = self.scene_panel.slot_at(
+                            self.mouse_position.transform(transform.inverse())
+                        ) {
+                            // Right click to rename
+                            match button {
+                                MouseButton::Left => self.sequencer_interface.handle_scene_slot(index),
+                                _ => self.sequencer_interface.start_scene_name_edit(index)
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+                return state;
+            },
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(keycode @ (VirtualKeyCode::S | VirtualKeyCode::O)),
+                    ..
+                },
+                ..
+            } if self.modifiers.ctrl() => {
+                match keycode {
+                    VirtualKeyCode::S => self.save_project(self.modifiers.shift()),
+                    _ => self.open_project()
+                }
+                return state;
+            },
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+            },
             _ => {}
         }
         self.sequencer_interface.handle_window_event(event, window);
@@ -151,5 +280,42 @@ impl Drawable for Instrument {
             depth: Depth::Mid,
         });
         draw.with(&self.sequencer_interface);
+        draw.with(&self.transport_panel);
+        draw.with(&self.scene_panel);
+        draw.primitive_absolute(Primitive::Text(Text {
+            label: format!("limiter -{:.1} dB", self.gain_reduction_db),
+            position: (0.0, self.global_layout.vertical.divide),
+            scale: 20.0,
+            color: if self.gain_reduction_db > 0.1 { Color::RED } else { Color::BLACK },
+            depth: Depth::Top,
+        }));
+        let output_status = self.output.status();
+        draw.primitive_absolute(Primitive::Text(Text {
+            label: output_status.to_string(),
+            position: (OUTPUT_STATUS_X, self.global_layout.vertical.divide),
+            scale: 20.0,
+            color: if matches!(output_status, OutputStatus::Reconnecting { .. }) { Color::RED } else { Color::BLACK },
+            depth: Depth::Top,
+        }));
     }
 }
+
+fn project_file_dialog() -> FileDialog {
+    FileDialog::new().add_filter("project", &[PROJECT_EXTENSION])
+}
+
+// The whole sound from the start of a new channel, cut off where the channel
+// ends if the sound is longer
+fn clip_for_source(source_index: usize, metadata: &SoundMetadata) -> Clip {
+    let mut clip = Clip {
+        enabled: true,
+        source_index,
+        channel_location_start: 0,
+        channel_location_end: u64::MAX,
+        source_scale: 1.0,
+        source_shift: 0,
+    };
+    clip.constrain_to_source(metadata.length);
+    clip.clamp_to_length(sequencer::DEFAULT_CHANNEL_LENGTH);
+    clip
+}
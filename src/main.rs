@@ -3,24 +3,17 @@ mod sequencer;
 mod ui;
 mod config;
 mod instrument;
+mod project;
+mod cli;
 mod util;
 
-use crate::config::InstrumentConfig;
-use crate::instrument::Instrument;
-use crate::ui::Application;
-use crate::sound::Sound;
+use crate::cli::Command;
 
 
 fn main() {
-    let mut config = InstrumentConfig::default();
-
-    let files = [
-        "assets/samples/kick.wav",
-        "assets/samples/snare.wav",
-        "assets/samples/hihat.wav",
-        "assets/samples/flute.wav"
-    ];
-    config.sounds = files.map(|path| Sound::from_wav_file(path, &config.output)).into();
-
-    Instrument::run(config);
-}
\ No newline at end of file
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = Command::parse(&args).and_then(|command| command.run()) {
+        eprintln!("{}", err);
+        std::process::exit(err.exit_code());
+    }
+}
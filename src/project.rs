@@ -0,0 +1,904 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::sequencer::{
+    Clip, Junction, JunctionType, JunctionCondition, Trigger, Playhead, BoundaryMode, VoiceConfig,
+    ChannelStrip, MAX_CHANNELS, MAX_CLIPS_PER_CHANNEL, MAX_JUNCTIONS_PER_CHANNEL, MAX_JUMP_DESTINATIONS,
+    MAX_VOICES_PER_CHANNEL, MAX_TRIGGERS, MAX_SCENES
+};
+use crate::sound::{Sound, Float, MAX_SOUNDS};
+
+
+// Bumped whenever older files would no longer load as they are
+pub const PROJECT_VERSION: u32 = 1;
+pub const PROJECT_EXTENSION: &str = "toml";
+
+// Loaded sounds by sound bank slot
+pub type ProjectSounds = [Option<Sound<Float>>; MAX_SOUNDS];
+
+// A complete patch, saved as TOML so that it diffs cleanly.  Lists can be
+// left out of hand written files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Project {
+    pub version: u32,
+    // Every location in the file is in frames at this rate
+    pub sample_rate: usize,
+    pub bpm: u32,
+    // In beats
+    pub loop_length: u64,
+    #[serde(default)]
+    pub sounds: Vec<ProjectSound>,
+    pub channels: Vec<ProjectChannel>,
+    // Only enabled triggers, clips and junctions are stored
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub scenes: Vec<ProjectScene>
+}
+
+// A sound bank slot.  The path is relative to the project file when the
+// sound is somewhere below it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectSound {
+    pub index: usize,
+    pub path: PathBuf
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectChannel {
+    pub length: u64,
+    pub boundary_mode: BoundaryMode,
+    pub voice_config: VoiceConfig,
+    pub strip: ChannelStrip,
+    #[serde(default)]
+    pub clips: Vec<Clip>,
+    #[serde(default)]
+    pub junctions: Vec<Junction>
+}
+
+// The playing voices of each channel, anything else is stopped on recall
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectScene {
+    pub index: usize,
+    pub name: String,
+    pub channels: Vec<Vec<Playhead>>
+}
+
+#[derive(Debug)]
+pub enum ProjectError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Write(toml::ser::Error),
+    UnsupportedVersion(u32),
+    // Everything problems() found, the project is left unloaded
+    Invalid(Vec<Problem>),
+    Sound {
+        path: PathBuf,
+        error: hound::Error
+    }
+}
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectError::Io(error) => write!(f, "could not access project file: {}", error),
+            ProjectError::Parse(error) => write!(f, "could not read project file: {}", error),
+            ProjectError::Write(error) => write!(f, "could not write project file: {}", error),
+            ProjectError::UnsupportedVersion(version) => write!(
+                f,
+                "project file version {} is newer than this build supports ({})",
+                version,
+                PROJECT_VERSION
+            ),
+            ProjectError::Invalid(problems) => write!(
+                f,
+                "project has {} problem{}: {}",
+                problems.len(),
+                if problems.len() == 1 { "" } else { "s" },
+                problems.iter().map(Problem::to_string).collect::<Vec<_>>().join(", ")
+            ),
+            ProjectError::Sound { path, error } => write!(
+                f,
+                "could not load sound {}: {}",
+                path.display(),
+                error
+            )
+        }
+    }
+}
+
+impl std::error::Error for ProjectError {}
+
+impl From<io::Error> for ProjectError {
+    fn from(error: io::Error) -> Self {
+        ProjectError::Io(error)
+    }
+}
+
+impl From<toml::de::Error> for ProjectError {
+    fn from(error: toml::de::Error) -> Self {
+        ProjectError::Parse(error)
+    }
+}
+
+impl From<toml::ser::Error> for ProjectError {
+    fn from(error: toml::ser::Error) -> Self {
+        ProjectError::Write(error)
+    }
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Project, ProjectError> {
+        let project: Project = toml::from_str(&fs::read_to_string(path)?)?;
+        if project.version > PROJECT_VERSION {
+            return Err(ProjectError::UnsupportedVersion(project.version));
+        }
+        Ok(project)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ProjectError> {
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Loads a project, checks it, moves it to the sample rate it will play at
+    // and loads its sounds, then keeps every clip within its sound as the
+    // interface does while editing.  The instrument and the command line
+    // both load projects through here.
+    pub fn load_for_playback(
+        path: &Path,
+        sample_rate: Option<usize>
+    ) -> Result<(Project, ProjectSounds), ProjectError> {
+        let mut project = Project::load(path)?;
+        let problems = project.problems();
+        if !problems.is_empty() {
+            return Err(ProjectError::Invalid(problems));
+        }
+        if let Some(sample_rate) = sample_rate {
+            project.rescale(sample_rate);
+        }
+        let sounds = project.load_sounds(path, project.sample_rate)?;
+        project.constrain_to_sounds(&sounds);
+        Ok((project, sounds))
+    }
+
+    pub fn constrain_to_sounds(&mut self, sounds: &[Option<Sound<Float>>]) {
+        for clip in self.channels.iter_mut().flat_map(|channel| channel.clips.iter_mut()) {
+            if let (true, Some(Some(sound))) = (clip.enabled, sounds.get(clip.source_index)) {
+                clip.constrain_to_source(sound.metadata.length);
+            }
+        }
+    }
+
+    // Loads every sound into its slot, stopping at the first that fails
+    pub fn load_sounds(
+        &self,
+        project_path: &Path,
+        sample_rate: usize
+    ) -> Result<ProjectSounds, ProjectError> {
+        let mut sounds: ProjectSounds = Default::default();
+        for project_sound in &self.sounds {
+            if project_sound.index >= MAX_SOUNDS {
+                continue;
+            }
+            let path = project_sound.resolve(project_path);
+            let sound = Sound::from_wav_file(&path, sample_rate)
+                .map_err(|error| ProjectError::Sound { path, error })?;
+            sounds[project_sound.index] = Some(sound);
+        }
+        Ok(sounds)
+    }
+
+    // Everything that would be cut, clamped or ignored on load.  Sounds are
+    // only checked for a valid slot, not loaded.
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let num_channels = self.channels.len();
+        if num_channels == 0 || num_channels > MAX_CHANNELS {
+            problems.push(Problem::new(
+                ProblemKind::ChannelCount,
+                format!("{} channels, expected 1 to {}", num_channels, MAX_CHANNELS)
+            ));
+        }
+        if self.sample_rate == 0 {
+            problems.push(Problem::new(ProblemKind::SampleRate, String::from("sample rate is 0")));
+        }
+        if self.bpm == 0 {
+            problems.push(Problem::new(ProblemKind::Tempo, String::from("tempo is 0 bpm")));
+        }
+        if self.loop_length == 0 {
+            problems.push(Problem::new(ProblemKind::LoopLength, String::from("loop length is 0 beats")));
+        }
+
+        for (position, sound) in self.sounds.iter().enumerate() {
+            if sound.index >= MAX_SOUNDS {
+                problems.push(Problem::new(
+                    ProblemKind::SoundSlot,
+                    format!("sound {} is past the last slot ({})", sound.index, MAX_SOUNDS - 1)
+                ));
+            }
+            if self.sounds[..position].iter().any(|other| other.index == sound.index) {
+                problems.push(Problem::new(
+                    ProblemKind::SoundSlotReused,
+                    format!("sound slot {} is used more than once", sound.index)
+                ));
+            }
+        }
+
+        let channel_length = |channel_index: usize| {
+            self.channels.get(channel_index).map(|channel| channel.length)
+        };
+        let check_destination = |problems: &mut Vec<Problem>, context: &str, channel_index: usize, location: u64| {
+            match channel_length(channel_index) {
+                None => problems.push(Problem::new(
+                    ProblemKind::MissingDestinationChannel,
+                    format!("{} jumps to missing channel {}", context, channel_index)
+                )),
+                Some(length) if location >= length => problems.push(Problem::new(
+                    ProblemKind::DestinationPastChannel,
+                    format!("{} jumps to {} which is past the end of channel {}", context, location, channel_index)
+                )),
+                _ => {}
+            }
+        };
+        let check_junction_type = |problems: &mut Vec<Problem>, context: &str, junction_type: JunctionType| {
+            match junction_type {
+                JunctionType::Jump { destination_channel_index, destination_location, .. } => {
+                    check_destination(problems, context, destination_channel_index, destination_location);
+                },
+                JunctionType::RandomJump { destinations, num_destinations, .. } => {
+                    if num_destinations == 0 || num_destinations > MAX_JUMP_DESTINATIONS {
+                        problems.push(Problem::new(
+                            ProblemKind::DestinationCount,
+                            format!(
+                                "{} has {} destinations, expected 1 to {}",
+                                context,
+                                num_destinations,
+                                MAX_JUMP_DESTINATIONS
+                            )
+                        ));
+                    }
+                    for destination in destinations.iter().take(num_destinations) {
+                        check_destination(problems, context, destination.channel_index, destination.location);
+                    }
+                },
+                JunctionType::Reflect | JunctionType::Stop => {}
+            }
+        };
+
+        for (channel_index, channel) in self.channels.iter().enumerate() {
+            if channel.length == 0 {
+                problems.push(Problem::new(
+                    ProblemKind::ChannelLength,
+                    format!("channel {} has no length", channel_index)
+                ));
+            }
+            if channel.voice_config.limit == 0 || channel.voice_config.limit > MAX_VOICES_PER_CHANNEL {
+                problems.push(Problem::new(
+                    ProblemKind::VoiceLimit,
+                    format!(
+                        "channel {} allows {} voices, expected 1 to {}",
+                        channel_index,
+                        channel.voice_config.limit,
+                        MAX_VOICES_PER_CHANNEL
+                    )
+                ));
+            }
+            if channel.clips.len() > MAX_CLIPS_PER_CHANNEL {
+                problems.push(Problem::new(
+                    ProblemKind::ClipCount,
+                    format!(
+                        "channel {} has {} clips, only {} fit",
+                        channel_index,
+                        channel.clips.len(),
+                        MAX_CLIPS_PER_CHANNEL
+                    )
+                ));
+            }
+            if channel.junctions.len() > MAX_JUNCTIONS_PER_CHANNEL {
+                problems.push(Problem::new(
+                    ProblemKind::JunctionCount,
+                    format!(
+                        "channel {} has {} junctions, only {} fit",
+                        channel_index,
+                        channel.junctions.len(),
+                        MAX_JUNCTIONS_PER_CHANNEL
+                    )
+                ));
+            }
+            for (clip_index, clip) in channel.clips.iter().enumerate() {
+                let context = format!("channel {} clip {}", channel_index, clip_index);
+                if !self.sounds.iter().any(|sound| sound.index == clip.source_index) {
+                    problems.push(Problem::new(
+                        ProblemKind::MissingSound,
+                        format!("{} plays missing sound {}", context, clip.source_index)
+                    ));
+                }
+                if clip.channel_location_start >= clip.channel_location_end {
+                    problems.push(Problem::new(
+                        ProblemKind::ClipEndsBeforeStart,
+                        format!("{} ends before it starts", context)
+                    ));
+                }
+                if clip.channel_location_end > channel.length {
+                    problems.push(Problem::new(
+                        ProblemKind::ClipPastChannel,
+                        format!("{} runs past the end of the channel", context)
+                    ));
+                }
+                if clip.source_scale <= 0.0 {
+                    problems.push(Problem::new(
+                        ProblemKind::SourceScale,
+                        format!("{} has a source scale of {}", context, clip.source_scale)
+                    ));
+                }
+            }
+            for (junction_index, junction) in channel.junctions.iter().enumerate() {
+                let context = format!("channel {} junction {}", channel_index, junction_index);
+                if junction.location >= channel.length {
+                    problems.push(Problem::new(
+                        ProblemKind::JunctionPastChannel,
+                        format!("{} is past the end of the channel", context)
+                    ));
+                }
+                check_junction_type(&mut problems, &context, junction.junction_type);
+                if let JunctionCondition::Alternate { alternate } = junction.condition {
+                    check_junction_type(&mut problems, &context, alternate);
+                }
+            }
+        }
+
+        if self.triggers.len() > MAX_TRIGGERS {
+            problems.push(Problem::new(
+                ProblemKind::TriggerCount,
+                format!("{} triggers, only {} fit", self.triggers.len(), MAX_TRIGGERS)
+            ));
+        }
+        for (trigger_index, trigger) in self.triggers.iter().enumerate() {
+            let context = format!("trigger {}", trigger_index);
+            if trigger.location >= self.loop_length {
+                problems.push(Problem::new(
+                    ProblemKind::TriggerPastLoop,
+                    format!("{} is past the end of the loop", context)
+                ));
+            }
+            check_destination(&mut problems, &context, trigger.destination_channel_index, trigger.destination_location);
+        }
+
+        for scene in &self.scenes {
+            if scene.index >= MAX_SCENES {
+                problems.push(Problem::new(
+                    ProblemKind::SceneSlot,
+                    format!("scene {} is past the last slot ({})", scene.index, MAX_SCENES - 1)
+                ));
+            }
+            if scene.channels.len() > num_channels {
+                problems.push(Problem::new(
+                    ProblemKind::SceneChannelCount,
+                    format!("scene {} has more channels than the project", scene.index)
+                ));
+            }
+            for (channel_index, playheads) in scene.channels.iter().enumerate() {
+                if playheads.len() > MAX_VOICES_PER_CHANNEL {
+                    problems.push(Problem::new(
+                        ProblemKind::ScenePlayheadCount,
+                        format!(
+                            "scene {} channel {} has {} playheads, only {} fit",
+                            scene.index,
+                            channel_index,
+                            playheads.len(),
+                            MAX_VOICES_PER_CHANNEL
+                        )
+                    ));
+                }
+                let length = channel_length(channel_index).unwrap_or(0);
+                if playheads.iter().any(|playhead| playhead.location >= length) {
+                    problems.push(Problem::new(
+                        ProblemKind::ScenePlayheadPastChannel,
+                        format!(
+                            "scene {} channel {} has a playhead past the end of the channel",
+                            scene.index,
+                            channel_index
+                        )
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    // Moves every location in the project to the given sample rate.  Starts
+    // round down and ends and lengths round up, so that everything that was
+    // inside a channel or clip still is.
+    pub fn rescale(&mut self, sample_rate: usize) {
+        let from = self.sample_rate;
+        if from == sample_rate || from == 0 {
+            return;
+        }
+        let location = |location: u64| rescale_location(location, from, sample_rate);
+        let length = |length: u64| rescale_length(length, from, sample_rate);
+        let rescale_junction_type = |junction_type: &mut JunctionType| {
+            match junction_type {
+                JunctionType::Jump { destination_location, .. } => {
+                    *destination_location = location(*destination_location);
+                },
+                JunctionType::RandomJump { destinations, .. } => {
+                    for destination in destinations.iter_mut() {
+                        destination.location = location(destination.location);
+                    }
+                },
+                JunctionType::Reflect | JunctionType::Stop => {}
+            }
+        };
+
+        for channel in &mut self.channels {
+            channel.length = length(channel.length);
+            for clip in &mut channel.clips {
+                clip.channel_location_start = location(clip.channel_location_start);
+                clip.channel_location_end = length(clip.channel_location_end);
+                clip.source_shift = location(clip.source_shift);
+            }
+            for junction in &mut channel.junctions {
+                junction.location = location(junction.location);
+                rescale_junction_type(&mut junction.junction_type);
+                if let JunctionCondition::Alternate { alternate } = &mut junction.condition {
+                    rescale_junction_type(alternate);
+                }
+            }
+        }
+        // Trigger locations are in beats
+        for trigger in &mut self.triggers {
+            trigger.destination_location = location(trigger.destination_location);
+        }
+        for scene in &mut self.scenes {
+            for playhead in scene.channels.iter_mut().flatten() {
+                playhead.location = location(playhead.location);
+            }
+        }
+        self.sample_rate = sample_rate;
+    }
+}
+
+// A location in frames at one sample rate as frames at another, rounding down
+pub fn rescale_location(location: u64, from: usize, to: usize) -> u64 {
+    (location as u128 * to as u128 / from as u128) as u64
+}
+
+// The same for the end of a range, rounding up
+pub fn rescale_length(length: u64, from: usize, to: usize) -> u64 {
+    (length as u128 * to as u128).div_ceil(from as u128) as u64
+}
+
+// What problems() found, tests and tools can tell problems apart without
+// depending on the wording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblemKind {
+    ChannelCount,
+    SampleRate,
+    Tempo,
+    LoopLength,
+    SoundSlot,
+    SoundSlotReused,
+    // Only found by loading the sound, see the validate command
+    UnreadableSound,
+    ChannelLength,
+    VoiceLimit,
+    ClipCount,
+    JunctionCount,
+    MissingSound,
+    ClipEndsBeforeStart,
+    ClipPastChannel,
+    SourceScale,
+    JunctionPastChannel,
+    DestinationCount,
+    MissingDestinationChannel,
+    DestinationPastChannel,
+    TriggerCount,
+    TriggerPastLoop,
+    SceneSlot,
+    SceneChannelCount,
+    ScenePlayheadCount,
+    ScenePlayheadPastChannel
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub message: String
+}
+
+impl Problem {
+    pub fn new(kind: ProblemKind, message: String) -> Self {
+        Self {
+            kind,
+            message
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ProjectSound {
+    pub fn new(index: usize, sound_path: &Path, project_path: &Path) -> Self {
+        let sound_path = fs::canonicalize(sound_path).unwrap_or_else(|_| sound_path.to_path_buf());
+        let path = project_directory(project_path)
+            .and_then(|directory| sound_path.strip_prefix(directory).ok())
+            .map(Path::to_path_buf)
+            .unwrap_or(sound_path);
+        Self {
+            index,
+            path
+        }
+    }
+
+    pub fn resolve(&self, project_path: &Path) -> PathBuf {
+        match project_path.parent() {
+            Some(directory) if self.path.is_relative() => directory.join(&self.path),
+            _ => self.path.clone()
+        }
+    }
+}
+
+fn project_directory(project_path: &Path) -> Option<PathBuf> {
+    let directory = project_path.parent()?;
+    // An unsaved file has no canonical path of its own, but its directory does
+    let directory = if directory.as_os_str().is_empty() { Path::new(".") } else { directory };
+    fs::canonicalize(directory).ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::{JumpDestination, PlayheadState, PlayheadDirection, VoiceStealPolicy};
+    use crate::sound::{SoundMetadata, StereoFrame};
+
+    const CHANNEL_LENGTH: u64 = 1000;
+
+    // Uses a bit of everything, without any problems
+    fn project() -> Project {
+        let mut destinations = [JumpDestination::default(); MAX_JUMP_DESTINATIONS];
+        destinations[0] = JumpDestination { channel_index: 0, location: 100, weight: 1.0 };
+        destinations[1] = JumpDestination { channel_index: 1, location: 200, weight: 0.5 };
+        let channel = ProjectChannel {
+            length: CHANNEL_LENGTH,
+            boundary_mode: BoundaryMode::Wrap,
+            voice_config: VoiceConfig {
+                limit: 2,
+                steal_policy: VoiceStealPolicy::Quietest
+            },
+            strip: ChannelStrip {
+                gain_db: -6.0,
+                pan: 0.5,
+                mute: false,
+                solo: true
+            },
+            clips: vec![Clip {
+                enabled: true,
+                source_index: 3,
+                channel_location_start: 100,
+                channel_location_end: 600,
+                source_scale: 2.0,
+                source_shift: 50
+            }],
+            junctions: vec![
+                Junction {
+                    enabled: true,
+                    location: 700,
+                    junction_type: JunctionType::Jump {
+                        destination_channel_index: 1,
+                        destination_location: 10,
+                        split: true
+                    },
+                    condition: JunctionCondition::EveryNth { n: 2 }
+                },
+                Junction {
+                    enabled: true,
+                    location: 900,
+                    junction_type: JunctionType::RandomJump {
+                        destinations,
+                        num_destinations: 2,
+                        split: false
+                    },
+                    condition: JunctionCondition::Alternate { alternate: JunctionType::Reflect }
+                }
+            ]
+        };
+        Project {
+            version: PROJECT_VERSION,
+            sample_rate: 48000,
+            bpm: 120,
+            loop_length: 16,
+            sounds: vec![ProjectSound { index: 3, path: PathBuf::from("samples/kick.wav") }],
+            channels: vec![channel.clone(), ProjectChannel { clips: Vec::new(), junctions: Vec::new(), ..channel }],
+            triggers: vec![Trigger {
+                enabled: true,
+                location: 4,
+                destination_channel_index: 1,
+                destination_location: 0
+            }],
+            scenes: vec![ProjectScene {
+                index: 2,
+                name: String::from("intro"),
+                channels: vec![
+                    vec![Playhead {
+                        state: PlayheadState::Playing,
+                        location: 300,
+                        direction: PlayheadDirection::Left
+                    }],
+                    Vec::new()
+                ]
+            }]
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_everything() {
+        let project = project();
+        let text = toml::to_string_pretty(&project).unwrap();
+        let loaded: Project = toml::from_str(&text).unwrap();
+        assert_eq!(toml::to_string_pretty(&loaded).unwrap(), text);
+
+        assert_eq!(loaded.sounds[0].path, PathBuf::from("samples/kick.wav"));
+        let clip = loaded.channels[0].clips[0];
+        assert_eq!((clip.channel_location_start, clip.channel_location_end), (100, 600));
+        assert_eq!((clip.source_scale, clip.source_shift), (2.0, 50));
+        assert!(matches!(
+            loaded.channels[0].junctions[1].junction_type,
+            JunctionType::RandomJump { num_destinations: 2, split: false, .. }
+        ));
+        assert_eq!(loaded.scenes[0].name, "intro");
+        assert_eq!(loaded.scenes[0].channels[0][0].location, 300);
+        assert!(loaded.problems().is_empty());
+    }
+
+    #[test]
+    fn round_trip_through_a_file() {
+        let path = std::env::temp_dir().join(format!("state_machine_project_{}.toml", std::process::id()));
+        project().save(&path).unwrap();
+        let loaded = Project::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            toml::to_string_pretty(&loaded.unwrap()).unwrap(),
+            toml::to_string_pretty(&project()).unwrap()
+        );
+    }
+
+    #[test]
+    fn lists_can_be_left_out() {
+        let project: Project = toml::from_str(&format!(
+            "version = {}\nsample_rate = 48000\nbpm = 120\nloop_length = 16\nchannels = []\n",
+            PROJECT_VERSION
+        )).unwrap();
+        assert!(project.sounds.is_empty() && project.triggers.is_empty() && project.scenes.is_empty());
+    }
+
+    // Each case breaks one thing in an otherwise valid project
+    #[test]
+    fn problems_are_found() {
+        type BreakProject = Box<dyn Fn(&mut Project)>;
+        let random_jump = |num_destinations: usize| -> BreakProject {
+            Box::new(move |project| {
+                let junction = &mut project.channels[0].junctions[1];
+                let JunctionType::RandomJump { destinations, split, .. } = junction.junction_type else {
+                    unreachable!()
+                };
+                junction.junction_type = JunctionType::RandomJump { destinations, num_destinations, split };
+            })
+        };
+        let voice_limit = |limit: usize| -> BreakProject {
+            Box::new(move |project| project.channels[1].voice_config.limit = limit)
+        };
+        let cases: [(&str, BreakProject, ProblemKind); 30] = [
+            ("no channels", Box::new(|project| {
+                project.channels.clear();
+                project.scenes.clear();
+                project.triggers.clear();
+            }), ProblemKind::ChannelCount),
+            ("too many channels", Box::new(|project| {
+                project.channels.resize(MAX_CHANNELS + 1, project.channels[1].clone());
+            }), ProblemKind::ChannelCount),
+            ("no sample rate", Box::new(|project| project.sample_rate = 0), ProblemKind::SampleRate),
+            ("no tempo", Box::new(|project| project.bpm = 0), ProblemKind::Tempo),
+            ("no loop length", Box::new(|project| {
+                project.loop_length = 0;
+                project.triggers.clear();
+            }), ProblemKind::LoopLength),
+            ("sound past the last slot", Box::new(|project| {
+                project.sounds.push(ProjectSound { index: MAX_SOUNDS, path: PathBuf::from("snare.wav") });
+            }), ProblemKind::SoundSlot),
+            ("sound slot used twice", Box::new(|project| {
+                project.sounds.push(ProjectSound { index: 3, path: PathBuf::from("snare.wav") });
+            }), ProblemKind::SoundSlotReused),
+            ("channel without length", Box::new(|project| {
+                project.channels[1].length = 0;
+                project.scenes.clear();
+                project.triggers.clear();
+                project.channels[0].junctions.clear();
+            }), ProblemKind::ChannelLength),
+            ("no voices", voice_limit(0), ProblemKind::VoiceLimit),
+            ("too many voices", voice_limit(MAX_VOICES_PER_CHANNEL + 1), ProblemKind::VoiceLimit),
+            ("too many clips", Box::new(|project| {
+                let clip = project.channels[0].clips[0];
+                project.channels[0].clips.resize(MAX_CLIPS_PER_CHANNEL + 1, clip);
+            }), ProblemKind::ClipCount),
+            ("too many junctions", Box::new(|project| {
+                let junction = project.channels[0].junctions[0];
+                project.channels[0].junctions.resize(MAX_JUNCTIONS_PER_CHANNEL + 1, junction);
+            }), ProblemKind::JunctionCount),
+            ("clip plays missing sound", Box::new(|project| {
+                project.channels[0].clips[0].source_index = 4;
+            }), ProblemKind::MissingSound),
+            ("clip ends before it starts", Box::new(|project| {
+                project.channels[0].clips[0].channel_location_end = 100;
+            }), ProblemKind::ClipEndsBeforeStart),
+            ("clip runs past the channel", Box::new(|project| {
+                project.channels[0].clips[0].channel_location_end = CHANNEL_LENGTH + 1;
+            }), ProblemKind::ClipPastChannel),
+            ("clip without source scale", Box::new(|project| {
+                project.channels[0].clips[0].source_scale = 0.0;
+            }), ProblemKind::SourceScale),
+            ("junction past the channel", Box::new(|project| {
+                project.channels[0].junctions[0].location = CHANNEL_LENGTH;
+            }), ProblemKind::JunctionPastChannel),
+            ("jump to missing channel", Box::new(|project| {
+                project.channels[0].junctions[0].junction_type = JunctionType::Jump {
+                    destination_channel_index: 2,
+                    destination_location: 0,
+                    split: false
+                };
+            }), ProblemKind::MissingDestinationChannel),
+            ("jump past the channel", Box::new(|project| {
+                project.channels[0].junctions[0].junction_type = JunctionType::Jump {
+                    destination_channel_index: 1,
+                    destination_location: CHANNEL_LENGTH,
+                    split: false
+                };
+            }), ProblemKind::DestinationPastChannel),
+            ("random jump without destinations", random_jump(0), ProblemKind::DestinationCount),
+            ("random jump with too many destinations", random_jump(MAX_JUMP_DESTINATIONS + 1), ProblemKind::DestinationCount),
+            ("random jump to missing channel", Box::new(|project| {
+                if let JunctionType::RandomJump { destinations, .. } = &mut project.channels[0].junctions[1].junction_type {
+                    destinations[1].channel_index = 5;
+                }
+            }), ProblemKind::MissingDestinationChannel),
+            ("alternate jump to missing channel", Box::new(|project| {
+                project.channels[0].junctions[1].condition = JunctionCondition::Alternate {
+                    alternate: JunctionType::Jump {
+                        destination_channel_index: 7,
+                        destination_location: 0,
+                        split: false
+                    }
+                };
+            }), ProblemKind::MissingDestinationChannel),
+            ("too many triggers", Box::new(|project| {
+                let trigger = project.triggers[0];
+                project.triggers.resize(MAX_TRIGGERS + 1, trigger);
+            }), ProblemKind::TriggerCount),
+            ("trigger past the loop", Box::new(|project| project.triggers[0].location = 16), ProblemKind::TriggerPastLoop),
+            ("trigger to missing channel", Box::new(|project| {
+                project.triggers[0].destination_channel_index = 2;
+            }), ProblemKind::MissingDestinationChannel),
+            ("scene past the last slot", Box::new(|project| project.scenes[0].index = MAX_SCENES), ProblemKind::SceneSlot),
+            ("scene with more channels than the project", Box::new(|project| {
+                project.scenes[0].channels.push(Vec::new());
+            }), ProblemKind::SceneChannelCount),
+            ("scene with too many playheads", Box::new(|project| {
+                let playhead = project.scenes[0].channels[0][0];
+                project.scenes[0].channels[0].resize(MAX_VOICES_PER_CHANNEL + 1, playhead);
+            }), ProblemKind::ScenePlayheadCount),
+            ("scene playhead past the channel", Box::new(|project| {
+                project.scenes[0].channels[0][0].location = CHANNEL_LENGTH;
+            }), ProblemKind::ScenePlayheadPastChannel)
+        ];
+        for (name, break_project, expected) in cases {
+            let mut project = project();
+            break_project(&mut project);
+            let kinds: Vec<ProblemKind> = project.problems().iter().map(|problem| problem.kind).collect();
+            assert_eq!(kinds, [expected], "{}", name);
+        }
+    }
+
+    #[test]
+    fn random_jumps_save_only_their_destinations() {
+        let text = toml::to_string_pretty(&project()).unwrap();
+        assert_eq!(text.matches("weight").count(), 2);
+        assert!(!text.contains("num_destinations"));
+    }
+
+    #[test]
+    fn random_jumps_that_list_every_slot_still_load() {
+        let junction_type: JunctionType = toml::from_str(
+            "[RandomJump]\n\
+             split = true\n\
+             num_destinations = 1\n\
+             destinations = [\n\
+                 { channel_index = 1, location = 20, weight = 1.0 },\n\
+                 { channel_index = 0, location = 0, weight = 0.0 },\n\
+             ]\n"
+        ).unwrap();
+        let JunctionType::RandomJump { destinations, num_destinations, split } = junction_type else {
+            panic!("expected a random jump, got {:?}", junction_type);
+        };
+        assert_eq!((num_destinations, split), (1, true));
+        assert_eq!((destinations[0].channel_index, destinations[0].location), (1, 20));
+
+        let too_many = format!(
+            "[RandomJump]\nsplit = false\ndestinations = [{}]\n",
+            ["{ channel_index = 0, location = 0, weight = 1.0 }"; MAX_JUMP_DESTINATIONS + 1].join(", ")
+        );
+        assert!(toml::from_str::<JunctionType>(&too_many).is_err());
+        assert!(toml::from_str::<JunctionType>(
+            "[RandomJump]\nsplit = false\nnum_destinations = 2\ndestinations = []\n"
+        ).is_err());
+    }
+
+    #[test]
+    fn rescale_moves_every_location() {
+        let mut project = project();
+        project.rescale(24000);
+        assert_eq!(project.sample_rate, 24000);
+        let channel = &project.channels[0];
+        assert_eq!(channel.length, CHANNEL_LENGTH / 2);
+        let clip = channel.clips[0];
+        assert_eq!((clip.channel_location_start, clip.channel_location_end, clip.source_shift), (50, 300, 25));
+        assert_eq!(channel.junctions[0].location, 350);
+        assert!(matches!(
+            channel.junctions[0].junction_type,
+            JunctionType::Jump { destination_location: 5, .. }
+        ));
+        let JunctionType::RandomJump { destinations, .. } = channel.junctions[1].junction_type else {
+            unreachable!()
+        };
+        assert_eq!((destinations[0].location, destinations[1].location), (50, 100));
+        // In beats, only the destination moves
+        assert_eq!(project.triggers[0].location, 4);
+        assert_eq!(project.scenes[0].channels[0][0].location, 150);
+        assert!(project.problems().is_empty());
+    }
+
+    #[test]
+    fn rescale_keeps_everything_inside_its_channel() {
+        let mut project = project();
+        project.channels[0].length = 1001;
+        project.channels[0].junctions[0].location = 1000;
+        project.channels[0].clips[0].channel_location_start = 599;
+        project.rescale(19200);
+        // 1001 * 0.4 = 400.4, 1000 * 0.4 = 400, 599 * 0.4 = 239.6
+        assert_eq!(project.channels[0].length, 401);
+        assert_eq!(project.channels[0].junctions[0].location, 400);
+        let clip = project.channels[0].clips[0];
+        assert_eq!((clip.channel_location_start, clip.channel_location_end), (239, 240));
+        assert!(project.problems().is_empty());
+    }
+
+    #[test]
+    fn clips_are_kept_within_their_sounds() {
+        let mut project = project();
+        let mut sounds: ProjectSounds = Default::default();
+        sounds[3] = Some(Sound {
+            metadata: SoundMetadata {
+                name: String::from("kick"),
+                length: 200,
+                path: PathBuf::from("samples/kick.wav")
+            },
+            data: vec![StereoFrame(0.0, 0.0); 200].into_boxed_slice()
+        });
+        project.constrain_to_sounds(&sounds);
+        // 150 frames of sound after the shift, stretched to twice the length
+        assert_eq!(project.channels[0].clips[0].channel_location_end, 400);
+
+        // A missing sound leaves the clip as it is
+        let mut project = self::project();
+        project.constrain_to_sounds(&[]);
+        assert_eq!(project.channels[0].clips[0].channel_location_end, 600);
+    }
+}
@@ -1,109 +1,277 @@
-use crate::sound::{SoundBankIndex, Float};
+use serde::{Serialize, Deserialize};
+
+use crate::sound::{SoundBankIndex, StereoFrame, Float};
 
 pub const MAX_CLIPS_PER_CHANNEL: usize = 32;
 pub const MAX_JUNCTIONS_PER_CHANNEL: usize = 32;
+pub const MAX_JUMP_DESTINATIONS: usize = 8;
+pub const MAX_VOICES_PER_CHANNEL: usize = 8;
+pub const DEFAULT_VOICE_LIMIT: usize = 4;
 
+const VOICE_LEVEL_DECAY: Float = 0.999;
 
-#[derive(Debug, Default, Clone, Copy)]
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub enum PlayheadState {
     Playing,
     #[default] Stopped
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PlayheadDirection {
     #[default] Right,
     Left
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+// What the playhead does when it runs off either end of the channel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BoundaryMode {
+    #[default] Stop,
+    Wrap,
+    Bounce
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct Playhead {
     pub state: PlayheadState,
     pub location: u64,
     pub direction: PlayheadDirection
 }
 
-#[derive(Default)]
-pub struct Channel {
-    pub clips: [Clip; MAX_CLIPS_PER_CHANNEL],
-    pub junctions: [Junction; MAX_JUNCTIONS_PER_CHANNEL],
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoiceStealPolicy {
+    #[default] Oldest,
+    Quietest
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VoiceConfig {
+    // Number of voices in use, at most MAX_VOICES_PER_CHANNEL
+    pub limit: usize,
+    pub steal_policy: VoiceStealPolicy
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_VOICE_LIMIT,
+            steal_policy: VoiceStealPolicy::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Voice {
     pub playhead: Playhead,
-    pub playhead_override_this_frame: bool,
-    pub length: u64
+    // Frame at which the voice was launched
+    pub started_at: u64,
+    // Peak of the last frame the voice produced, used to find the quietest
+    pub level: Float,
+    pub override_this_frame: bool
 }
 
-impl Channel {
-    pub fn step_playhead_single_frame(&mut self) {
-        if self.playhead_override_this_frame {
-            self.playhead_override_this_frame = false;
+impl Voice {
+    pub fn is_playing(&self) -> bool {
+        match self.playhead.state {
+            PlayheadState::Playing => true,
+            PlayheadState::Stopped => false
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.playhead.state = PlayheadState::Stopped;
+    }
+
+    // Peak follower with a slow release so that a voice passing through a
+    // quiet spot is not immediately considered the quietest
+    pub fn record_level(&mut self, frame: StereoFrame<Float>) {
+        let peak = frame.left().abs().max(frame.right().abs());
+        self.level = peak.max(self.level * VOICE_LEVEL_DECAY);
+    }
+
+    fn step_single_frame(&mut self, length: u64, boundary_mode: BoundaryMode) {
+        if self.override_this_frame {
+            self.override_this_frame = false;
+            return;
+        }
+        if !self.is_playing() {
             return;
         }
-        if self.is_playing() {
-            let playhead = &mut self.playhead;
-            match playhead.direction {
-                PlayheadDirection::Right => {
+        let playhead = &mut self.playhead;
+        match playhead.direction {
+            PlayheadDirection::Right => {
+                if playhead.location + 1 < length {
                     playhead.location += 1;
-                    if playhead.location >= self.length {
+                    return;
+                }
+                match boundary_mode {
+                    BoundaryMode::Stop => {
+                        playhead.location += 1;
                         playhead.state = PlayheadState::Stopped;
+                    },
+                    BoundaryMode::Wrap => {
+                        playhead.location = 0;
+                    },
+                    BoundaryMode::Bounce => {
+                        playhead.direction = PlayheadDirection::Left;
+                        playhead.location = playhead.location.saturating_sub(1);
                     }
-                },
-                PlayheadDirection::Left => {
-                    if playhead.location <= 0 {
+                }
+            },
+            PlayheadDirection::Left => {
+                if playhead.location > 0 {
+                    playhead.location -= 1;
+                    return;
+                }
+                match boundary_mode {
+                    BoundaryMode::Stop => {
                         playhead.state = PlayheadState::Stopped;
-                    } else {
-                        playhead.location -= 1;
+                    },
+                    BoundaryMode::Wrap => {
+                        playhead.location = length - 1;
+                    },
+                    BoundaryMode::Bounce => {
+                        playhead.direction = PlayheadDirection::Right;
+                        playhead.location = 1.min(length - 1);
                     }
                 }
             }
         }
     }
+}
 
-    pub fn is_playing(&self) -> bool {
-        match self.playhead.state {
-            PlayheadState::Playing => true,
-            PlayheadState::Stopped => false
+#[derive(Default)]
+pub struct Channel {
+    pub clips: [Clip; MAX_CLIPS_PER_CHANNEL],
+    pub junctions: [Junction; MAX_JUNCTIONS_PER_CHANNEL],
+    pub junction_pass_counts: [u32; MAX_JUNCTIONS_PER_CHANNEL],
+    pub voices: [Voice; MAX_VOICES_PER_CHANNEL],
+    pub voice_config: VoiceConfig,
+    pub length: u64,
+    pub boundary_mode: BoundaryMode
+}
+
+impl Channel {
+    pub fn step_playheads_single_frame(&mut self) {
+        for voice in &mut self.voices {
+            voice.step_single_frame(self.length, self.boundary_mode);
         }
     }
 
+    pub fn is_playing(&self) -> bool {
+        self.voices.iter().any(Voice::is_playing)
+    }
+
     pub fn stop(&mut self) {
-        self.playhead.state = PlayheadState::Stopped;
+        for voice in &mut self.voices {
+            voice.stop();
+        }
     }
 
-    pub fn get_current_junction(&self) -> Option<Junction> {
-        if !self.is_playing() {
-            return None;
+    // Starts a new voice, stealing one according to the steal policy when
+    // all of them are busy
+    pub fn launch(&mut self, playhead: Playhead, frame: u64) {
+        let voices = &mut self.voices[..self.voice_config.limit];
+        let voice_index = voices.iter().position(|voice| !voice.is_playing()).unwrap_or_else(|| {
+            let mut steal_index = 0;
+            for (voice_index, voice) in voices.iter().enumerate() {
+                let steal = &voices[steal_index];
+                let better = match self.voice_config.steal_policy {
+                    VoiceStealPolicy::Oldest => voice.started_at < steal.started_at,
+                    VoiceStealPolicy::Quietest => voice.level < steal.level
+                };
+                if better {
+                    steal_index = voice_index;
+                }
+            }
+            steal_index
+        });
+        voices[voice_index] = Voice {
+            playhead,
+            started_at: frame,
+            level: 0.0,
+            override_this_frame: false
+        };
+    }
+
+    pub fn set_voice_config(&mut self, voice_config: VoiceConfig) {
+        let limit = voice_config.limit.clamp(1, MAX_VOICES_PER_CHANNEL);
+        for voice in &mut self.voices[limit..] {
+            voice.stop();
         }
-        for junction in self.junctions {
-            if junction.enabled && (junction.location == self.playhead.location) {
-                return Some(junction);
+        self.voice_config = VoiceConfig {
+            limit,
+            ..voice_config
+        };
+    }
+
+    // Clips are cut at the new end and anything starting past it is disabled
+    pub fn set_length(&mut self, length: u64) {
+        self.length = length.max(1);
+        for clip in &mut self.clips {
+            clip.clamp_to_length(self.length);
+        }
+        for junction in &mut self.junctions {
+            junction.clamp_to_length(self.length);
+        }
+        for voice in &mut self.voices {
+            if voice.playhead.location >= self.length {
+                voice.playhead.location = self.length - 1;
+                voice.stop();
             }
         }
-        None
     }
 
-    pub fn get_current_sound_bank_index(&self) -> Option<SoundBankIndex> {
-        if !self.is_playing() {
+    pub fn get_current_junction_index(&self, voice_index: usize) -> Option<usize> {
+        let voice = &self.voices[voice_index];
+        if !voice.is_playing() {
             return None;
         }
-        // Can probably cache this data somewhere, cache invalid if playhead mutated
-        for clip in &self.clips {
-            if !clip.enabled {
-                continue;
-            }
-            if self.playhead.location >= clip.channel_location_start &&
-               self.playhead.location <  clip.channel_location_end
-            {
-                return Some(SoundBankIndex {
-                    source_index: clip.source_index,
-                    frame_index: (self.playhead.location - clip.channel_location_start) as usize
-                });
-            }
+        self.junctions.iter().position(|junction| {
+            junction.enabled && (junction.location == voice.playhead.location)
+        })
+    }
+
+    // Counts the pass over the voice's current junction and returns its index
+    // and what it does on this pass, if anything.  Passes by every voice are
+    // counted.
+    pub fn pass_current_junction(&mut self, voice_index: usize) -> Option<(usize, JunctionType)> {
+        let junction_index = self.get_current_junction_index(voice_index)?;
+        let pass_count = &mut self.junction_pass_counts[junction_index];
+        *pass_count = pass_count.saturating_add(1);
+        let junction_type = self.junctions[junction_index].on_pass(*pass_count)?;
+        Some((junction_index, junction_type))
+    }
+
+    pub fn reset_junction_pass_counts(&mut self) {
+        self.junction_pass_counts = [0; MAX_JUNCTIONS_PER_CHANNEL];
+    }
+
+    pub fn get_current_clip_index(&self, voice_index: usize) -> Option<usize> {
+        let voice = &self.voices[voice_index];
+        if !voice.is_playing() {
+            return None;
         }
-        None
+        // Can probably cache this data somewhere, cache invalid if playhead mutated
+        self.clips.iter().position(|clip| {
+            clip.enabled &&
+            voice.playhead.location >= clip.channel_location_start &&
+            voice.playhead.location <  clip.channel_location_end
+        })
+    }
+
+    pub fn get_current_sound_bank_index(&self, voice_index: usize) -> Option<SoundBankIndex> {
+        let clip = &self.clips[self.get_current_clip_index(voice_index)?];
+        let position = clip.source_position(self.voices[voice_index].playhead.location)?;
+        Some(SoundBankIndex {
+            source_index: clip.source_index,
+            frame_index: position.floor() as usize,
+            frame_fraction: position.fract() as Float
+        })
     }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Clip {
     pub enabled: bool,
     pub source_index: usize,
@@ -118,20 +286,313 @@ pub struct Clip {
     pub source_shift: u64
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+impl Clip {
+    // A source_scale of 2.0 stretches the source to twice its length (playing
+    // it an octave down), source_shift is the number of source frames skipped
+    // at the start of the clip
+    pub fn source_position(&self, channel_location: u64) -> Option<f64> {
+        if self.source_scale <= 0.0 {
+            return None;
+        }
+        let offset = channel_location.checked_sub(self.channel_location_start)?;
+        Some(self.source_shift as f64 + offset as f64 / self.source_scale as f64)
+    }
+
+    pub fn max_length(&self, source_length: usize) -> u64 {
+        let remaining = (source_length as u64).saturating_sub(self.source_shift);
+        (remaining as f64 * self.source_scale.max(0.0) as f64).floor() as u64
+    }
+
+    pub fn constrain_to_source(&mut self, source_length: usize) {
+        self.channel_location_end = self.channel_location_end.min(
+            self.channel_location_start + self.max_length(source_length)
+        );
+    }
+
+    pub fn clamp_to_length(&mut self, channel_length: u64) {
+        if self.channel_location_start >= channel_length {
+            self.enabled = false;
+        }
+        self.channel_location_end = self.channel_location_end.min(channel_length);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct JumpDestination {
+    pub channel_index: usize,
+    pub location: u64,
+    // Relative to the other destinations of the same junction
+    pub weight: Float
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(into = "JunctionTypeFile", try_from = "JunctionTypeFile")]
 pub enum JunctionType {
     Jump {
         destination_channel_index: usize,
         destination_location: u64,
         split: bool
     },
+    // Jumps to one of the destinations, chosen at random by weight every time
+    // the junction is crossed
+    RandomJump {
+        destinations: [JumpDestination; MAX_JUMP_DESTINATIONS],
+        num_destinations: usize,
+        split: bool
+    },
     Reflect,
     #[default] Stop
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+// How a junction type is saved.  Random jumps only list the destinations in
+// use, files that list every slot along with num_destinations still load.
+#[derive(Serialize, Deserialize)]
+enum JunctionTypeFile {
+    Jump {
+        destination_channel_index: usize,
+        destination_location: u64,
+        split: bool
+    },
+    RandomJump {
+        destinations: Vec<JumpDestination>,
+        #[serde(default, skip_serializing)]
+        num_destinations: Option<usize>,
+        split: bool
+    },
+    Reflect,
+    Stop
+}
+
+impl From<JunctionType> for JunctionTypeFile {
+    fn from(junction_type: JunctionType) -> Self {
+        match junction_type {
+            JunctionType::Jump { destination_channel_index, destination_location, split } => JunctionTypeFile::Jump {
+                destination_channel_index,
+                destination_location,
+                split
+            },
+            JunctionType::RandomJump { destinations, num_destinations, split } => JunctionTypeFile::RandomJump {
+                destinations: destinations[..num_destinations.min(MAX_JUMP_DESTINATIONS)].to_vec(),
+                num_destinations: None,
+                split
+            },
+            JunctionType::Reflect => JunctionTypeFile::Reflect,
+            JunctionType::Stop => JunctionTypeFile::Stop
+        }
+    }
+}
+
+impl TryFrom<JunctionTypeFile> for JunctionType {
+    type Error = String;
+
+    fn try_from(junction_type: JunctionTypeFile) -> Result<Self, Self::Error> {
+        Ok(match junction_type {
+            JunctionTypeFile::Jump { destination_channel_index, destination_location, split } => JunctionType::Jump {
+                destination_channel_index,
+                destination_location,
+                split
+            },
+            JunctionTypeFile::RandomJump { destinations: listed, num_destinations, split } => {
+                if listed.len() > MAX_JUMP_DESTINATIONS {
+                    return Err(format!(
+                        "random jump lists {} destinations, only {} fit",
+                        listed.len(),
+                        MAX_JUMP_DESTINATIONS
+                    ));
+                }
+                let num_destinations = num_destinations.unwrap_or(listed.len());
+                if num_destinations > listed.len() {
+                    return Err(format!(
+                        "random jump uses {} destinations but lists {}",
+                        num_destinations,
+                        listed.len()
+                    ));
+                }
+                let mut destinations = [JumpDestination::default(); MAX_JUMP_DESTINATIONS];
+                destinations[..listed.len()].copy_from_slice(&listed);
+                JunctionType::RandomJump { destinations, num_destinations, split }
+            },
+            JunctionTypeFile::Reflect => JunctionType::Reflect,
+            JunctionTypeFile::Stop => JunctionType::Stop
+        })
+    }
+}
+
+// Passes are counted from 1 and only while the junction is enabled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum JunctionCondition {
+    #[default] Always,
+    // Fires on passes n, 2n, 3n, ...
+    EveryNth {
+        n: u32
+    },
+    // Fires on the first n passes and is transparent after that
+    FirstN {
+        n: u32
+    },
+    // Odd passes use the junction's own type, even passes use alternate
+    Alternate {
+        alternate: JunctionType
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Junction {
     pub enabled: bool,
     pub location: u64,
     pub junction_type: JunctionType,
+    pub condition: JunctionCondition
+}
+
+impl Junction {
+    // The playhead never reaches the channel length itself, so a junction
+    // there would never fire
+    pub fn clamp_to_length(&mut self, channel_length: u64) {
+        if self.location >= channel_length {
+            self.enabled = false;
+        }
+    }
+
+    pub fn on_pass(&self, pass_count: u32) -> Option<JunctionType> {
+        match self.condition {
+            JunctionCondition::Always => Some(self.junction_type),
+            JunctionCondition::EveryNth { n } => {
+                (pass_count % n.max(1) == 0).then_some(self.junction_type)
+            },
+            JunctionCondition::FirstN { n } => {
+                (pass_count <= n).then_some(self.junction_type)
+            },
+            JunctionCondition::Alternate { alternate } => {
+                if pass_count % 2 == 1 {
+                    Some(self.junction_type)
+                } else {
+                    Some(alternate)
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(source_scale: Float, source_shift: u64) -> Clip {
+        Clip {
+            enabled: true,
+            source_index: 0,
+            channel_location_start: 100,
+            channel_location_end: 1000,
+            source_scale,
+            source_shift
+        }
+    }
+
+    #[test]
+    fn source_position_follows_scale_and_shift() {
+        assert_eq!(clip(1.0, 0).source_position(100), Some(0.0));
+        assert_eq!(clip(1.0, 0).source_position(150), Some(50.0));
+        assert_eq!(clip(2.0, 0).source_position(150), Some(25.0));
+        assert_eq!(clip(0.5, 10).source_position(150), Some(110.0));
+        // Before the clip, or with a scale that maps nothing
+        assert_eq!(clip(1.0, 0).source_position(99), None);
+        assert_eq!(clip(0.0, 0).source_position(150), None);
+        assert_eq!(clip(-1.0, 0).source_position(150), None);
+    }
+
+    #[test]
+    fn max_length_is_the_scaled_remainder_of_the_source() {
+        assert_eq!(clip(1.0, 0).max_length(300), 300);
+        assert_eq!(clip(1.0, 100).max_length(300), 200);
+        assert_eq!(clip(2.0, 100).max_length(300), 400);
+        assert_eq!(clip(0.5, 0).max_length(301), 150);
+        assert_eq!(clip(1.0, 400).max_length(300), 0);
+        assert_eq!(clip(-1.0, 0).max_length(300), 0);
+    }
+
+    #[test]
+    fn constrain_to_source_only_shortens() {
+        let mut short = clip(1.0, 0);
+        short.constrain_to_source(300);
+        assert_eq!((short.channel_location_start, short.channel_location_end), (100, 400));
+
+        let mut long = clip(2.0, 0);
+        long.constrain_to_source(10_000);
+        assert_eq!(long.channel_location_end, 1000);
+
+        let mut shifted = clip(1.0, 250);
+        shifted.constrain_to_source(300);
+        assert_eq!(shifted.channel_location_end, 150);
+    }
+
+    fn junction(condition: JunctionCondition) -> Junction {
+        Junction {
+            enabled: true,
+            location: 0,
+            junction_type: JunctionType::Reflect,
+            condition
+        }
+    }
+
+    // What the junction does on passes 1 to 6
+    fn passes(condition: JunctionCondition) -> Vec<Option<JunctionType>> {
+        let junction = junction(condition);
+        (1..=6).map(|pass_count| junction.on_pass(pass_count)).collect()
+    }
+
+    const R: Option<JunctionType> = Some(JunctionType::Reflect);
+    const S: Option<JunctionType> = Some(JunctionType::Stop);
+
+    #[test]
+    fn always_fires_every_pass() {
+        assert_eq!(passes(JunctionCondition::Always), [R; 6]);
+    }
+
+    #[test]
+    fn every_nth_fires_on_multiples() {
+        assert_eq!(passes(JunctionCondition::EveryNth { n: 2 }), [None, R, None, R, None, R]);
+        assert_eq!(passes(JunctionCondition::EveryNth { n: 3 }), [None, None, R, None, None, R]);
+        assert_eq!(passes(JunctionCondition::EveryNth { n: 1 }), [R; 6]);
+        // A count of 0 is treated as 1 rather than dividing by zero
+        assert_eq!(passes(JunctionCondition::EveryNth { n: 0 }), [R; 6]);
+    }
+
+    #[test]
+    fn first_n_fires_then_goes_transparent() {
+        assert_eq!(passes(JunctionCondition::FirstN { n: 2 }), [R, R, None, None, None, None]);
+        assert_eq!(passes(JunctionCondition::FirstN { n: 0 }), [None; 6]);
+    }
+
+    #[test]
+    fn alternate_switches_type_every_pass() {
+        let condition = JunctionCondition::Alternate { alternate: JunctionType::Stop };
+        assert_eq!(passes(condition), [R, S, R, S, R, S]);
+    }
+
+    #[test]
+    fn passes_are_counted_by_every_voice() {
+        let mut channel = Channel {
+            length: 1000,
+            ..Default::default()
+        };
+        channel.junctions[0] = Junction {
+            location: 100,
+            ..junction(JunctionCondition::EveryNth { n: 2 })
+        };
+        for voice in &mut channel.voices[..2] {
+            voice.playhead = Playhead {
+                state: PlayheadState::Playing,
+                location: 100,
+                direction: PlayheadDirection::Right
+            };
+        }
+        assert_eq!(channel.pass_current_junction(0), None);
+        assert_eq!(channel.pass_current_junction(1), Some((0, JunctionType::Reflect)));
+        assert_eq!(channel.junction_pass_counts[0], 2);
+        // Only voices on a junction count
+        channel.voices[2].playhead.state = PlayheadState::Playing;
+        assert_eq!(channel.pass_current_junction(2), None);
+        assert_eq!(channel.junction_pass_counts[0], 2);
+    }
 }
@@ -1,11 +1,29 @@
-const MAX_TRIGGERS: usize = 128;
+use serde::{Serialize, Deserialize};
 
+use crate::sequencer::MAX_CHANNELS;
 
-#[derive(Debug, Default, Clone, Copy)]
+pub const MAX_TRIGGERS: usize = 128;
+pub const DEFAULT_BPM: u32 = 120;
+pub const DEFAULT_BEATS_PER_BAR: u64 = 4;
+pub const DEFAULT_LOOP_LENGTH: u64 = 4 * DEFAULT_BEATS_PER_BAR;  // beats
+
+
+// Launches a playhead when the loop reaches the start of beat `location`
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct Trigger {
-    location: u64,
-    destination_channel_index: usize,
-    destination_location: u64
+    pub enabled: bool,
+    pub location: u64,
+    pub destination_channel_index: usize,
+    pub destination_location: u64
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClockSummary {
+    pub playing: bool,
+    pub bpm: u32,
+    pub beat: u64,
+    pub beats_per_bar: u64,
+    pub length: u64
 }
 
 pub struct ControlLoop {
@@ -13,17 +31,23 @@ pub struct ControlLoop {
     length: u64,
     location: u64,
     playing: bool,
-    samples_per_interval: u64
+    samples_per_interval: u64,
+    sample_rate: u32,
+    bpm: u32,
+    beats_per_bar: u64
 }
 
 impl ControlLoop {
-    pub fn new(length: u64) -> ControlLoop {
-        Self { 
+    pub fn new(length: u64, sample_rate: u32) -> ControlLoop {
+        Self {
             triggers: [Trigger::default(); MAX_TRIGGERS],
-            length,
+            length: length.max(1),
             location: 0,
             playing: false,
-            samples_per_interval: 24_000
+            samples_per_interval: bpm_to_spb(DEFAULT_BPM, sample_rate).max(1),
+            sample_rate,
+            bpm: DEFAULT_BPM,
+            beats_per_bar: DEFAULT_BEATS_PER_BAR
         }
     }
 
@@ -35,20 +59,151 @@ impl ControlLoop {
         self.playing = false;
     }
 
-    fn update_on_interval(&mut self) {
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    // Back to the start of the loop
+    pub fn rewind(&mut self) {
+        self.location = 0;
+    }
+
+    pub fn set_tempo(&mut self, bpm: u32) {
+        let bpm = bpm.max(1);
+        // A tempo too fast for the sample rate would round down to no frames
+        // per beat
+        let samples_per_interval = bpm_to_spb(bpm, self.sample_rate).max(1);
+
+        // Keep the same position within the current beat
+        let beat = self.location / self.samples_per_interval;
+        let offset = self.location % self.samples_per_interval;
+        self.location = beat * samples_per_interval
+            + offset * samples_per_interval / self.samples_per_interval;
 
+        self.bpm = bpm;
+        self.samples_per_interval = samples_per_interval;
     }
 
-    pub fn update(&mut self) {
-        if self.playing {
-            if self.location % self.samples_per_interval == 0 {
-                self.update_on_interval();
-            }
-            self.location = (self.location + 1) % (self.length * self.samples_per_interval);
+    pub fn set_length(&mut self, length: u64) {
+        self.length = length.max(1);
+        self.location %= self.length * self.samples_per_interval;
+    }
+
+    // Triggers for slots or channels that do not exist are dropped, so that
+    // firing never has to check
+    pub fn set_trigger(&mut self, index: usize, trigger: Trigger) {
+        if trigger.destination_channel_index >= MAX_CHANNELS {
+            return;
+        }
+        if let Some(slot) = self.triggers.get_mut(index) {
+            *slot = trigger;
         }
     }
+
+    pub fn beat(&self) -> u64 {
+        self.location / self.samples_per_interval
+    }
+
+    pub fn summary(&self) -> ClockSummary {
+        ClockSummary {
+            playing: self.playing,
+            bpm: self.bpm,
+            beat: self.beat(),
+            beats_per_bar: self.beats_per_bar,
+            length: self.length
+        }
+    }
+
+    pub fn has_triggers(&self) -> bool {
+        self.triggers.iter().any(|trigger| trigger.enabled)
+    }
+
+    pub fn triggers_at(&self, beat: u64) -> impl Iterator<Item = &Trigger> {
+        self.triggers.iter().filter(move |trigger| trigger.enabled && trigger.location == beat)
+    }
+
+    // Advances the loop by one frame and returns the beat that starts on this
+    // frame, if any
+    pub fn update(&mut self) -> Option<u64> {
+        if !self.playing {
+            return None;
+        }
+        let beat_started = (self.location % self.samples_per_interval == 0).then(|| self.beat());
+        self.location = (self.location + 1) % (self.length * self.samples_per_interval);
+        beat_started
+    }
 }
 
 pub fn bpm_to_spb(bpm: u32, sample_rate: u32) -> u64 {
-    ((sample_rate * 60) as f32 / bpm as f32).round() as u64
-}
\ No newline at end of file
+    ((sample_rate as u64 * 60) as f32 / bpm as f32).round() as u64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn trigger(location: u64, destination_channel_index: usize) -> Trigger {
+        Trigger {
+            enabled: true,
+            location,
+            destination_channel_index,
+            destination_location: 0
+        }
+    }
+
+    #[test]
+    fn beats_in_frames() {
+        assert_eq!(bpm_to_spb(120, SAMPLE_RATE), 24000);
+        assert_eq!(bpm_to_spb(60, 44100), 44100);
+        assert_eq!(bpm_to_spb(90, 44100), 29400);
+        // Rounded to the nearest frame
+        assert_eq!(bpm_to_spb(7, 48000), 411429);
+    }
+
+    #[test]
+    fn too_fast_a_tempo_keeps_a_frame_per_beat() {
+        let mut control_loop = ControlLoop::new(4, 10);
+        control_loop.set_tempo(u32::MAX);
+        control_loop.play();
+        assert_eq!(control_loop.update(), Some(0));
+        assert_eq!(control_loop.update(), Some(1));
+    }
+
+    #[test]
+    fn beats_start_on_their_first_frame() {
+        let mut control_loop = ControlLoop::new(2, SAMPLE_RATE);
+        control_loop.set_tempo(6000);
+        assert_eq!(control_loop.update(), None);
+        control_loop.play();
+        let beats: Vec<(usize, u64)> = (0..1500)
+            .filter_map(|frame| control_loop.update().map(|beat| (frame, beat)))
+            .collect();
+        // 480 frames per beat, wrapping after two beats
+        assert_eq!(beats, [(0, 0), (480, 1), (960, 0), (1440, 1)]);
+    }
+
+    #[test]
+    fn triggers_at_their_beat() {
+        let mut control_loop = ControlLoop::new(4, SAMPLE_RATE);
+        control_loop.set_trigger(0, trigger(1, 2));
+        control_loop.set_trigger(5, trigger(1, 3));
+        control_loop.set_trigger(6, Trigger { enabled: false, ..trigger(1, 4) });
+        control_loop.set_trigger(7, trigger(2, 5));
+        let channels: Vec<usize> = control_loop.triggers_at(1)
+            .map(|trigger| trigger.destination_channel_index)
+            .collect();
+        assert_eq!(channels, [2, 3]);
+        assert!(control_loop.has_triggers());
+    }
+
+    #[test]
+    fn bad_triggers_are_dropped() {
+        let mut control_loop = ControlLoop::new(4, SAMPLE_RATE);
+        control_loop.set_trigger(MAX_TRIGGERS, trigger(0, 0));
+        control_loop.set_trigger(0, trigger(0, MAX_CHANNELS));
+        assert!(!control_loop.has_triggers());
+    }
+}
@@ -1,4 +1,8 @@
-use crate::sequencer::{Clip, Junction, Playhead, NUM_CHANNELS};
+use crate::sequencer::{
+    Clip, Junction, Playhead, BoundaryMode, VoiceConfig, ChannelStrip, Trigger, ClockSummary,
+    TransportState, Scene, MAX_CHANNELS, MAX_VOICES_PER_CHANNEL
+};
+use crate::sound::Float;
 
 
 #[derive(Debug, Clone, Copy)]
@@ -17,19 +21,218 @@ pub enum SequencerControlMessage {
         index: ChannelItemIndex,
         junction: Junction
     },
+    // Overwrites the voice at index.item_index
     SyncPlayhead {
         index: ChannelItemIndex,
         playhead: Playhead
+    },
+    // Starts a new voice alongside the ones already playing
+    LaunchPlayhead {
+        channel_index: usize,
+        playhead: Playhead
+    },
+    SetChannelVoices {
+        channel_index: usize,
+        voice_config: VoiceConfig
+    },
+    RemoveClip {
+        index: ChannelItemIndex
+    },
+    RemoveJunction {
+        index: ChannelItemIndex
+    },
+    SetChannelGain {
+        channel_index: usize,
+        gain_db: Float
+    },
+    SetChannelPan {
+        channel_index: usize,
+        pan: Float
+    },
+    SetChannelMute {
+        channel_index: usize,
+        mute: bool
+    },
+    SetChannelSolo {
+        channel_index: usize,
+        solo: bool
+    },
+    SetRandomSeed {
+        seed: u64
+    },
+    // Resets the junction pass counters of one channel, or of all channels
+    ResetJunctionPassCounts {
+        channel_index: Option<usize>
+    },
+    SyncTrigger {
+        index: usize,
+        trigger: Trigger
+    },
+    SetTempo {
+        bpm: u32
+    },
+    SetClockLength {
+        beats: u64
+    },
+    SetClockPlaying {
+        playing: bool
+    },
+    SetNumChannels {
+        num_channels: usize
+    },
+    SetChannelLength {
+        channel_index: usize,
+        length: u64
+    },
+    SetChannelBoundaryMode {
+        channel_index: usize,
+        boundary_mode: BoundaryMode
+    },
+    // Holds every playhead where it is
+    PauseTransport,
+    ResumeTransport,
+    // Stops every playhead and the clock, and rewinds the clock
+    StopAll,
+    // Remembers the current playheads and clock state for ResetTransport
+    SaveTransportStart,
+    ResetTransport,
+    // Scenes are sent one channel at a time to keep messages small
+    SyncScene {
+        index: usize,
+        channel_index: usize,
+        playheads: [Playhead; MAX_VOICES_PER_CHANNEL]
+    },
+    // Applies every channel of the scene on the same frame, at the start of
+    // the next beat if quantized
+    RecallScene {
+        index: usize,
+        quantize: bool
+    },
+    // Stores the playheads of the frame it is handled on, and sends them back
+    // as SceneCaptured
+    CaptureScene {
+        index: usize
     }
 }
 
+// Messages with the same key set the same piece of state, so only the latest
+// one matters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalesceKey {
+    discriminant: std::mem::Discriminant<SequencerControlMessage>,
+    channel_index: usize,
+    item_index: usize
+}
+
+impl SequencerControlMessage {
+    pub fn coalesce_key(&self) -> Option<CoalesceKey> {
+        use SequencerControlMessage::*;
+        let (channel_index, item_index) = match *self {
+            SyncClip { index, .. }
+            | SyncJunction { index, .. }
+            | SyncPlayhead { index, .. } => (index.channel_index, index.item_index),
+            SetChannelGain { channel_index, .. }
+            | SetChannelPan { channel_index, .. }
+            | SetChannelMute { channel_index, .. }
+            | SetChannelSolo { channel_index, .. }
+            | SetChannelBoundaryMode { channel_index, .. } => (channel_index, 0),
+            SyncTrigger { index, .. } => (0, index),
+            SyncScene { index, channel_index, .. } => (channel_index, index),
+            SetRandomSeed { .. }
+            | SetTempo { .. }
+            | SetClockLength { .. }
+            | SetClockPlaying { .. } => (0, 0),
+            // These clear, clamp or stop things on the way, which the
+            // interface mirrors without syncing each item, so every step has
+            // to reach the engine
+            SetNumChannels { .. }
+            | SetChannelLength { .. }
+            | SetChannelVoices { .. }
+            | RemoveClip { .. }
+            | RemoveJunction { .. }
+            | LaunchPlayhead { .. }
+            | ResetJunctionPassCounts { .. }
+            | PauseTransport
+            | ResumeTransport
+            | StopAll
+            | SaveTransportStart
+            | ResetTransport
+            | RecallScene { .. }
+            | CaptureScene { .. } => return None
+        };
+        Some(CoalesceKey {
+            discriminant: std::mem::discriminant(self),
+            channel_index,
+            item_index
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScheduledControlMessage {
+    // Frame (in terms of SequencerSummary::total_frames_processed) at which the
+    // message is applied, or None to apply it as soon as possible
+    pub target_frame: Option<u64>,
+    pub message: SequencerControlMessage
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct SequencerSummary {
-    pub playheads: [Playhead; NUM_CHANNELS],
-    pub total_frames_processed: u64
+    pub playheads: [[Playhead; MAX_VOICES_PER_CHANNEL]; MAX_CHANNELS],
+    pub mixer: [ChannelStrip; MAX_CHANNELS],
+    pub num_channels: usize,
+    pub clock: ClockSummary,
+    pub transport: TransportState,
+    pub total_frames_processed: u64,
+    // Running totals of what the audio thread could not fit in the event
+    // ring buffer
+    pub dropped_events: u64,
+    pub dropped_summaries: u64,
+    // Scheduled messages applied as soon as they arrived because the
+    // schedule was full
+    pub early_messages: u64
 }
 
+// Everything apart from Tick is stamped with the frame (in terms of
+// SequencerSummary::total_frames_processed) on which it happened
 #[derive(Debug, Clone, Copy)]
 pub enum SequencerEvent {
-    Tick(SequencerSummary)
+    Tick(SequencerSummary),
+    JunctionFired {
+        frame: u64,
+        index: ChannelItemIndex,
+        voice_index: usize
+    },
+    PlayheadStarted {
+        frame: u64,
+        channel_index: usize,
+        voice_index: usize,
+        playhead: Playhead
+    },
+    PlayheadStopped {
+        frame: u64,
+        channel_index: usize,
+        voice_index: usize,
+        location: u64
+    },
+    PlayheadReflected {
+        frame: u64,
+        channel_index: usize,
+        voice_index: usize,
+        playhead: Playhead
+    },
+    ClipEntered {
+        frame: u64,
+        index: ChannelItemIndex,
+        voice_index: usize
+    },
+    ClipExited {
+        frame: u64,
+        index: ChannelItemIndex,
+        voice_index: usize
+    },
+    SceneCaptured {
+        index: usize,
+        scene: Scene
+    }
 }
\ No newline at end of file
@@ -0,0 +1,136 @@
+use crate::sequencer::{bpm_to_spb, DEFAULT_BPM, DEFAULT_BEATS_PER_BAR};
+
+
+pub const DEFAULT_SUBDIVISION: u64 = 4;
+pub const MAX_SUBDIVISION: u64 = 32;
+
+// More lines than this and the finer divisions are dropped when drawing
+const MAX_GRID_LINES: u64 = 256;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridLine {
+    Bar,
+    Beat,
+    Subdivision
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Grid {
+    pub bpm: u32,
+    // Divisions per beat
+    pub subdivision: u64,
+    pub beats_per_bar: u64,
+    pub sample_rate: u32
+}
+
+impl Grid {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            bpm: DEFAULT_BPM,
+            subdivision: DEFAULT_SUBDIVISION,
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            sample_rate
+        }
+    }
+
+    pub fn samples_per_beat(&self) -> u64 {
+        bpm_to_spb(self.bpm.max(1), self.sample_rate).max(1)
+    }
+
+    pub fn samples_per_bar(&self) -> u64 {
+        self.samples_per_beat() * self.beats_per_bar.max(1)
+    }
+
+    pub fn spacing(&self) -> u64 {
+        (self.samples_per_beat() / self.subdivision.max(1)).max(1)
+    }
+
+    pub fn snap(&self, location: u64) -> u64 {
+        let spacing = self.spacing();
+        (location + spacing / 2) / spacing * spacing
+    }
+
+    pub fn lines(&self, channel_length: u64) -> impl Iterator<Item = (u64, GridLine)> {
+        let samples_per_beat = self.samples_per_beat();
+        let samples_per_bar = self.samples_per_bar();
+        let spacing = [self.spacing(), samples_per_beat, samples_per_bar]
+            .into_iter()
+            .find(|spacing| channel_length / spacing <= MAX_GRID_LINES)
+            .unwrap_or(samples_per_bar);
+
+        (0..=channel_length / spacing).map(move |i| {
+            let location = i * spacing;
+            let line = if location % samples_per_bar == 0 {
+                GridLine::Bar
+            } else if location % samples_per_beat == 0 {
+                GridLine::Beat
+            } else {
+                GridLine::Subdivision
+            };
+            (location, line)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 24000 samples per beat, 6000 per subdivision
+    fn grid() -> Grid {
+        Grid::new(48000)
+    }
+
+    #[test]
+    fn snap_rounds_to_the_nearest_subdivision() {
+        let grid = grid();
+        assert_eq!(grid.spacing(), 6000);
+        for (location, snapped) in [(0, 0), (2999, 0), (3000, 6000), (6000, 6000), (8999, 6000), (23999, 24000)] {
+            assert_eq!(grid.snap(location), snapped, "{}", location);
+        }
+    }
+
+    #[test]
+    fn zero_settings_still_snap() {
+        let grid = Grid { bpm: 0, subdivision: 0, beats_per_bar: 0, sample_rate: 0 };
+        assert_eq!(grid.spacing(), 1);
+        assert_eq!(grid.snap(1234), 1234);
+        assert_eq!(grid.lines(3).count(), 4);
+    }
+
+    #[test]
+    fn lines_mark_bars_beats_and_subdivisions() {
+        // Every line of the first beat, then only the beats
+        let lines: Vec<(u64, GridLine)> = grid().lines(5 * 24000)
+            .filter(|&(location, _)| location < 24000 || location % 24000 == 0)
+            .collect();
+        assert_eq!(lines, [
+            (0, GridLine::Bar),
+            (6000, GridLine::Subdivision),
+            (12000, GridLine::Subdivision),
+            (18000, GridLine::Subdivision),
+            (24000, GridLine::Beat),
+            (48000, GridLine::Beat),
+            (72000, GridLine::Beat),
+            (96000, GridLine::Bar),
+            (120000, GridLine::Beat)
+        ]);
+        assert_eq!(grid().lines(5 * 24000).count(), 21);
+    }
+
+    #[test]
+    fn long_channels_drop_the_finer_lines() {
+        let grid = grid();
+        // Too many subdivisions, few enough beats
+        let beats: Vec<u64> = grid.lines(200 * 24000).map(|(location, _)| location).collect();
+        assert_eq!(beats.len(), 201);
+        assert!(beats.iter().all(|location| location % 24000 == 0));
+
+        // Too many beats as well, only bars are left
+        let lines: Vec<(u64, GridLine)> = grid.lines(400 * 24000).collect();
+        assert_eq!(lines.len(), 101);
+        assert!(lines.iter().all(|&(_, line)| line == GridLine::Bar));
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::VecDeque;
+
+use crate::sequencer::{Clip, Junction};
+
+
+// Oldest steps are dropped beyond this
+const MAX_UNDO_STEPS: usize = 256;
+
+// A change to a single clip or junction slot.  Adding sets a free slot and
+// deleting clears one, so every edit is a before and after of the slot.
+#[derive(Debug, Clone, Copy)]
+pub enum Edit {
+    Clip {
+        channel_index: usize,
+        clip_index: usize,
+        before: Clip,
+        after: Clip
+    },
+    Junction {
+        channel_index: usize,
+        junction_index: usize,
+        before: Junction,
+        after: Junction
+    }
+}
+
+impl Edit {
+    // Folds a later edit of the same slot into this one
+    fn merge(&mut self, edit: &Edit) -> bool {
+        match (self, edit) {
+            (
+                Edit::Clip { channel_index, clip_index, after, .. },
+                Edit::Clip { channel_index: other_channel_index, clip_index: other_clip_index, after: other_after, .. }
+            ) if channel_index == other_channel_index && clip_index == other_clip_index => {
+                *after = *other_after;
+                true
+            },
+            (
+                Edit::Junction { channel_index, junction_index, after, .. },
+                Edit::Junction {
+                    channel_index: other_channel_index,
+                    junction_index: other_junction_index,
+                    after: other_after,
+                    ..
+                }
+            ) if channel_index == other_channel_index && junction_index == other_junction_index => {
+                *after = *other_after;
+                true
+            },
+            _ => false
+        }
+    }
+
+    fn changes_nothing(&self) -> bool {
+        match self {
+            Edit::Clip { before, after, .. } => before == after,
+            Edit::Junction { before, after, .. } => before == after
+        }
+    }
+}
+
+// Undo history for clip and junction edits.  Edits made between begin_group
+// and end_group, e.g. over a drag, become one step.  Channel settings such as
+// length and boundary mode are not part of the history, so it is cleared when
+// a channel shrinks or is removed.
+#[derive(Debug, Default)]
+pub struct History {
+    undo_steps: VecDeque<Vec<Edit>>,
+    redo_steps: Vec<Vec<Edit>>,
+    group: Option<Vec<Edit>>
+}
+
+impl History {
+    pub fn begin_group(&mut self) {
+        self.end_group();
+        self.group = Some(Vec::new());
+    }
+
+    // A slot dragged back to where it started drops out of the step
+    pub fn end_group(&mut self) {
+        if let Some(mut step) = self.group.take() {
+            step.retain(|edit| !edit.changes_nothing());
+            self.push_step(step);
+        }
+    }
+
+    pub fn record(&mut self, edit: Edit) {
+        if self.group.is_none() && edit.changes_nothing() {
+            return;
+        }
+        match &mut self.group {
+            Some(group) => {
+                if !group.iter_mut().any(|grouped| grouped.merge(&edit)) {
+                    group.push(edit);
+                }
+            },
+            None => self.push_step(vec![edit])
+        }
+    }
+
+    fn push_step(&mut self, step: Vec<Edit>) {
+        if step.is_empty() {
+            return;
+        }
+        if self.undo_steps.len() == MAX_UNDO_STEPS {
+            self.undo_steps.pop_front();
+        }
+        self.undo_steps.push_back(step);
+        self.redo_steps.clear();
+    }
+
+    // Returns the edits to revert, the caller applies each before state in
+    // reverse order
+    pub fn undo(&mut self) -> Option<Vec<Edit>> {
+        self.end_group();
+        let step = self.undo_steps.pop_back()?;
+        self.redo_steps.push(step.clone());
+        Some(step)
+    }
+
+    // Returns the edits to reapply, the caller applies each after state in
+    // order
+    pub fn redo(&mut self) -> Option<Vec<Edit>> {
+        self.end_group();
+        let step = self.redo_steps.pop()?;
+        self.undo_steps.push_back(step.clone());
+        Some(step)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo_steps.clear();
+        self.redo_steps.clear();
+        self.group = None;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip_at(start: u64) -> Clip {
+        Clip {
+            enabled: true,
+            channel_location_start: start,
+            channel_location_end: start + 100,
+            source_scale: 1.0,
+            ..Default::default()
+        }
+    }
+
+    fn move_clip(clip_index: usize, from: u64, to: u64) -> Edit {
+        Edit::Clip {
+            channel_index: 0,
+            clip_index,
+            before: clip_at(from),
+            after: clip_at(to)
+        }
+    }
+
+    // (clip index, start before, start after) of each edit in a step
+    fn starts(step: Option<Vec<Edit>>) -> Vec<(usize, u64, u64)> {
+        step.expect("no step").iter().map(|edit| match edit {
+            Edit::Clip { clip_index, before, after, .. } => {
+                (*clip_index, before.channel_location_start, after.channel_location_start)
+            },
+            Edit::Junction { .. } => panic!("unexpected junction edit")
+        }).collect()
+    }
+
+    #[test]
+    fn undo_and_redo_walk_the_steps() {
+        let mut history = History::default();
+        history.record(move_clip(0, 0, 10));
+        history.record(move_clip(1, 0, 20));
+
+        assert_eq!(starts(history.undo()), [(1, 0, 20)]);
+        assert_eq!(starts(history.undo()), [(0, 0, 10)]);
+        assert!(history.undo().is_none());
+
+        assert_eq!(starts(history.redo()), [(0, 0, 10)]);
+        assert_eq!(starts(history.redo()), [(1, 0, 20)]);
+        assert!(history.redo().is_none());
+        assert_eq!(starts(history.undo()), [(1, 0, 20)]);
+    }
+
+    #[test]
+    fn a_drag_is_one_step() {
+        let mut history = History::default();
+        history.begin_group();
+        history.record(move_clip(0, 0, 10));
+        history.record(move_clip(0, 10, 20));
+        history.record(move_clip(1, 0, 5));
+        history.record(move_clip(0, 20, 30));
+        history.end_group();
+
+        // From where the drag started to where it ended, once per slot
+        assert_eq!(starts(history.undo()), [(0, 0, 30), (1, 0, 5)]);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn an_empty_drag_is_not_a_step() {
+        let mut history = History::default();
+        history.record(move_clip(0, 0, 10));
+        history.begin_group();
+        history.end_group();
+        assert_eq!(starts(history.undo()), [(0, 0, 10)]);
+    }
+
+    #[test]
+    fn edits_that_change_nothing_are_not_steps() {
+        let mut history = History::default();
+        history.record(move_clip(0, 0, 10));
+        history.record(move_clip(0, 10, 10));
+        history.begin_group();
+        history.record(move_clip(1, 0, 5));
+        history.record(move_clip(1, 5, 0));
+        history.end_group();
+        history.begin_group();
+        history.record(move_clip(0, 10, 20));
+        history.record(move_clip(1, 0, 5));
+        history.record(move_clip(0, 20, 10));
+        history.end_group();
+
+        assert_eq!(starts(history.undo()), [(1, 0, 5)]);
+        assert_eq!(starts(history.undo()), [(0, 0, 10)]);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn undo_ends_an_open_group() {
+        let mut history = History::default();
+        history.begin_group();
+        history.record(move_clip(0, 0, 10));
+        assert_eq!(starts(history.undo()), [(0, 0, 10)]);
+        history.record(move_clip(0, 0, 20));
+        assert_eq!(starts(history.undo()), [(0, 0, 20)]);
+    }
+
+    #[test]
+    fn a_new_edit_clears_redo() {
+        let mut history = History::default();
+        history.record(move_clip(0, 0, 10));
+        history.record(move_clip(0, 10, 20));
+        history.undo();
+        history.record(move_clip(0, 10, 30));
+
+        assert!(history.redo().is_none());
+        assert_eq!(starts(history.undo()), [(0, 10, 30)]);
+        assert_eq!(starts(history.undo()), [(0, 0, 10)]);
+    }
+
+    #[test]
+    fn oldest_steps_are_dropped() {
+        let mut history = History::default();
+        for step in 0..MAX_UNDO_STEPS as u64 + 10 {
+            history.record(move_clip(0, step, step + 1));
+        }
+        let mut undone = 0;
+        let mut last = None;
+        while let Some(step) = history.undo() {
+            undone += 1;
+            last = Some(step);
+        }
+        assert_eq!(undone, MAX_UNDO_STEPS);
+        assert_eq!(starts(last), [(0, 10, 11)]);
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut history = History::default();
+        history.record(move_clip(0, 0, 10));
+        history.record(move_clip(0, 10, 20));
+        history.undo();
+        history.begin_group();
+        history.record(move_clip(1, 0, 5));
+        history.clear();
+        history.end_group();
+
+        assert!(history.undo().is_none());
+        assert!(history.redo().is_none());
+    }
+}
@@ -1,17 +1,28 @@
 mod style;
 mod state;
+mod grid;
+mod transport;
+mod scenes;
+mod history;
 
 use wgpu::Color;
-use winit::{event::{WindowEvent, MouseButton, ElementState}, window::Window};
+use winit::{event::{WindowEvent, MouseButton, ElementState, KeyboardInput, VirtualKeyCode, ModifiersState}, window::Window};
 
 pub use state::*;
+pub use grid::*;
+pub use transport::*;
+pub use scenes::*;
+pub use style::Theme;
+use history::{History, Edit};
 use crate::{sequencer::*, ui::input::{InputHandler, Input}, instrument::{Instrument, InstrumentState}};
 use crate::ui::Depth;
 use crate::ui::primitive::{Draw, Primitive, Quad, Text, Line};
 use crate::ui::input::MousePosition;
-use crate::config::CLEAR_COLOR;
 use crate::ui::{Transform, Transformable};
 use crate::ui::primitive::Drawable;
+use crate::ui::{Position, ApplyTransform};
+use crate::sound::{Float, MAX_SOUNDS};
+use crate::project::{Project, ProjectChannel, ProjectScene, PROJECT_VERSION};
 
 
 #[derive(Debug, Default)]
@@ -22,37 +33,141 @@ pub struct ClipInterface {
 
 #[derive(Debug, Default)]
 pub struct JunctionInterface {
-    model: Junction
+    model: Junction,
+    // Frame on which the junction last fired
+    last_fired: Option<u64>
 }
 
 #[derive(Default)]
 pub struct ChannelInterface {
     clips: [ClipInterface; MAX_CLIPS_PER_CHANNEL],
     junctions: [JunctionInterface; MAX_JUNCTIONS_PER_CHANNEL],
-    active_clips: usize,
-    active_junctions: usize,
+    boundary_mode: BoundaryMode,
+    voice_config: VoiceConfig,
+    // Kept here rather than read back from the summary, so that key repeats
+    // build on the last step even before the engine has caught up
+    strip: ChannelStrip
 }
 
+impl ChannelInterface {
+    // Disabled slots are free, removed items leave a disabled slot behind
+    fn free_clip_slot(&self) -> Option<usize> {
+        self.clips.iter().position(|clip| !clip.model.enabled)
+    }
+
+    fn free_junction_slot(&self) -> Option<usize> {
+        self.junctions.iter().position(|junction| !junction.model.enabled)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelFullError {
+    pub channel_index: usize
+}
+
+impl std::fmt::Display for ChannelFullError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "channel {} is full", self.channel_index)
+    }
+}
+
+impl std::error::Error for ChannelFullError {}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AddDestinationError {
+    ChannelFull(ChannelFullError),
+    // Only jumps and random jumps have destinations
+    NotAJump {
+        channel_index: usize,
+        junction_index: usize
+    },
+    DestinationsFull {
+        channel_index: usize,
+        junction_index: usize
+    }
+}
+
+impl std::fmt::Display for AddDestinationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddDestinationError::ChannelFull(error) => write!(f, "{}", error),
+            AddDestinationError::NotAJump { channel_index, junction_index } => write!(
+                f,
+                "channel {} junction {} is not a jump, only jumps can have destinations added",
+                channel_index,
+                junction_index
+            ),
+            AddDestinationError::DestinationsFull { channel_index, junction_index } => write!(
+                f,
+                "channel {} junction {} already has {} destinations",
+                channel_index,
+                junction_index,
+                MAX_JUMP_DESTINATIONS
+            )
+        }
+    }
+}
+
+impl std::error::Error for AddDestinationError {}
+
+impl From<ChannelFullError> for AddDestinationError {
+    fn from(error: ChannelFullError) -> Self {
+        AddDestinationError::ChannelFull(error)
+    }
+}
+
+// How long a junction stays highlighted after firing, about a tenth of a
+// second at 48 kHz
+const JUNCTION_FLASH_FRAMES: u64 = 4800;
+
+// Range of the clip stretch, each X press doubles or halves it
+const MIN_SOURCE_SCALE: Float = 0.125;
+const MAX_SOURCE_SCALE: Float = 8.0;
+
 pub struct SequencerInterface {
     controller: SequencerController,
-    channels: [ChannelInterface; NUM_CHANNELS],
+    channels: [ChannelInterface; MAX_CHANNELS],
+    num_channels: usize,
+    triggers: [Trigger; MAX_TRIGGERS],
+    scenes: [SceneSlot; MAX_SCENES],
+    // Slot being renamed and the name typed so far
+    scene_name_edit: Option<(usize, String)>,
+    history: History,
+    source_lengths: [Option<usize>; MAX_SOUNDS],
     summary: SequencerSummary,
-    channel_length: u64,
+    channel_lengths: [u64; MAX_CHANNELS],
+    // Longest of the active channels, every lane is drawn on this scale
+    timeline_length: u64,
+    grid: Grid,
+    grid_enabled: bool,
     mouse_position: MousePosition,
+    modifiers: ModifiersState,
     state: State,
-    transform: Transform
+    transform: Transform,
+    theme: Theme
 }
 
 impl SequencerInterface {
-    pub fn init(controller: SequencerController) -> Self {
+    pub fn init(controller: SequencerController, sample_rate: usize, theme: Theme) -> Self {
         Self {
             controller,
             channels: Default::default(),
+            num_channels: DEFAULT_NUM_CHANNELS,
+            triggers: [Trigger::default(); MAX_TRIGGERS],
+            scenes: std::array::from_fn(SceneSlot::empty),
+            scene_name_edit: None,
+            history: History::default(),
+            source_lengths: [None; MAX_SOUNDS],
             summary: Default::default(),
-            channel_length: DEFAULT_CHANNEL_LENGTH,
+            channel_lengths: [DEFAULT_CHANNEL_LENGTH; MAX_CHANNELS],
+            timeline_length: DEFAULT_CHANNEL_LENGTH,
+            grid: Grid::new(sample_rate as u32),
+            grid_enabled: true,
             mouse_position: MousePosition::default(),
+            modifiers: ModifiersState::default(),
             state: State::default(),
-            transform: Transform::identity()
+            transform: Transform::identity(),
+            theme
         }
     }
 
@@ -61,25 +176,27 @@ impl SequencerInterface {
     }
 
     fn get_potential_action(&self) -> Action {
-        let channel_index = mouse_position_to_channel_index(self.mouse_position);
-        let channel_location = mouse_position_to_channel_location(self.mouse_position, self.channel_length);
-        if mouse_position_is_on_junction_lane(self.mouse_position, channel_index) {
+        let channel_index = mouse_position_to_channel_index(self.mouse_position, self.num_channels);
+        let channel_location = mouse_position_to_channel_location(self.mouse_position, self.timeline_length);
+        if mouse_position_is_on_junction_lane(self.mouse_position, channel_index, self.num_channels) {
             return Action::Channel {
                 channel_action: ChannelAction::CreateJunction,
                 channel_index,
                 channel_location
             }
         }
-        let channel = &self.channels[channel_index];
-        for clip_index in 0..channel.active_clips {
-            if channel.clips[clip_index].quad.contains(self.mouse_position) {
-                return Action::Channel {
-                    channel_action: ChannelAction::GrabClip { 
-                        clip_index
-                    },
-                    channel_index,
-                    channel_location
-                }
+        if let Some(clip_index) = self.find_clip_under_mouse(channel_index) {
+            let clip = self.channels[channel_index].clips[clip_index].model;
+            let tolerance = (style::CLIP_EDGE_HIT_WIDTH * self.timeline_length as f32) as u64;
+            let channel_action = if clip.channel_location_end.abs_diff(channel_location) <= tolerance {
+                ChannelAction::TrimClip { clip_index }
+            } else {
+                ChannelAction::GrabClip { clip_index }
+            };
+            return Action::Channel {
+                channel_action,
+                channel_index,
+                channel_location
             }
         }
         Action::Channel {
@@ -97,6 +214,20 @@ impl SequencerInterface {
             WindowEvent::CursorMoved { .. } => {
                 self.handle_cursor_move()
             }
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(keycode),
+                    ..
+                },
+                ..
+            } => {
+                self.handle_key_press(*keycode)
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+                self.state
+            }
             _ => self.state
         };
         window.set_cursor_icon(self.state.cursor_icon());
@@ -108,9 +239,10 @@ impl SequencerInterface {
 
     fn handle_mouse_input(&mut self, button: &MouseButton, element_state: &ElementState) -> State {
         match self.state {
-            State::GrabbingClip { .. } => {
+            State::GrabbingClip { .. } | State::TrimmingClip { .. } => {
                 match (button, element_state) {
                     (MouseButton::Left, ElementState::Released) => {
+                        self.history.end_group();
                         State::default()
                     },
                     _ => self.state
@@ -129,17 +261,33 @@ impl SequencerInterface {
             } => {
                 match (button, element_state) {
                     (MouseButton::Left, ElementState::Released) => {
-                        let index = mouse_position_to_channel_index(self.mouse_position);
-                        let location = mouse_position_to_channel_location(self.mouse_position, self.channel_length);
-                        self.handle_create_junction(
-                            source_channel_index,
-                            if index == source_channel_index {
+                        let index = mouse_position_to_channel_index(self.mouse_position, self.num_channels);
+                        let location = self.snap_location(
+                            mouse_position_to_channel_location(self.mouse_position, self.timeline_length)
+                        );
+                        let result = if index == source_channel_index {
+                            self.handle_create_junction(
+                                source_channel_index,
                                 Junction {
                                     enabled: true,
                                     location,
-                                    junction_type: JunctionType::Reflect
+                                    junction_type: JunctionType::Reflect,
+                                    condition: JunctionCondition::Always
                                 }
-                            } else {
+                            ).map(|_| ()).map_err(AddDestinationError::from)
+                        } else if self.modifiers.shift() {
+                            self.handle_add_random_jump_destination(
+                                source_channel_index,
+                                source_channel_location,
+                                JumpDestination {
+                                    channel_index: index,
+                                    location,
+                                    weight: 1.0
+                                }
+                            )
+                        } else {
+                            self.handle_create_junction(
+                                source_channel_index,
                                 Junction {
                                     enabled: true,
                                     location: source_channel_location,
@@ -147,10 +295,14 @@ impl SequencerInterface {
                                         destination_channel_index: index,
                                         destination_location: location,
                                         split: true
-                                    }
+                                    },
+                                    condition: JunctionCondition::Always
                                 }
-                            }
-                        );
+                            ).map(|_| ()).map_err(AddDestinationError::from)
+                        };
+                        if let Err(err) = result {
+                            println!("{}", err);
+                        }
                         State::default()
                     },
                     _ => self.state
@@ -169,6 +321,13 @@ impl SequencerInterface {
                 self.handle_clip_move(channel_index, clip_index, relative_location);
                 self.state
             },
+            State::TrimmingClip {
+                channel_index,
+                clip_index
+            } => {
+                self.handle_clip_trim(channel_index, clip_index);
+                self.state
+            },
             State::Hovering { .. } => State::Hovering {
                     potential_action: self.get_potential_action()
                 },
@@ -178,6 +337,219 @@ impl SequencerInterface {
         }
     }
 
+    fn handle_key_press(&mut self, keycode: VirtualKeyCode) -> State {
+        if self.modifiers.ctrl() {
+            match keycode {
+                VirtualKeyCode::Z if self.modifiers.shift() => self.redo(),
+                VirtualKeyCode::Z => self.undo(),
+                VirtualKeyCode::Y => self.redo(),
+                _ => return self.state
+            }
+            return State::default();
+        }
+        let channel_index = mouse_position_to_channel_index(self.mouse_position, self.num_channels);
+        let channel_location = mouse_position_to_channel_location(self.mouse_position, self.timeline_length);
+        if let Some(junction_index) = self.find_junction_near(channel_index, channel_location) {
+            if self.handle_junction_key_press(channel_index, junction_index, keycode) {
+                return self.state;
+            }
+        }
+        if let Some(scene_index) = keycode_to_scene_index(keycode) {
+            if self.modifiers.shift() {
+                self.capture_scene(scene_index);
+            } else {
+                // Holding alt recalls straight away instead of on the beat
+                self.recall_scene(scene_index, !self.modifiers.alt());
+            }
+            return self.state;
+        }
+        if let Some(clip_index) = self.find_clip_under_mouse(channel_index) {
+            if self.handle_clip_key_press(channel_index, clip_index, keycode) {
+                return self.state;
+            }
+        }
+        let strip = self.channels[channel_index].strip;
+        match keycode {
+            VirtualKeyCode::Delete | VirtualKeyCode::Back => {
+                self.handle_delete(channel_index, channel_location);
+            },
+            VirtualKeyCode::E => {
+                self.set_channel_length(channel_index, self.snap_location(channel_location));
+            },
+            VirtualKeyCode::Comma => {
+                let length = self.channel_lengths[channel_index];
+                self.set_channel_length(channel_index, length.saturating_sub(self.grid.samples_per_bar()));
+            },
+            VirtualKeyCode::Period => {
+                let length = self.channel_lengths[channel_index];
+                self.set_channel_length(channel_index, length + self.grid.samples_per_bar());
+            },
+            VirtualKeyCode::B => {
+                let boundary_mode = match self.channels[channel_index].boundary_mode {
+                    BoundaryMode::Stop => BoundaryMode::Wrap,
+                    BoundaryMode::Wrap => BoundaryMode::Bounce,
+                    BoundaryMode::Bounce => BoundaryMode::Stop
+                };
+                self.set_channel_boundary_mode(channel_index, boundary_mode);
+            },
+            VirtualKeyCode::V if self.modifiers.shift() => {
+                let voice_config = self.channels[channel_index].voice_config;
+                self.set_channel_voices(channel_index, VoiceConfig {
+                    steal_policy: match voice_config.steal_policy {
+                        VoiceStealPolicy::Oldest => VoiceStealPolicy::Quietest,
+                        VoiceStealPolicy::Quietest => VoiceStealPolicy::Oldest
+                    },
+                    ..voice_config
+                });
+            },
+            VirtualKeyCode::V => {
+                let voice_config = self.channels[channel_index].voice_config;
+                self.set_channel_voices(channel_index, VoiceConfig {
+                    limit: voice_config.limit % MAX_VOICES_PER_CHANNEL + 1,
+                    ..voice_config
+                });
+            },
+            VirtualKeyCode::Space => self.handle_transport_button(TransportButton::PlayPause),
+            VirtualKeyCode::Escape => self.handle_transport_button(TransportButton::Stop),
+            VirtualKeyCode::Home if self.modifiers.shift() => {
+                self.handle_transport_button(TransportButton::SaveStart);
+            },
+            VirtualKeyCode::Home => self.handle_transport_button(TransportButton::Reset),
+            VirtualKeyCode::R => self.reset_junction_pass_counts(None),
+            VirtualKeyCode::M => self.set_channel_mute(channel_index, !strip.mute),
+            VirtualKeyCode::S => self.set_channel_solo(channel_index, !strip.solo),
+            VirtualKeyCode::Up => self.set_channel_gain(channel_index, strip.gain_db + 1.0),
+            VirtualKeyCode::Down => self.set_channel_gain(channel_index, strip.gain_db - 1.0),
+            VirtualKeyCode::Left => self.set_channel_pan(channel_index, strip.pan - 0.1),
+            VirtualKeyCode::Right => self.set_channel_pan(channel_index, strip.pan + 0.1),
+            VirtualKeyCode::G => self.grid_enabled = !self.grid_enabled,
+            VirtualKeyCode::LBracket => {
+                self.grid.subdivision = (self.grid.subdivision / 2).max(1);
+            },
+            VirtualKeyCode::RBracket => {
+                self.grid.subdivision = (self.grid.subdivision * 2).min(MAX_SUBDIVISION);
+            },
+            VirtualKeyCode::P => self.set_clock_playing(!self.summary.clock.playing),
+            VirtualKeyCode::Equals | VirtualKeyCode::NumpadAdd => {
+                self.set_tempo(self.summary.clock.bpm + self.tempo_step());
+            },
+            VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract => {
+                self.set_tempo(self.summary.clock.bpm.saturating_sub(self.tempo_step()));
+            },
+            VirtualKeyCode::N if self.modifiers.shift() => {
+                self.set_num_channels(self.num_channels - 1);
+            },
+            VirtualKeyCode::N => self.set_num_channels(self.num_channels + 1),
+            VirtualKeyCode::T if self.modifiers.shift() => {
+                self.remove_triggers(channel_index);
+            },
+            VirtualKeyCode::T => {
+                self.add_trigger(Trigger {
+                    enabled: true,
+                    location: self.summary.clock.beat,
+                    destination_channel_index: channel_index,
+                    destination_location: self.snap_location(channel_location)
+                });
+            },
+            _ => {}
+        }
+        self.state
+    }
+
+    // C cycles the condition and digits set its count.  On alternating
+    // junctions A cycles the alternate between stop and reflect, and shift A
+    // swaps it with the junction's own type so that jumps can alternate too.
+    // Returns whether the key press was used.
+    fn handle_junction_key_press(
+        &mut self,
+        channel_index: usize,
+        junction_index: usize,
+        keycode: VirtualKeyCode
+    ) -> bool {
+        let junction = self.channels[channel_index].junctions[junction_index].model;
+        let condition = match (keycode, junction.condition) {
+            (VirtualKeyCode::C, JunctionCondition::Always) => {
+                JunctionCondition::EveryNth { n: 2 }
+            },
+            (VirtualKeyCode::C, JunctionCondition::EveryNth { n }) => {
+                JunctionCondition::FirstN { n }
+            },
+            (VirtualKeyCode::C, JunctionCondition::FirstN { .. }) => {
+                JunctionCondition::Alternate { alternate: JunctionType::Stop }
+            },
+            (VirtualKeyCode::C, JunctionCondition::Alternate { .. }) => {
+                JunctionCondition::Always
+            },
+            (VirtualKeyCode::A, JunctionCondition::Alternate { alternate }) if self.modifiers.shift() => {
+                self.set_junction(channel_index, junction_index, Junction {
+                    junction_type: alternate,
+                    condition: JunctionCondition::Alternate { alternate: junction.junction_type },
+                    ..junction
+                });
+                return true;
+            },
+            (VirtualKeyCode::A, JunctionCondition::Alternate { alternate }) => {
+                let alternate = match alternate {
+                    JunctionType::Stop => JunctionType::Reflect,
+                    _ => JunctionType::Stop
+                };
+                JunctionCondition::Alternate { alternate }
+            },
+            (keycode, JunctionCondition::EveryNth { .. }) => {
+                let Some(n) = keycode_to_digit(keycode) else { return false };
+                JunctionCondition::EveryNth { n: n.max(1) }
+            },
+            (keycode, JunctionCondition::FirstN { .. }) => {
+                let Some(n) = keycode_to_digit(keycode) else { return false };
+                JunctionCondition::FirstN { n }
+            },
+            _ => return false
+        };
+        self.set_junction_condition(channel_index, junction_index, condition);
+        true
+    }
+
+    // X stretches the source under the clip, D skips further into it, with
+    // shift going the other way.  Returns whether the key press was used.
+    fn handle_clip_key_press(
+        &mut self,
+        channel_index: usize,
+        clip_index: usize,
+        keycode: VirtualKeyCode
+    ) -> bool {
+        let model = self.channels[channel_index].clips[clip_index].model;
+        let reverse = self.modifiers.shift();
+        let model = match keycode {
+            VirtualKeyCode::X => {
+                let scale = if reverse { model.source_scale / 2.0 } else { model.source_scale * 2.0 };
+                Clip {
+                    source_scale: scale.clamp(MIN_SOURCE_SCALE, MAX_SOURCE_SCALE),
+                    ..model
+                }
+            },
+            VirtualKeyCode::D => {
+                let step = self.grid.spacing();
+                let shift = if reverse {
+                    model.source_shift.saturating_sub(step)
+                } else {
+                    model.source_shift + step
+                };
+                // Keep at least one frame of the sound
+                let max_shift = match self.source_lengths.get(model.source_index) {
+                    Some(&Some(source_length)) => (source_length as u64).saturating_sub(1),
+                    _ => u64::MAX
+                };
+                Clip {
+                    source_shift: shift.min(max_shift),
+                    ..model
+                }
+            },
+            _ => return false
+        };
+        self.set_clip(channel_index, clip_index, model);
+        true
+    }
+
     fn handle_action(&mut self, action: Action, button: &MouseButton, element_state: &ElementState) -> State {
         match action {
             Action::Channel {
@@ -190,8 +562,11 @@ impl SequencerInterface {
                         match (button, element_state) {
                             (MouseButton::Left, ElementState::Pressed) => {
                                 let clip = &self.channels[channel_index].clips[clip_index];
+                                let relative_location = channel_location
+                                    .saturating_sub(clip.model.channel_location_start);
+                                self.history.begin_group();
                                 State::GrabbingClip {
-                                    relative_location: channel_location - clip.model.channel_location_start,
+                                    relative_location,
                                     channel_index,
                                     clip_index,
                                 }
@@ -199,11 +574,23 @@ impl SequencerInterface {
                             _ => self.state
                         }
                     },
+                    ChannelAction::TrimClip { clip_index } => {
+                        match (button, element_state) {
+                            (MouseButton::Left, ElementState::Pressed) => {
+                                self.history.begin_group();
+                                State::TrimmingClip {
+                                    channel_index,
+                                    clip_index
+                                }
+                            },
+                            _ => self.state
+                        }
+                    },
                     ChannelAction::CreateJunction => {
                         match (button, element_state) {
                             (MouseButton::Left, ElementState::Pressed) => State::CreatingJunction {
                                 source_channel_index: channel_index,
-                                source_channel_location: channel_location
+                                source_channel_location: self.snap_location(channel_location)
                             },
                             _ => self.state
                         }
@@ -234,93 +621,763 @@ impl SequencerInterface {
         }
     }
 
+    fn is_snapping(&self) -> bool {
+        // Holding alt bypasses the grid
+        self.grid_enabled && !self.modifiers.alt()
+    }
+
+    fn snap_location(&self, location: u64) -> u64 {
+        if !self.is_snapping() {
+            return location;
+        }
+        self.grid.snap(location).min(self.timeline_length)
+    }
+
+    // Snaps whichever end of the clip is closer to a grid line
+    fn snap_clip_start(&self, start: u64, width: u64) -> u64 {
+        if !self.is_snapping() {
+            return start;
+        }
+        let snapped_start = self.grid.snap(start);
+        let snapped_end = self.grid.snap(start + width);
+        if snapped_start.abs_diff(start) <= snapped_end.abs_diff(start + width) {
+            snapped_start
+        } else {
+            snapped_end.saturating_sub(width)
+        }
+    }
+
     pub fn handle_clip_move(&mut self, channel_index: usize, clip_index: usize, relative_location: u64) {
-        let clip = &mut self.channels[channel_index].clips[clip_index];
-        let width = clip.model.channel_location_end - clip.model.channel_location_start;
-        let start = mouse_position_to_channel_location(self.mouse_position, self.channel_length)
-            .saturating_sub(relative_location)
-            .min(self.channel_length - width);
-        
-        clip.model.channel_location_start = start;
-        clip.model.channel_location_end = start + width;
-        clip.quad = clip_to_quad(
-            channel_index,
-            self.channel_length,
-            clip.model
+        let model = self.channels[channel_index].clips[clip_index].model;
+        let width = model.channel_location_end - model.channel_location_start;
+        let start = mouse_position_to_channel_location(self.mouse_position, self.timeline_length)
+            .saturating_sub(relative_location);
+        let start = self.snap_clip_start(start, width)
+            .min(self.channel_lengths[channel_index].saturating_sub(width));
+
+        self.set_clip(channel_index, clip_index, Clip {
+            channel_location_start: start,
+            channel_location_end: start + width,
+            ..model
+        });
+    }
+
+    pub fn handle_clip_trim(&mut self, channel_index: usize, clip_index: usize) {
+        let model = self.channels[channel_index].clips[clip_index].model;
+        let end = self.snap_location(
+            mouse_position_to_channel_location(self.mouse_position, self.timeline_length)
         );
-        self.controller.control_message_sender.push(
-            SequencerControlMessage::SyncClip {
-                index: ChannelItemIndex {
-                    channel_index,
-                    item_index: clip_index
-                },
-                clip: clip.model
-            }
-        ).unwrap();
+        let min_end = model.channel_location_start + 1;
+        let end = end.clamp(min_end, self.max_clip_end(channel_index, model).max(min_end));
+        self.set_clip(channel_index, clip_index, Clip {
+            channel_location_end: end,
+            ..model
+        });
     }
 
-    pub fn handle_create_junction(&mut self, channel_index: usize, model: Junction) {
-        let channel = &mut self.channels[channel_index];
-        channel.junctions[channel.active_junctions] = JunctionInterface {
-            model
+    // Where a clip starting where it does runs out of sound or channel
+    fn max_clip_end(&self, channel_index: usize, model: Clip) -> u64 {
+        let source_end = match self.source_lengths.get(model.source_index) {
+            Some(&Some(source_length)) => model.channel_location_start + model.max_length(source_length),
+            _ => u64::MAX
         };
-        self.controller.control_message_sender.push(
-            SequencerControlMessage::SyncJunction {
-                index: ChannelItemIndex {
-                    channel_index,
-                    item_index: channel.active_junctions
-                },
-                junction: model
-            }
-        ).unwrap();
+        source_end.min(self.channel_lengths[channel_index])
+    }
 
-        channel.active_junctions += 1;
+    // Clips are kept within their sound, so the interface is told how long
+    // each one is whenever the sound bank changes
+    pub fn set_source_length(&mut self, source_index: usize, length: Option<usize>) {
+        if let Some(slot) = self.source_lengths.get_mut(source_index) {
+            *slot = length;
+        }
     }
 
-    pub fn add_clip(&mut self, channel_index: usize, model: Clip) {
-        let channel = &mut self.channels[channel_index];
-        channel.clips[channel.active_clips] = ClipInterface {
+    // Clips never play past the end of their sound
+    fn constrain_clip(&self, mut model: Clip) -> Clip {
+        if let Some(&Some(source_length)) = self.source_lengths.get(model.source_index) {
+            model.constrain_to_source(source_length);
+        }
+        model
+    }
+
+    // Every change to a clip or junction slot goes through set_clip or
+    // set_junction so that it ends up in the history.  Setting a slot to what
+    // it already holds is not an edit.
+    fn set_clip(&mut self, channel_index: usize, clip_index: usize, model: Clip) {
+        let model = if model.enabled { self.constrain_clip(model) } else { model };
+        let before = self.channels[channel_index].clips[clip_index].model;
+        if before == model {
+            return;
+        }
+        self.history.record(Edit::Clip {
+            channel_index,
+            clip_index,
+            before,
+            after: model
+        });
+        self.apply_clip(channel_index, clip_index, model);
+    }
+
+    fn set_junction(&mut self, channel_index: usize, junction_index: usize, model: Junction) {
+        let before = self.channels[channel_index].junctions[junction_index].model;
+        if before == model {
+            return;
+        }
+        self.history.record(Edit::Junction {
+            channel_index,
+            junction_index,
+            before,
+            after: model
+        });
+        self.apply_junction(channel_index, junction_index, model);
+    }
+
+    fn apply_clip(&mut self, channel_index: usize, clip_index: usize, model: Clip) {
+        self.channels[channel_index].clips[clip_index] = ClipInterface {
             model,
             quad: clip_to_quad(
                 channel_index,
-                self.channel_length,
-                model
+                self.num_channels,
+                self.timeline_length,
+                model,
+                self.theme.clip
             )
         };
-        self.controller.control_message_sender.push(
-            SequencerControlMessage::SyncClip {
-                index: ChannelItemIndex {
-                    channel_index,
-                    item_index: channel.active_clips
+        let index = ChannelItemIndex {
+            channel_index,
+            item_index: clip_index
+        };
+        if model.enabled {
+            self.send(SequencerControlMessage::SyncClip { index, clip: model });
+        } else {
+            self.send(SequencerControlMessage::RemoveClip { index });
+        }
+    }
+
+    fn apply_junction(&mut self, channel_index: usize, junction_index: usize, model: Junction) {
+        self.channels[channel_index].junctions[junction_index] = JunctionInterface {
+            model,
+            last_fired: None
+        };
+        let index = ChannelItemIndex {
+            channel_index,
+            item_index: junction_index
+        };
+        if model.enabled {
+            self.send(SequencerControlMessage::SyncJunction { index, junction: model });
+        } else {
+            self.send(SequencerControlMessage::RemoveJunction { index });
+        }
+    }
+
+    fn apply_edit(&mut self, edit: Edit, undo: bool) {
+        match edit {
+            Edit::Clip { channel_index, clip_index, before, after } => {
+                self.apply_clip(channel_index, clip_index, if undo { before } else { after });
+            },
+            Edit::Junction { channel_index, junction_index, before, after } => {
+                self.apply_junction(channel_index, junction_index, if undo { before } else { after });
+            }
+        }
+    }
+
+    pub fn undo(&mut self) {
+        if let Some(step) = self.history.undo() {
+            for edit in step.into_iter().rev() {
+                self.apply_edit(edit, true);
+            }
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some(step) = self.history.redo() {
+            for edit in step {
+                self.apply_edit(edit, false);
+            }
+        }
+    }
+
+    // For patches set up in code, which are not edits that can be undone
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    pub fn handle_create_junction(
+        &mut self,
+        channel_index: usize,
+        mut model: Junction
+    ) -> Result<usize, ChannelFullError> {
+        model.location = model.location.min(self.channel_lengths[channel_index] - 1);
+        let junction_index = self.channels[channel_index].free_junction_slot()
+            .ok_or(ChannelFullError { channel_index })?;
+        self.set_junction(channel_index, junction_index, model);
+        Ok(junction_index)
+    }
+
+    // Removes the junction near the mouse, or else the clip under it
+    fn handle_delete(&mut self, channel_index: usize, channel_location: u64) {
+        if let Some(junction_index) = self.find_junction_near(channel_index, channel_location) {
+            self.remove_junction(channel_index, junction_index);
+        } else if let Some(clip_index) = self.find_clip_under_mouse(channel_index) {
+            self.remove_clip(channel_index, clip_index);
+        } else {
+            return;
+        }
+        self.state = State::default();
+    }
+
+    pub fn remove_junction(&mut self, channel_index: usize, junction_index: usize) {
+        self.set_junction(channel_index, junction_index, Junction::default());
+    }
+
+    pub fn remove_clip(&mut self, channel_index: usize, clip_index: usize) {
+        self.set_clip(channel_index, clip_index, Clip::default());
+    }
+
+    // Adds a destination to the jump junction at (or near) the given location,
+    // turning it into a random jump, or creates a new random jump if there is
+    // no junction there yet.  Anything else there is left alone.
+    pub fn handle_add_random_jump_destination(
+        &mut self,
+        channel_index: usize,
+        location: u64,
+        destination: JumpDestination
+    ) -> Result<(), AddDestinationError> {
+        let Some(junction_index) = self.find_junction_near(channel_index, location) else {
+            let mut destinations = [JumpDestination::default(); MAX_JUMP_DESTINATIONS];
+            destinations[0] = destination;
+            self.handle_create_junction(channel_index, Junction {
+                enabled: true,
+                location,
+                junction_type: JunctionType::RandomJump {
+                    destinations,
+                    num_destinations: 1,
+                    split: true
                 },
-                clip: model
+                condition: JunctionCondition::Always
+            })?;
+            return Ok(());
+        };
+
+        let mut junction = self.channels[channel_index].junctions[junction_index].model;
+        junction.junction_type = match junction.junction_type {
+            JunctionType::Jump {
+                destination_channel_index,
+                destination_location,
+                split
+            } => {
+                let mut destinations = [JumpDestination::default(); MAX_JUMP_DESTINATIONS];
+                destinations[0] = JumpDestination {
+                    channel_index: destination_channel_index,
+                    location: destination_location,
+                    weight: 1.0
+                };
+                destinations[1] = destination;
+                JunctionType::RandomJump {
+                    destinations,
+                    num_destinations: 2,
+                    split
+                }
+            },
+            JunctionType::RandomJump {
+                mut destinations,
+                num_destinations,
+                split
+            } if num_destinations < MAX_JUMP_DESTINATIONS => {
+                destinations[num_destinations] = destination;
+                JunctionType::RandomJump {
+                    destinations,
+                    num_destinations: num_destinations + 1,
+                    split
+                }
+            },
+            JunctionType::RandomJump { .. } => {
+                return Err(AddDestinationError::DestinationsFull { channel_index, junction_index });
+            },
+            JunctionType::Reflect | JunctionType::Stop => {
+                return Err(AddDestinationError::NotAJump { channel_index, junction_index });
             }
-        ).unwrap();
-        channel.active_clips += 1;
+        };
+        self.set_junction(channel_index, junction_index, junction);
+        Ok(())
     }
 
-    pub fn handle_set_playhead(&mut self, channel_index: usize, playhead: Playhead) {
-        self.controller.control_message_sender.push(
-            SequencerControlMessage::SyncPlayhead {
-                index: ChannelItemIndex {
+    pub fn set_junction_condition(
+        &mut self,
+        channel_index: usize,
+        junction_index: usize,
+        condition: JunctionCondition
+    ) {
+        let junction = self.channels[channel_index].junctions[junction_index].model;
+        self.set_junction(channel_index, junction_index, Junction {
+            condition,
+            ..junction
+        });
+    }
+
+    fn tempo_step(&self) -> u32 {
+        if self.modifiers.shift() { 10 } else { 1 }
+    }
+
+    pub fn set_tempo(&mut self, bpm: u32) {
+        self.send(
+            SequencerControlMessage::SetTempo { bpm: bpm.max(1) }
+        );
+    }
+
+    pub fn set_clock_playing(&mut self, playing: bool) {
+        self.send(
+            SequencerControlMessage::SetClockPlaying { playing }
+        );
+    }
+
+    pub fn set_clock_length(&mut self, beats: u64) {
+        self.send(
+            SequencerControlMessage::SetClockLength { beats: beats.max(1) }
+        );
+    }
+
+    pub fn add_trigger(&mut self, trigger: Trigger) {
+        if let Some(index) = self.triggers.iter().position(|trigger| !trigger.enabled) {
+            self.triggers[index] = trigger;
+            self.sync_trigger(index);
+        }
+    }
+
+    pub fn remove_triggers(&mut self, channel_index: usize) {
+        for index in 0..MAX_TRIGGERS {
+            let trigger = &mut self.triggers[index];
+            if trigger.enabled && trigger.destination_channel_index == channel_index {
+                trigger.enabled = false;
+                self.sync_trigger(index);
+            }
+        }
+    }
+
+    fn sync_trigger(&mut self, index: usize) {
+        self.send(
+            SequencerControlMessage::SyncTrigger {
+                index,
+                trigger: self.triggers[index]
+            }
+        );
+    }
+
+    pub fn reset_junction_pass_counts(&mut self, channel_index: Option<usize>) {
+        self.send(
+            SequencerControlMessage::ResetJunctionPassCounts { channel_index }
+        );
+    }
+
+    fn find_junction_near(&self, channel_index: usize, location: u64) -> Option<usize> {
+        let tolerance = (style::JUNCTION_HIT_WIDTH * self.timeline_length as f32) as u64;
+        let channel = &self.channels[channel_index];
+        channel.junctions.iter().position(|junction| {
+            junction.model.enabled && junction.model.location.abs_diff(location) <= tolerance
+        })
+    }
+
+    fn find_clip_under_mouse(&self, channel_index: usize) -> Option<usize> {
+        self.channels[channel_index].clips.iter().position(|clip| {
+            clip.model.enabled && clip.quad.contains(self.mouse_position)
+        })
+    }
+
+    pub fn add_clip(&mut self, channel_index: usize, model: Clip) -> Result<usize, ChannelFullError> {
+        let clip_index = self.channels[channel_index].free_clip_slot()
+            .ok_or(ChannelFullError { channel_index })?;
+        self.set_clip(channel_index, clip_index, model);
+        Ok(clip_index)
+    }
+
+    // Channels above the new count are cleared, along with any triggers that
+    // launch them
+    pub fn set_num_channels(&mut self, num_channels: usize) {
+        let num_channels = num_channels.clamp(1, MAX_CHANNELS);
+        if num_channels == self.num_channels {
+            return;
+        }
+        for channel_index in num_channels..self.num_channels {
+            self.channels[channel_index] = ChannelInterface::default();
+            self.channel_lengths[channel_index] = DEFAULT_CHANNEL_LENGTH;
+            self.remove_triggers(channel_index);
+        }
+        if num_channels < self.num_channels {
+            self.history.clear();
+        }
+        self.num_channels = num_channels;
+        self.send(
+            SequencerControlMessage::SetNumChannels { num_channels }
+        );
+
+        // Lane heights depend on the number of channels
+        self.update_layout();
+        self.state = State::default();
+    }
+
+    // Mirrors the clamping done by the sequencer, clips and junctions past the
+    // new end are cut or disabled
+    pub fn set_channel_length(&mut self, channel_index: usize, length: u64) {
+        let length = length.max(1);
+        if length < self.channel_lengths[channel_index] {
+            self.history.clear();
+        }
+        self.channel_lengths[channel_index] = length;
+        let channel = &mut self.channels[channel_index];
+        for clip in &mut channel.clips {
+            clip.model.clamp_to_length(length);
+        }
+        for junction in &mut channel.junctions {
+            junction.model.clamp_to_length(length);
+        }
+        self.send(
+            SequencerControlMessage::SetChannelLength { channel_index, length }
+        );
+
+        self.update_layout();
+        self.state = State::default();
+    }
+
+    pub fn set_channel_boundary_mode(&mut self, channel_index: usize, boundary_mode: BoundaryMode) {
+        self.channels[channel_index].boundary_mode = boundary_mode;
+        self.send(
+            SequencerControlMessage::SetChannelBoundaryMode { channel_index, boundary_mode }
+        );
+    }
+
+    fn update_layout(&mut self) {
+        let num_channels = self.num_channels;
+        self.timeline_length = self.channel_lengths[..num_channels].iter()
+            .copied()
+            .max()
+            .unwrap_or(DEFAULT_CHANNEL_LENGTH);
+        for (channel_index, channel) in self.channels[..num_channels].iter_mut().enumerate() {
+            for clip in channel.clips.iter_mut().filter(|clip| clip.model.enabled) {
+                clip.quad = clip_to_quad(
                     channel_index,
-                    item_index: 0
-                },
+                    num_channels,
+                    self.timeline_length,
+                    clip.model,
+                    self.theme.clip
+                );
+            }
+        }
+    }
+
+    // Clicking launches a new voice rather than moving an existing one, so
+    // repeated clicks layer
+    pub fn handle_set_playhead(&mut self, channel_index: usize, playhead: Playhead) {
+        self.send(
+            SequencerControlMessage::LaunchPlayhead {
+                channel_index,
                 playhead
             }
-        ).unwrap();
+        );
+    }
+
+    pub fn set_channel_voices(&mut self, channel_index: usize, voice_config: VoiceConfig) {
+        self.channels[channel_index].voice_config = voice_config;
+        self.send(
+            SequencerControlMessage::SetChannelVoices { channel_index, voice_config }
+        );
+    }
+
+    pub fn set_channel_gain(&mut self, channel_index: usize, gain_db: Float) {
+        let gain_db = gain_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+        self.channels[channel_index].strip.gain_db = gain_db;
+        self.send(
+            SequencerControlMessage::SetChannelGain { channel_index, gain_db }
+        );
+    }
+
+    pub fn set_channel_pan(&mut self, channel_index: usize, pan: Float) {
+        let pan = pan.clamp(-1.0, 1.0);
+        self.channels[channel_index].strip.pan = pan;
+        self.send(
+            SequencerControlMessage::SetChannelPan { channel_index, pan }
+        );
+    }
+
+    pub fn set_channel_mute(&mut self, channel_index: usize, mute: bool) {
+        self.channels[channel_index].strip.mute = mute;
+        self.send(
+            SequencerControlMessage::SetChannelMute { channel_index, mute }
+        );
+    }
+
+    pub fn set_channel_solo(&mut self, channel_index: usize, solo: bool) {
+        self.channels[channel_index].strip.solo = solo;
+        self.send(
+            SequencerControlMessage::SetChannelSolo { channel_index, solo }
+        );
+    }
+
+    pub fn handle_transport_button(&mut self, button: TransportButton) {
+        let message = match button {
+            TransportButton::PlayPause => match self.summary.transport {
+                TransportState::Playing => SequencerControlMessage::PauseTransport,
+                TransportState::Paused => SequencerControlMessage::ResumeTransport
+            },
+            TransportButton::Stop => SequencerControlMessage::StopAll,
+            TransportButton::Reset => SequencerControlMessage::ResetTransport,
+            TransportButton::SaveStart => SequencerControlMessage::SaveTransportStart
+        };
+        self.send(message);
+    }
+
+    // The engine captures the scene on the frame the message arrives and
+    // sends it back, until then the slot shows the latest summary
+    pub fn capture_scene(&mut self, index: usize) {
+        self.scenes[index].scene = Some(Scene { playheads: self.summary.playheads });
+        self.send(SequencerControlMessage::CaptureScene { index });
+    }
+
+    fn sync_scene(&mut self, index: usize) {
+        let Some(scene) = self.scenes[index].scene else {
+            return;
+        };
+        for (channel_index, playheads) in scene.playheads.into_iter().enumerate() {
+            self.send(SequencerControlMessage::SyncScene { index, channel_index, playheads });
+        }
+    }
+
+    pub fn recall_scene(&mut self, index: usize, quantize: bool) {
+        if self.scenes[index].scene.is_some() {
+            self.send(SequencerControlMessage::RecallScene { index, quantize });
+        }
+    }
+
+    // Recalls a captured scene on the next beat, or straight away while the
+    // clock is stopped, or captures into an empty slot
+    pub fn handle_scene_slot(&mut self, index: usize) {
+        if self.scenes[index].scene.is_some() {
+            self.recall_scene(index, self.summary.clock.playing);
+        } else {
+            self.capture_scene(index);
+        }
+    }
+
+    pub fn scenes(&self) -> &[SceneSlot; MAX_SCENES] {
+        &self.scenes
+    }
+
+    // Starts from the current name, typing goes to the name until Enter or
+    // Escape
+    pub fn start_scene_name_edit(&mut self, index: usize) {
+        self.finish_scene_name_edit(true);
+        self.scene_name_edit = Some((index, self.scenes[index].name.clone()));
+    }
+
+    // An empty name goes back to the default one
+    pub fn finish_scene_name_edit(&mut self, keep: bool) {
+        if let Some((index, name)) = self.scene_name_edit.take() {
+            if keep {
+                let name = name.trim();
+                self.scenes[index].name = if name.is_empty() {
+                    SceneSlot::empty(index).name
+                } else {
+                    String::from(name)
+                };
+            }
+        }
+    }
+
+    pub fn scene_name_edit(&self) -> Option<(usize, &str)> {
+        self.scene_name_edit.as_ref().map(|(index, name)| (*index, name.as_str()))
+    }
+
+    pub fn handle_scene_name_event(&mut self, event: &WindowEvent) {
+        let Some((_, name)) = &mut self.scene_name_edit else {
+            return;
+        };
+        match event {
+            WindowEvent::ReceivedCharacter(c) if !c.is_control() && name.chars().count() < MAX_SCENE_NAME_LENGTH => {
+                name.push(*c);
+            },
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(keycode),
+                    ..
+                },
+                ..
+            } => match keycode {
+                VirtualKeyCode::Back => {
+                    name.pop();
+                },
+                VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => self.finish_scene_name_edit(true),
+                VirtualKeyCode::Escape => self.finish_scene_name_edit(false),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    pub fn transport(&self) -> TransportState {
+        self.summary.transport
+    }
+
+    // Everything apart from the sounds, which the sequencer only knows by
+    // index
+    pub fn save_project(&self) -> Project {
+        let channels = (0..self.num_channels).map(|channel_index| {
+            let channel = &self.channels[channel_index];
+            ProjectChannel {
+                length: self.channel_lengths[channel_index],
+                boundary_mode: channel.boundary_mode,
+                voice_config: channel.voice_config,
+                strip: self.channels[channel_index].strip,
+                clips: channel.clips.iter()
+                    .map(|clip| clip.model)
+                    .filter(|clip| clip.enabled)
+                    .collect(),
+                junctions: channel.junctions.iter()
+                    .map(|junction| junction.model)
+                    .filter(|junction| junction.enabled)
+                    .collect()
+            }
+        }).collect();
+        let scenes = self.scenes.iter().enumerate().filter_map(|(index, slot)| {
+            let scene = slot.scene?;
+            Some(ProjectScene {
+                index,
+                name: slot.name.clone(),
+                channels: scene.playheads[..self.num_channels].iter().map(|playheads| {
+                    playheads.iter()
+                        .filter(|playhead| matches!(playhead.state, PlayheadState::Playing))
+                        .copied()
+                        .collect()
+                }).collect()
+            })
+        }).collect();
+        Project {
+            version: PROJECT_VERSION,
+            sample_rate: self.grid.sample_rate as usize,
+            bpm: self.grid.bpm,
+            loop_length: if self.summary.clock.length > 0 {
+                self.summary.clock.length
+            } else {
+                DEFAULT_LOOP_LENGTH
+            },
+            sounds: Vec::new(),
+            channels,
+            triggers: self.triggers.iter().copied().filter(|trigger| trigger.enabled).collect(),
+            scenes
+        }
+    }
+
+    // Replaces the whole patch, stopping playback and clearing the history.
+    // Anything past the sequencer's limits is left out.
+    pub fn load_project(&mut self, project: &Project) {
+        self.send(SequencerControlMessage::StopAll);
+        self.set_num_channels(project.channels.len());
+        for channel_index in 0..self.num_channels {
+            for clip_index in 0..MAX_CLIPS_PER_CHANNEL {
+                if self.channels[channel_index].clips[clip_index].model.enabled {
+                    self.apply_clip(channel_index, clip_index, Clip::default());
+                }
+            }
+            for junction_index in 0..MAX_JUNCTIONS_PER_CHANNEL {
+                if self.channels[channel_index].junctions[junction_index].model.enabled {
+                    self.apply_junction(channel_index, junction_index, Junction::default());
+                }
+            }
+            let Some(channel) = project.channels.get(channel_index) else {
+                continue;
+            };
+            self.set_channel_length(channel_index, channel.length);
+            self.set_channel_boundary_mode(channel_index, channel.boundary_mode);
+            self.set_channel_voices(channel_index, channel.voice_config);
+            self.set_channel_gain(channel_index, channel.strip.gain_db);
+            self.set_channel_pan(channel_index, channel.strip.pan);
+            self.set_channel_mute(channel_index, channel.strip.mute);
+            self.set_channel_solo(channel_index, channel.strip.solo);
+            for (clip_index, clip) in channel.clips.iter().take(MAX_CLIPS_PER_CHANNEL).enumerate() {
+                let clip = if clip.enabled { self.constrain_clip(*clip) } else { *clip };
+                self.apply_clip(channel_index, clip_index, clip);
+            }
+            for (junction_index, junction) in channel.junctions.iter().take(MAX_JUNCTIONS_PER_CHANNEL).enumerate() {
+                self.apply_junction(channel_index, junction_index, *junction);
+            }
+        }
+
+        for index in 0..MAX_TRIGGERS {
+            let trigger = project.triggers.get(index).copied().unwrap_or_default();
+            if trigger.enabled || self.triggers[index].enabled {
+                self.triggers[index] = trigger;
+                self.sync_trigger(index);
+            }
+        }
+
+        self.scene_name_edit = None;
+        self.scenes = std::array::from_fn(SceneSlot::empty);
+        for project_scene in project.scenes.iter().filter(|scene| scene.index < MAX_SCENES) {
+            let mut scene = Scene::default();
+            for (playheads, project_playheads) in scene.playheads.iter_mut().zip(project_scene.channels.iter()) {
+                for (playhead, project_playhead) in playheads.iter_mut().zip(project_playheads.iter()) {
+                    *playhead = *project_playhead;
+                }
+            }
+            self.scenes[project_scene.index] = SceneSlot {
+                name: project_scene.name.clone(),
+                scene: Some(scene)
+            };
+            self.sync_scene(project_scene.index);
+        }
+
+        self.set_tempo(project.bpm);
+        self.set_clock_length(project.loop_length);
+        self.history.clear();
+        self.state = State::default();
+    }
+
+    // Sends are queued by the controller when the ring buffer is full, only a
+    // backlog that keeps growing is reported
+    fn send(&mut self, message: SequencerControlMessage) {
+        if let Err(err) = self.controller.send(message) {
+            println!("{}", err);
+        }
     }
 
     pub fn update(&mut self) {
+        self.controller.flush();
         while let Ok(event) = self.controller.event_receiver.pop() {
             match event {
-                SequencerEvent::Tick(summary) => { self.summary = summary; }
+                SequencerEvent::Tick(summary) => { self.summary = summary; },
+                SequencerEvent::JunctionFired { frame, index, .. } => {
+                    self.channels[index.channel_index].junctions[index.item_index].last_fired = Some(frame);
+                },
+                // Unless the slot was cleared in the meantime
+                SequencerEvent::SceneCaptured { index, scene } => {
+                    if let Some(slot) = &mut self.scenes[index].scene {
+                        *slot = scene;
+                    }
+                },
+                _ => {}
             }
         }
+        if self.summary.clock.bpm > 0 {
+            self.grid.bpm = self.summary.clock.bpm;
+        }
     }
 
 }
 
+impl SequencerInterface {
+    // Text is not scaled by the draw transform, so labels are positioned in
+    // absolute coordinates
+    fn draw_label(&self, draw: &mut Draw, position: (f32, f32), label: String) {
+        let Position(x, y) = Position(position.0, position.1).apply(self.transform);
+        draw.primitive_absolute(Primitive::Text(Text {
+            label,
+            position: (x, y),
+            scale: style::LABEL_SCALE,
+            color: Color::BLACK,
+            depth: Depth::Top,
+        }));
+    }
+}
+
 impl Transformable for SequencerInterface {
     fn transform(&self) -> Transform {
         self.transform
@@ -335,17 +1392,59 @@ impl InputHandler<Instrument> for SequencerInterface {
 
 impl Drawable for SequencerInterface {
     fn draw(&self, draw: &mut Draw) {
-        for (channel_index, channel) in self.channels.iter().enumerate() {
+        if self.grid_enabled {
+            for (location, line) in self.grid.lines(self.timeline_length) {
+                let x = location as f32 / self.timeline_length as f32;
+                draw.line(Line {
+                    from: (x, 0.0),
+                    to: (x, 1.0),
+                    color: match line {
+                        GridLine::Bar => self.theme.grid_bar,
+                        GridLine::Beat => self.theme.grid_beat,
+                        GridLine::Subdivision => self.theme.grid_subdivision
+                    },
+                    depth: style::GRID_DEPTH,
+                });
+            }
+        }
+        let num_channels = self.num_channels;
+        for (channel_index, channel) in self.channels[..num_channels].iter().enumerate() {
             for clip in channel.clips.iter().filter(|clip| clip.model.enabled) {
                 draw.quad(clip.quad);
+                if let Some(label) = clip_source_label(clip.model) {
+                    self.draw_label(draw, clip.quad.position, label);
+                }
             }
             for junction in channel.junctions.iter().filter(|junction| junction.model.enabled) {
+                let flashing = junction.last_fired.is_some_and(|frame| {
+                    self.summary.total_frames_processed.saturating_sub(frame) < JUNCTION_FLASH_FRAMES
+                });
+                if flashing {
+                    draw.primitive(marker_to_primitive(
+                        channel_index,
+                        junction.model.location,
+                        num_channels,
+                        self.timeline_length,
+                        1.0,
+                        self.theme.junction_flash
+                    ));
+                }
+                if let Some(label) = junction_condition_label(junction.model.condition) {
+                    self.draw_label(
+                        draw,
+                        (
+                            junction.model.location as f32 / self.timeline_length as f32,
+                            channel_index as f32 / num_channels as f32
+                        ),
+                        label
+                    );
+                }
                 match junction.model.junction_type {
                     JunctionType::Jump {
                         destination_channel_index,
                         destination_location,
                         ..
-                    } => {
+                    } if destination_channel_index < num_channels => {
                         let (
                             source_marker,
                             dest_marker
@@ -354,36 +1453,84 @@ impl Drawable for SequencerInterface {
                             junction.model.location,
                             destination_channel_index,
                             destination_location,
-                            self.channel_length
+                            num_channels,
+                            self.timeline_length
                         );
                         draw.primitive(source_marker);
                         draw.primitive(dest_marker);
                     },
+                    JunctionType::RandomJump {
+                        destinations,
+                        num_destinations,
+                        ..
+                    } => {
+                        draw.primitive(marker_to_primitive(
+                            channel_index,
+                            junction.model.location,
+                            num_channels,
+                            self.timeline_length,
+                            1.0,
+                            self.theme.random_jump
+                        ));
+                        let destinations = &destinations[..num_destinations];
+                        let total_weight: Float = destinations.iter()
+                            .map(|destination| destination.weight.max(0.0))
+                            .sum();
+                        // Without any weight the junction never jumps, so
+                        // there is nowhere to mark
+                        for destination in destinations.iter()
+                            .filter(|destination| total_weight > 0.0 && destination.channel_index < num_channels)
+                        {
+                            // Taller markers are more likely destinations
+                            draw.primitive(marker_to_primitive(
+                                destination.channel_index,
+                                destination.location,
+                                num_channels,
+                                self.timeline_length,
+                                destination.weight.max(0.0) / total_weight,
+                                self.theme.random_jump
+                            ));
+                        }
+                    },
                     JunctionType::Reflect => {
                         let marker = reflect_junction_to_primitive(
                             channel_index,
                             junction.model.location,
-                            self.channel_length
+                            num_channels,
+                            self.timeline_length
                         );
                         draw.primitive(marker);
                     },
-                    JunctionType::Stop => todo!()
+                    // Jumps into a removed channel are not drawn
+                    JunctionType::Jump { .. } => {},
+                    JunctionType::Stop => {
+                        draw.primitive(marker_to_primitive(
+                            channel_index,
+                            junction.model.location,
+                            num_channels,
+                            self.timeline_length,
+                            1.0,
+                            Color::RED
+                        ));
+                    }
                 }
             }
 
-            let playhead = self.summary.playheads[channel_index];
-            match playhead.state {
-                PlayheadState::Playing => {
-                    draw.primitive(playhead_to_primitive(
-                        channel_index,
-                        self.channel_length,
-                        self.summary.playheads[channel_index]
-                    ));
-                },
-                _ => {},
+            for playhead in self.summary.playheads[channel_index] {
+                match playhead.state {
+                    PlayheadState::Playing => {
+                        draw.primitive(playhead_to_primitive(
+                            channel_index,
+                            num_channels,
+                            self.timeline_length,
+                            playhead
+                        ));
+                    },
+                    _ => {},
+                }
             }
 
-            let inv = 1.0 / NUM_CHANNELS as f32;
+            let inv = 1.0 / num_channels as f32;
             let y = inv * channel_index as f32;
             draw.line(Line {
                 from: (0.0, y),
@@ -391,8 +1538,27 @@ impl Drawable for SequencerInterface {
                 color: Color::BLACK,
                 depth: Depth::Mid,
             });
+            let end = self.channel_lengths[channel_index] as f32 / self.timeline_length as f32;
+            if end < 1.0 {
+                draw.quad(Quad {
+                    position: (end, y),
+                    size: (1.0 - end, inv),
+                    color: self.theme.channel_end,
+                    depth: style::CHANNEL_END_DEPTH,
+                });
+            }
             let dy = inv * style::JUNCTION_LANE_PROPORTION;
-            let Color { r, g, b, a } = CLEAR_COLOR;
+            self.draw_label(
+                draw,
+                (0.0, y),
+                format!(
+                    "{}  {}  {}",
+                    channel_strip_label(self.channels[channel_index].strip),
+                    boundary_mode_label(channel.boundary_mode),
+                    voice_config_label(channel.voice_config)
+                )
+            );
+            let Color { r, g, b, a } = self.theme.background;
             let s = 0.9;
             draw.quad(Quad {
                 position: (0.0, y),
@@ -403,50 +1569,200 @@ impl Drawable for SequencerInterface {
         }
 
         draw.primitive_absolute(Primitive::Text(Text {
-            label: format!("{} frames processed", self.summary.total_frames_processed),
+            label: format!(
+                "{} frames processed{}",
+                self.summary.total_frames_processed,
+                if self.summary.dropped_events + self.summary.dropped_summaries + self.summary.early_messages > 0 {
+                    format!(
+                        "  ({} events, {} summaries dropped, {} messages early)",
+                        self.summary.dropped_events,
+                        self.summary.dropped_summaries,
+                        self.summary.early_messages
+                    )
+                } else {
+                    String::new()
+                }
+            ),
             position: (0.0, 0.0),
             scale: 30.0,
             color: Color::BLACK,
             depth: Depth::Top,
         }));
+        let clock = self.summary.clock;
+        draw.primitive_absolute(Primitive::Text(Text {
+            label: format!(
+                "{} bpm  {}.{}{}",
+                clock.bpm,
+                clock.beat / clock.beats_per_bar.max(1) + 1,
+                clock.beat % clock.beats_per_bar.max(1) + 1,
+                if clock.playing { "" } else { "  (stopped)" }
+            ),
+            position: (0.0, 0.04),
+            scale: 30.0,
+            color: Color::BLACK,
+            depth: Depth::Top,
+        }));
+
+        for trigger in self.triggers.iter().filter(|trigger| trigger.enabled) {
+            draw.primitive(marker_to_primitive(
+                trigger.destination_channel_index,
+                trigger.destination_location,
+                num_channels,
+                self.timeline_length,
+                0.5,
+                self.theme.trigger
+            ));
+            self.draw_label(
+                draw,
+                (
+                    trigger.destination_location as f32 / self.timeline_length as f32,
+                    (trigger.destination_channel_index as f32 + 0.5) / num_channels as f32
+                ),
+                format!("b{}", trigger.location + 1)
+            );
+        }
     }
 }
 
 
 
-fn mouse_position_to_channel_index(mouse_position: MousePosition) -> usize {
-    ((mouse_position.y * NUM_CHANNELS as f32).floor() as usize)
-        .clamp(0, NUM_CHANNELS - 1)
+fn mouse_position_to_channel_index(mouse_position: MousePosition, num_channels: usize) -> usize {
+    ((mouse_position.y * num_channels as f32).floor() as usize)
+        .clamp(0, num_channels - 1)
 }
 
 fn mouse_position_to_channel_location(mouse_position: MousePosition, channel_length: u64) -> u64 {
     (channel_length as f32 * mouse_position.x).floor() as u64
 }
 
-fn mouse_position_is_on_junction_lane(mouse_position: MousePosition, channel_index: usize) -> bool {
-    let inv = 1.0 / NUM_CHANNELS as f32;
+fn mouse_position_is_on_junction_lane(
+    mouse_position: MousePosition,
+    channel_index: usize,
+    num_channels: usize
+) -> bool {
+    let inv = 1.0 / num_channels as f32;
     let y = inv * channel_index as f32;
     
     mouse_position.y - y < inv * style::JUNCTION_LANE_PROPORTION
 }
 
-fn clip_to_quad(channel_index: usize, channel_length: u64, clip: Clip) -> Quad {
+fn junction_condition_label(condition: JunctionCondition) -> Option<String> {
+    match condition {
+        JunctionCondition::Always => None,
+        JunctionCondition::EveryNth { n } => Some(format!("/{}", n)),
+        JunctionCondition::FirstN { n } => Some(format!("<={}", n)),
+        JunctionCondition::Alternate { .. } => Some(String::from("alt"))
+    }
+}
+
+// Only clips that don't play their sound as is get a label
+fn clip_source_label(clip: Clip) -> Option<String> {
+    if clip.source_scale == 1.0 && clip.source_shift == 0 {
+        return None;
+    }
+    match clip.source_shift {
+        0 => Some(format!("x{}", clip.source_scale)),
+        shift => Some(format!("x{} +{}", clip.source_scale, shift))
+    }
+}
+
+fn keycode_to_scene_index(keycode: VirtualKeyCode) -> Option<usize> {
+    use VirtualKeyCode::*;
+    let index = match keycode {
+        F1 => 0,
+        F2 => 1,
+        F3 => 2,
+        F4 => 3,
+        F5 => 4,
+        F6 => 5,
+        F7 => 6,
+        F8 => 7,
+        _ => return None
+    };
+    Some(index)
+}
+
+fn keycode_to_digit(keycode: VirtualKeyCode) -> Option<u32> {
+    use VirtualKeyCode::*;
+    let digit = match keycode {
+        Key0 => 0,
+        Key1 => 1,
+        Key2 => 2,
+        Key3 => 3,
+        Key4 => 4,
+        Key5 => 5,
+        Key6 => 6,
+        Key7 => 7,
+        Key8 => 8,
+        Key9 => 9,
+        _ => return None
+    };
+    Some(digit)
+}
+
+fn boundary_mode_label(boundary_mode: BoundaryMode) -> &'static str {
+    match boundary_mode {
+        BoundaryMode::Stop => "stop",
+        BoundaryMode::Wrap => "wrap",
+        BoundaryMode::Bounce => "bounce"
+    }
+}
+
+fn voice_config_label(voice_config: VoiceConfig) -> String {
+    format!(
+        "{}v {}",
+        voice_config.limit,
+        match voice_config.steal_policy {
+            VoiceStealPolicy::Oldest => "oldest",
+            VoiceStealPolicy::Quietest => "quietest"
+        }
+    )
+}
+
+fn channel_strip_label(strip: ChannelStrip) -> String {
+    let gain = if strip.gain_db <= MIN_GAIN_DB {
+        String::from("-inf dB")
+    } else {
+        format!("{:+.1} dB", strip.gain_db)
+    };
+    let pan = if strip.pan.abs() < 0.01 {
+        String::from("C")
+    } else if strip.pan < 0.0 {
+        format!("L{:.0}", -100.0 * strip.pan)
+    } else {
+        format!("R{:.0}", 100.0 * strip.pan)
+    };
+    format!(
+        "{} {}{}{}",
+        gain,
+        pan,
+        if strip.mute { " [M]" } else { "" },
+        if strip.solo { " [S]" } else { "" }
+    )
+}
+
+fn clip_to_quad(channel_index: usize, num_channels: usize, channel_length: u64, clip: Clip, color: Color) -> Quad {
     let w = (clip.channel_location_end as f32 - clip.channel_location_start as f32) / channel_length as f32;
-    let h = 1.0 / NUM_CHANNELS as f32;
+    let h = 1.0 / num_channels as f32;
     let x = clip.channel_location_start as f32 / channel_length as f32;
-    let y = channel_index as f32 / NUM_CHANNELS as f32;
+    let y = channel_index as f32 / num_channels as f32;
     Quad {
         position: (x, y),
         size: (w, h),
-        color: style::CLIP_COLOR,
+        color: color,
         depth: Depth::Mid
     }
 }
 
-fn playhead_to_primitive(channel_index: usize, channel_length: u64, playhead: Playhead) -> Primitive {
-    let h = 1.0 / NUM_CHANNELS as f32;
+fn playhead_to_primitive(
+    channel_index: usize,
+    num_channels: usize,
+    channel_length: u64,
+    playhead: Playhead
+) -> Primitive {
+    let h = 1.0 / num_channels as f32;
     let x = playhead.location as f32 / channel_length as f32;
-    let y = channel_index as f32 / NUM_CHANNELS as f32;
+    let y = channel_index as f32 / num_channels as f32;
     Primitive::Quad(Quad {
         position: (x, y),
         size: (style::MARKER_LINE_WIDTH, h),
@@ -458,9 +1774,10 @@ fn playhead_to_primitive(channel_index: usize, channel_length: u64, playhead: Pl
 fn reflect_junction_to_primitive(
     channel_index: usize,
     channel_location: u64,
+    num_channels: usize,
     channel_length: u64
 ) -> Primitive {
-    let h = 1.0 / NUM_CHANNELS as f32;
+    let h = 1.0 / num_channels as f32;
     let x = channel_location as f32 / channel_length as f32;
     let y = h * channel_index as f32;
     Primitive::Quad(Quad {
@@ -471,14 +1788,34 @@ fn reflect_junction_to_primitive(
     })
 }
 
+fn marker_to_primitive(
+    channel_index: usize,
+    channel_location: u64,
+    num_channels: usize,
+    channel_length: u64,
+    height_proportion: f32,
+    color: Color
+) -> Primitive {
+    let h = 1.0 / num_channels as f32;
+    let x = channel_location as f32 / channel_length as f32;
+    let y = h * (channel_index as f32 + 1.0 - height_proportion);
+    Primitive::Quad(Quad {
+        position: (x, y),
+        size: (style::MARKER_LINE_WIDTH, h * height_proportion),
+        color,
+        depth: Depth::Front,
+    })
+}
+
 fn jump_junction_to_primitives(
     source_channel_index: usize,
     source_channel_location: u64,
     destination_channel_index: usize,
     destination_location: u64,
+    num_channels: usize,
     channel_length: u64
 ) -> (Primitive, Primitive) {
-    let h = 1.0 / NUM_CHANNELS as f32;
+    let h = 1.0 / num_channels as f32;
     let x = source_channel_location as f32 / channel_length as f32;
     let y = h * source_channel_index as f32;
     let x_dest = destination_location as f32 / channel_length as f32;
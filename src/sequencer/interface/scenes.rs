@@ -0,0 +1,81 @@
+use crate::sequencer::{Scene, MAX_SCENES};
+use crate::sequencer::interface::transport::{button_quad, draw_button};
+use crate::ui::primitive::{Draw, Drawable};
+use crate::ui::input::MousePosition;
+use crate::ui::{Transform, Transformable};
+
+
+// Enough to fit the side panel at the default size
+pub const MAX_SCENE_NAME_LENGTH: usize = 24;
+
+#[derive(Debug, Clone)]
+pub struct SceneSlot {
+    pub name: String,
+    // None until something has been captured
+    pub scene: Option<Scene>
+}
+
+impl SceneSlot {
+    pub fn empty(index: usize) -> Self {
+        Self {
+            name: format!("scene {}", index + 1),
+            scene: None
+        }
+    }
+}
+
+// Scene list for the side panel, one button per slot
+pub struct ScenePanel {
+    labels: [String; MAX_SCENES],
+    transform: Transform
+}
+
+impl ScenePanel {
+    pub fn new(transform: Transform) -> Self {
+        Self {
+            labels: Default::default(),
+            transform
+        }
+    }
+
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    // The slot whose name is being edited shows the name typed so far
+    pub fn set_scenes(&mut self, scenes: &[SceneSlot; MAX_SCENES], name_edit: Option<(usize, &str)>) {
+        for (index, (label, slot)) in self.labels.iter_mut().zip(scenes.iter()).enumerate() {
+            label.clear();
+            match name_edit {
+                Some((edit_index, name)) if edit_index == index => {
+                    label.push_str(&format!("F{} {}_", index + 1, name));
+                },
+                _ => label.push_str(&format!(
+                    "F{} {}{}",
+                    index + 1,
+                    slot.name,
+                    if slot.scene.is_some() { "" } else { " (empty)" }
+                ))
+            }
+        }
+    }
+
+    // Takes the mouse position in the panel's own coordinates
+    pub fn slot_at(&self, mouse_position: MousePosition) -> Option<usize> {
+        (0..MAX_SCENES).find(|index| button_quad(*index, MAX_SCENES).contains(mouse_position))
+    }
+}
+
+impl Transformable for ScenePanel {
+    fn transform(&self) -> Transform {
+        self.transform
+    }
+}
+
+impl Drawable for ScenePanel {
+    fn draw(&self, draw: &mut Draw) {
+        for (index, label) in self.labels.iter().enumerate() {
+            draw_button(draw, self.transform, index, MAX_SCENES, label);
+        }
+    }
+}
@@ -16,6 +16,9 @@ pub enum ChannelAction {
     GrabClip {
         clip_index: usize
     },
+    TrimClip {
+        clip_index: usize
+    },
     CreateJunction,
     ModifyJunction,
     SetPlayhead
@@ -28,6 +31,12 @@ pub enum State {
         clip_index: usize,
         relative_location: u64
     },
+    // Dragging the end of a clip, which can go no further than the end of
+    // its sound or its channel
+    TrimmingClip {
+        channel_index: usize,
+        clip_index: usize
+    },
     CreatingJunction {
         source_channel_index: usize,
         source_channel_location: u64
@@ -47,12 +56,14 @@ impl State {
     pub fn cursor_icon(&self) -> CursorIcon {
         match self {
             State::GrabbingClip { .. } => CursorIcon::Grabbing,
+            State::TrimmingClip { .. } => CursorIcon::EwResize,
             State::CreatingJunction { .. } => CursorIcon::Hand,
             State::Hovering { potential_action } => {
                 match potential_action {
                     Action::Channel { channel_action: action, .. } => {
                         match action {
                             ChannelAction::GrabClip { .. } => CursorIcon::Grab,
+                            ChannelAction::TrimClip { .. } => CursorIcon::EwResize,
                             ChannelAction::CreateJunction => CursorIcon::Hand,
                             ChannelAction::ModifyJunction => CursorIcon::Default,
                             ChannelAction::SetPlayhead => CursorIcon::Crosshair,
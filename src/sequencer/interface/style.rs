@@ -1,7 +1,67 @@
 use wgpu::Color;
 
+use crate::ui::Depth;
+use crate::config::CLEAR_COLOR;
+
 pub const CLIP_COLOR: Color = Color { r: 0.2, g: 0.4, b: 0.6, a: 1.0 };
 
+pub const RANDOM_JUMP_COLOR: Color = Color { r: 0.6, g: 0.2, b: 0.8, a: 1.0 };
+
+pub const JUNCTION_FLASH_COLOR: Color = Color { r: 1.0, g: 0.85, b: 0.0, a: 1.0 };
+
+pub const TRIGGER_COLOR: Color = Color { r: 0.9, g: 0.5, b: 0.1, a: 1.0 };
+
+pub const GRID_BAR_COLOR: Color = Color { r: 0.5, g: 0.5, b: 0.5, a: 1.0 };
+pub const GRID_BEAT_COLOR: Color = Color { r: 0.7, g: 0.7, b: 0.7, a: 1.0 };
+pub const GRID_SUBDIVISION_COLOR: Color = Color { r: 0.85, g: 0.85, b: 0.85, a: 1.0 };
+
+// In front of the lane backgrounds, behind clips and markers
+pub const GRID_DEPTH: Depth = Depth::Custom(0.2);
+
+// Shades the part of a lane past the end of a channel that is shorter than
+// the timeline, in front of the grid
+pub const CHANNEL_END_COLOR: Color = Color { r: 0.6, g: 0.6, b: 0.6, a: 1.0 };
+pub const CHANNEL_END_DEPTH: Depth = Depth::Custom(0.3);
+
 pub const JUNCTION_LANE_PROPORTION: f32 = 0.15;
 
-pub const MARKER_LINE_WIDTH: f32 = 0.002;
\ No newline at end of file
+pub const MARKER_LINE_WIDTH: f32 = 0.002;
+
+// Proportion of the channel length within which a click picks up a junction
+pub const JUNCTION_HIT_WIDTH: f32 = 0.005;
+
+// Proportion of the channel length at the end of a clip that trims it
+pub const CLIP_EDGE_HIT_WIDTH: f32 = 0.005;
+
+pub const LABEL_SCALE: f32 = 20.0;
+
+// The colours that can be changed from the user configuration file, each
+// defaults to the constant of the same name
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    pub background: Color,
+    pub clip: Color,
+    pub random_jump: Color,
+    pub junction_flash: Color,
+    pub trigger: Color,
+    pub grid_bar: Color,
+    pub grid_beat: Color,
+    pub grid_subdivision: Color,
+    pub channel_end: Color
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            background: CLEAR_COLOR,
+            clip: CLIP_COLOR,
+            random_jump: RANDOM_JUMP_COLOR,
+            junction_flash: JUNCTION_FLASH_COLOR,
+            trigger: TRIGGER_COLOR,
+            grid_bar: GRID_BAR_COLOR,
+            grid_beat: GRID_BEAT_COLOR,
+            grid_subdivision: GRID_SUBDIVISION_COLOR,
+            channel_end: CHANNEL_END_COLOR
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use wgpu::Color;
+
+use crate::sequencer::TransportState;
+use crate::ui::Depth;
+use crate::ui::primitive::{Draw, Drawable, Primitive, Quad, Text};
+use crate::ui::input::MousePosition;
+use crate::ui::{Transform, Transformable, Position, ApplyTransform};
+
+
+const BUTTON_MARGIN: f32 = 0.02;
+const BUTTON_COLOR: Color = Color { r: 0.8, g: 0.8, b: 0.8, a: 1.0 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportButton {
+    PlayPause,
+    Stop,
+    Reset,
+    SaveStart
+}
+
+const BUTTONS: [TransportButton; 4] = [
+    TransportButton::PlayPause,
+    TransportButton::Stop,
+    TransportButton::Reset,
+    TransportButton::SaveStart
+];
+
+// On-screen transport controls, a column of buttons filling whatever panel
+// it is given
+pub struct TransportPanel {
+    state: TransportState,
+    transform: Transform
+}
+
+impl TransportPanel {
+    pub fn new(transform: Transform) -> Self {
+        Self {
+            state: TransportState::default(),
+            transform
+        }
+    }
+
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    pub fn set_state(&mut self, state: TransportState) {
+        self.state = state;
+    }
+
+    // Takes the mouse position in the panel's own coordinates
+    pub fn button_at(&self, mouse_position: MousePosition) -> Option<TransportButton> {
+        BUTTONS.into_iter()
+            .enumerate()
+            .find(|(index, _)| button_quad(*index, BUTTONS.len()).contains(mouse_position))
+            .map(|(_, button)| button)
+    }
+
+    fn button_label(&self, button: TransportButton) -> &'static str {
+        match button {
+            TransportButton::PlayPause => match self.state {
+                TransportState::Playing => "pause [space]",
+                TransportState::Paused => "resume [space]"
+            },
+            TransportButton::Stop => "stop all [esc]",
+            TransportButton::Reset => "reset [home]",
+            TransportButton::SaveStart => "save start [shift+home]"
+        }
+    }
+}
+
+impl Transformable for TransportPanel {
+    fn transform(&self) -> Transform {
+        self.transform
+    }
+}
+
+impl Drawable for TransportPanel {
+    fn draw(&self, draw: &mut Draw) {
+        for (index, button) in BUTTONS.into_iter().enumerate() {
+            draw_button(draw, self.transform, index, BUTTONS.len(), self.button_label(button));
+        }
+    }
+}
+
+// A column of count buttons filling the panel
+pub(super) fn button_quad(index: usize, count: usize) -> Quad {
+    let height = (1.0 - BUTTON_MARGIN * (count + 1) as f32) / count as f32;
+    Quad {
+        position: (BUTTON_MARGIN, BUTTON_MARGIN + index as f32 * (height + BUTTON_MARGIN)),
+        size: (1.0 - 2.0 * BUTTON_MARGIN, height),
+        color: BUTTON_COLOR,
+        depth: Depth::Mid
+    }
+}
+
+pub(super) fn draw_button(draw: &mut Draw, transform: Transform, index: usize, count: usize, label: &str) {
+    let quad = button_quad(index, count);
+    draw.quad(quad);
+    // Text is not scaled by the draw transform
+    let Position(x, y) = Position(
+        quad.position.0 + BUTTON_MARGIN,
+        quad.position.1
+    ).apply(transform);
+    draw.primitive_absolute(Primitive::Text(Text {
+        label: String::from(label),
+        position: (x, y),
+        scale: 20.0,
+        color: Color::BLACK,
+        depth: Depth::Top,
+    }));
+}
@@ -0,0 +1,212 @@
+use std::f32::consts::{FRAC_PI_4, SQRT_2};
+
+use serde::{Serialize, Deserialize};
+
+use crate::sequencer::MAX_CHANNELS;
+use crate::sound::{StereoFrame, Float, db_to_amplitude};
+
+
+pub const MIN_GAIN_DB: Float = -60.0;
+pub const MAX_GAIN_DB: Float = 12.0;
+
+// One pole smoothing applied to the per channel gains every frame, roughly
+// 20 ms to settle at 48 kHz
+const SMOOTHING_COEFFICIENT: Float = 0.001;
+
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ChannelStrip {
+    pub gain_db: Float,
+    // -1.0 is hard left, 1.0 is hard right
+    pub pan: Float,
+    pub mute: bool,
+    pub solo: bool
+}
+
+impl Default for ChannelStrip {
+    fn default() -> Self {
+        Self {
+            gain_db: 0.0,
+            pan: 0.0,
+            mute: false,
+            solo: false
+        }
+    }
+}
+
+impl ChannelStrip {
+    // Constant power panning, scaled so that a centred channel plays at its
+    // gain on both sides.  Hard panned channels get 3 dB on their side.
+    fn stereo_gain(&self, any_solo: bool) -> (Float, Float) {
+        if self.mute || (any_solo && !self.solo) || self.gain_db <= MIN_GAIN_DB {
+            return (0.0, 0.0);
+        }
+        let gain = db_to_amplitude(self.gain_db) * SQRT_2;
+        let angle = (self.pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+        (gain * angle.cos(), gain * angle.sin())
+    }
+}
+
+pub struct Mixer {
+    pub strips: [ChannelStrip; MAX_CHANNELS],
+    current_gains: [(Float, Float); MAX_CHANNELS],
+    target_gains: [(Float, Float); MAX_CHANNELS],
+    // Fades every channel out, regardless of the strips
+    silenced: bool
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        let strips = [ChannelStrip::default(); MAX_CHANNELS];
+        let mut mixer = Self {
+            strips,
+            current_gains: Default::default(),
+            target_gains: Default::default(),
+            silenced: false
+        };
+        mixer.update_target_gains();
+        mixer.current_gains = mixer.target_gains;
+        mixer
+    }
+}
+
+impl Mixer {
+    pub fn set_gain(&mut self, channel_index: usize, gain_db: Float) {
+        self.strips[channel_index].gain_db = gain_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+        self.update_target_gains();
+    }
+
+    pub fn set_pan(&mut self, channel_index: usize, pan: Float) {
+        self.strips[channel_index].pan = pan.clamp(-1.0, 1.0);
+        self.update_target_gains();
+    }
+
+    pub fn set_mute(&mut self, channel_index: usize, mute: bool) {
+        self.strips[channel_index].mute = mute;
+        self.update_target_gains();
+    }
+
+    pub fn set_solo(&mut self, channel_index: usize, solo: bool) {
+        self.strips[channel_index].solo = solo;
+        self.update_target_gains();
+    }
+
+    pub fn reset(&mut self, channel_index: usize) {
+        self.strips[channel_index] = ChannelStrip::default();
+        self.update_target_gains();
+        self.current_gains[channel_index] = self.target_gains[channel_index];
+    }
+
+    pub fn set_silenced(&mut self, silenced: bool) {
+        self.silenced = silenced;
+        self.update_target_gains();
+    }
+
+    // Once true the first num_channels channels are below MIN_GAIN_DB and can
+    // be cut without a click.  The rest are not processed, so never fade.
+    pub fn is_silent(&self, num_channels: usize) -> bool {
+        let threshold = db_to_amplitude(MIN_GAIN_DB);
+        self.current_gains[..num_channels].iter()
+            .all(|(left, right)| left.abs() <= threshold && right.abs() <= threshold)
+    }
+
+    fn update_target_gains(&mut self) {
+        let any_solo = self.strips.iter().any(|strip| strip.solo);
+        for (target, strip) in self.target_gains.iter_mut().zip(self.strips.iter()) {
+            *target = if self.silenced { (0.0, 0.0) } else { strip.stereo_gain(any_solo) };
+        }
+    }
+
+    // Must be called exactly once per channel per frame, even when the
+    // channel is silent, so that the smoothing keeps moving
+    pub fn process(&mut self, channel_index: usize, frame: StereoFrame<Float>) -> StereoFrame<Float> {
+        let (left, right) = &mut self.current_gains[channel_index];
+        let (target_left, target_right) = self.target_gains[channel_index];
+        *left += SMOOTHING_COEFFICIENT * (target_left - *left);
+        *right += SMOOTHING_COEFFICIENT * (target_right - *right);
+
+        StereoFrame(*left * frame.left(), *right * frame.right())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(gain_db: Float, pan: Float) -> ChannelStrip {
+        ChannelStrip {
+            gain_db,
+            pan,
+            ..Default::default()
+        }
+    }
+
+    fn assert_gains(actual: (Float, Float), expected: (Float, Float)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-5 && (actual.1 - expected.1).abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn centre_is_unity() {
+        assert_gains(strip(0.0, 0.0).stereo_gain(false), (1.0, 1.0));
+        let half = db_to_amplitude(-6.0);
+        assert_gains(strip(-6.0, 0.0).stereo_gain(false), (half, half));
+    }
+
+    #[test]
+    fn panning_keeps_the_power() {
+        assert_gains(strip(0.0, -1.0).stereo_gain(false), (SQRT_2, 0.0));
+        assert_gains(strip(0.0, 1.0).stereo_gain(false), (0.0, SQRT_2));
+        // Past the ends is the same as hard panned
+        assert_gains(strip(0.0, 3.0).stereo_gain(false), (0.0, SQRT_2));
+        for pan in [-0.75, -0.3, 0.2, 0.9] {
+            let (left, right) = strip(0.0, pan).stereo_gain(false);
+            assert!((left * left + right * right - 2.0).abs() < 1e-5, "pan {}", pan);
+            assert_eq!(left > right, pan < 0.0);
+        }
+    }
+
+    #[test]
+    fn mute_solo_and_the_floor_are_silent() {
+        let muted = ChannelStrip { mute: true, ..strip(0.0, 0.0) };
+        assert_gains(muted.stereo_gain(false), (0.0, 0.0));
+        assert_gains(strip(0.0, 0.0).stereo_gain(true), (0.0, 0.0));
+        let soloed = ChannelStrip { solo: true, ..strip(0.0, 0.0) };
+        assert_gains(soloed.stereo_gain(true), (1.0, 1.0));
+        assert_gains(strip(MIN_GAIN_DB, 0.0).stereo_gain(false), (0.0, 0.0));
+    }
+
+    #[test]
+    fn gain_changes_are_smoothed() {
+        let mut mixer = Mixer::default();
+        let frame = StereoFrame(1.0, 1.0);
+        assert!((mixer.process(0, frame).left() - 1.0).abs() < 1e-6);
+
+        mixer.set_gain(0, MIN_GAIN_DB);
+        let first = mixer.process(0, frame).left();
+        assert!(first < 1.0 && first > 0.99);
+        for _ in 0..48000 {
+            mixer.process(0, frame);
+        }
+        assert!(mixer.process(0, frame).left() < 1e-6);
+        assert!(mixer.is_silent(1));
+        assert!(!mixer.is_silent(2));
+    }
+
+    #[test]
+    fn solo_silences_the_other_channels() {
+        let mut mixer = Mixer::default();
+        mixer.set_solo(1, true);
+        mixer.reset(0);
+        mixer.reset(2);
+        assert!(mixer.is_silent(1));
+        mixer.set_solo(1, false);
+        mixer.reset(0);
+        assert!(!mixer.is_silent(1));
+    }
+}
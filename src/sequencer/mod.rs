@@ -1,43 +1,186 @@
 mod channel;
 mod control_loop;
 mod event;
+mod mixer;
+mod scene;
+mod scheduler;
+mod transport;
 pub mod interface;
 
 use rtrb::{Consumer, Producer, RingBuffer};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
 
 pub use channel::*;
+pub use control_loop::*;
 pub use event::*;
+pub use mixer::*;
+pub use scene::*;
+pub use transport::*;
+use scheduler::ControlMessageQueue;
 use crate::sound::{SoundBank, StereoFrame, StereoFrameGenerator, Float};
+use crate::project::Project;
 
 
-pub const NUM_CHANNELS: usize = 4;
+// Channels are preallocated so that adding one never allocates on the audio
+// thread
+pub const MAX_CHANNELS: usize = 16;
+pub const DEFAULT_NUM_CHANNELS: usize = 4;
 pub const DEFAULT_CHANNEL_LENGTH: u64 = 500_000;
+pub const DEFAULT_RANDOM_SEED: u64 = 0;
 
 const SYNC_INTERVAL: u64 = 256;  // frames
 const RING_BUFFER_CAPACITY: usize = 1024;
 
+// Summaries are skipped when fewer slots than this are free, so that discrete
+// events still get through when the UI falls behind
+const SUMMARY_RESERVE: usize = RING_BUFFER_CAPACITY / 4;
+
+// Messages held back by the controller while the ring buffer is full, beyond
+// this sends fail
+const MAX_PENDING_MESSAGES: usize = 4 * RING_BUFFER_CAPACITY;
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueFullError {
+    pub message: ScheduledControlMessage
+}
+
+impl std::fmt::Display for QueueFullError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sequencer control queue is full, dropped {:?}", self.message.message)
+    }
+}
+
+impl std::error::Error for QueueFullError {}
+
 pub struct SequencerController {
-    pub control_message_sender: Producer<SequencerControlMessage>,
-    pub event_receiver: Consumer<SequencerEvent>
+    pub control_message_sender: Producer<ScheduledControlMessage>,
+    pub event_receiver: Consumer<SequencerEvent>,
+    // Messages waiting for space in the ring buffer, oldest first
+    pending: Vec<ScheduledControlMessage>
+}
+
+impl SequencerController {
+    pub fn send(&mut self, message: SequencerControlMessage) -> Result<(), QueueFullError> {
+        self.enqueue(ScheduledControlMessage {
+            target_frame: None,
+            message
+        })
+    }
+
+    pub fn send_at(
+        &mut self,
+        target_frame: u64,
+        message: SequencerControlMessage
+    ) -> Result<(), QueueFullError> {
+        self.enqueue(ScheduledControlMessage {
+            target_frame: Some(target_frame),
+            message
+        })
+    }
+
+    // While messages are backed up, a newer immediate message replaces any
+    // pending one that sets the same thing, and moves to the back so that
+    // ordering relative to other messages is kept
+    fn enqueue(&mut self, message: ScheduledControlMessage) -> Result<(), QueueFullError> {
+        if message.target_frame.is_none() {
+            if let Some(key) = message.message.coalesce_key() {
+                self.pending.retain(|pending| {
+                    pending.target_frame.is_some() || pending.message.coalesce_key() != Some(key)
+                });
+            }
+        }
+        if self.pending.len() >= MAX_PENDING_MESSAGES {
+            return Err(QueueFullError { message });
+        }
+        self.pending.push(message);
+        self.flush();
+        Ok(())
+    }
+
+    // Pushes as many pending messages as fit, should be called regularly
+    // from the UI thread so that a backlog drains
+    pub fn flush(&mut self) {
+        let count = self.control_message_sender.slots().min(self.pending.len());
+        for message in self.pending.drain(..count) {
+            // Cannot fail, there are at least count free slots
+            let _ = self.control_message_sender.push(message);
+        }
+    }
+
+    pub fn pending_messages(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+// Audio thread side of the event ring buffer, counts what it has to drop
+// instead of blocking or panicking
+struct EventSender {
+    producer: Producer<SequencerEvent>,
+    dropped_events: u64,
+    dropped_summaries: u64
+}
+
+impl EventSender {
+    fn push(&mut self, event: SequencerEvent) {
+        if self.producer.push(event).is_err() {
+            self.dropped_events += 1;
+        }
+    }
+
+    // Summaries go stale quickly, so one is dropped rather than eating into
+    // the space kept for discrete events
+    fn push_summary(&mut self, summary: SequencerSummary) {
+        if self.producer.slots() <= SUMMARY_RESERVE {
+            self.dropped_summaries += 1;
+            return;
+        }
+        self.push(SequencerEvent::Tick(summary));
+    }
 }
 
 pub struct Sequencer {
-    control_message_receiver: Consumer<SequencerControlMessage>,
-    event_sender: Producer<SequencerEvent>,
+    control_message_receiver: Consumer<ScheduledControlMessage>,
+    control_message_queue: ControlMessageQueue<RING_BUFFER_CAPACITY>,
+    event_sender: EventSender,
     summary: SequencerSummary,
-    channels: [Channel; NUM_CHANNELS],
-    playhead_mutations: [PlayheadMutation; NUM_CHANNELS],
+    channels: [Channel; MAX_CHANNELS],
+    num_channels: usize,
+    pending_launches: [PendingLaunches; MAX_CHANNELS],
+    // Clip each voice was in on the previous frame, for clip events
+    voice_clips: [[Option<usize>; MAX_VOICES_PER_CHANNEL]; MAX_CHANNELS],
+    // Voices in use per channel on the previous frame, so that voices of a
+    // removed channel or past a lowered limit report stopping once more
+    reported_voices: [usize; MAX_CHANNELS],
+    mixer: Mixer,
+    control_loop: ControlLoop,
+    transport: TransportState,
+    transport_start: TransportStart,
+    scenes: [Scene; MAX_SCENES],
+    pending_scene_recall: Option<SceneRecall>,
+    random: SmallRng,
     sound_bank: SoundBank<Float>
 }
 
+// Voices started by jumps and triggers during a frame, launched once every
+// channel has been processed.  More launches than there are voices would only
+// steal each other, so the extras are dropped.
 #[derive(Debug, Default)]
-struct PlayheadMutation {
-    updated_this_frame: bool,
-    playhead: Playhead
+struct PendingLaunches {
+    playheads: [Playhead; MAX_VOICES_PER_CHANNEL],
+    len: usize
+}
+
+impl PendingLaunches {
+    fn push(&mut self, playhead: Playhead) {
+        if self.len < MAX_VOICES_PER_CHANNEL {
+            self.playheads[self.len] = playhead;
+            self.len += 1;
+        }
+    }
 }
 
 impl Sequencer {
-    pub fn new(sound_bank: SoundBank<Float>) -> (SequencerController, Self) {
+    pub fn new(sound_bank: SoundBank<Float>, sample_rate: usize) -> (SequencerController, Self) {
         let (
             control_message_sender,
             control_message_receiver
@@ -50,154 +193,1155 @@ impl Sequencer {
         
         let sequencer_controller = SequencerController {
             control_message_sender,
-            event_receiver
+            event_receiver,
+            pending: Vec::new()
         };
 
-        let mut channels: [Channel; NUM_CHANNELS] = Default::default();
+        let mut channels: [Channel; MAX_CHANNELS] = Default::default();
         for channel in channels.iter_mut() {
             channel.length = DEFAULT_CHANNEL_LENGTH;
         }
         let sequencer = Self {
             control_message_receiver,
-            event_sender,
+            control_message_queue: Default::default(),
+            event_sender: EventSender {
+                producer: event_sender,
+                dropped_events: 0,
+                dropped_summaries: 0
+            },
             summary: Default::default(),
             channels,
-            playhead_mutations: Default::default(),
+            num_channels: DEFAULT_NUM_CHANNELS,
+            pending_launches: Default::default(),
+            voice_clips: [[None; MAX_VOICES_PER_CHANNEL]; MAX_CHANNELS],
+            reported_voices: [0; MAX_CHANNELS],
+            mixer: Default::default(),
+            control_loop: ControlLoop::new(DEFAULT_LOOP_LENGTH, sample_rate as u32),
+            transport: TransportState::default(),
+            transport_start: TransportStart::default(),
+            scenes: [Scene::default(); MAX_SCENES],
+            pending_scene_recall: None,
+            random: SmallRng::seed_from_u64(DEFAULT_RANDOM_SEED),
             sound_bank
         };
         
         (sequencer_controller, sequencer)
     }
 
+    // Sets up a whole patch directly, for rendering offline before the first
+    // frame.  A running sequencer is loaded through its controller instead.
+    pub fn load_project(&mut self, project: &Project) {
+        self.set_num_channels(project.channels.len());
+        for (channel_index, project_channel) in project.channels.iter().take(MAX_CHANNELS).enumerate() {
+            let channel = &mut self.channels[channel_index];
+            for (clip, project_clip) in channel.clips.iter_mut().zip(project_channel.clips.iter()) {
+                *clip = *project_clip;
+            }
+            for (junction, project_junction) in channel.junctions.iter_mut().zip(project_channel.junctions.iter()) {
+                *junction = *project_junction;
+            }
+            channel.set_length(project_channel.length);
+            channel.boundary_mode = project_channel.boundary_mode;
+            channel.set_voice_config(project_channel.voice_config);
+
+            let strip = project_channel.strip;
+            self.mixer.set_gain(channel_index, strip.gain_db);
+            self.mixer.set_pan(channel_index, strip.pan);
+            self.mixer.set_mute(channel_index, strip.mute);
+            self.mixer.set_solo(channel_index, strip.solo);
+        }
+        for (index, trigger) in project.triggers.iter().take(MAX_TRIGGERS).enumerate() {
+            self.control_loop.set_trigger(index, *trigger);
+        }
+        for project_scene in project.scenes.iter().filter(|scene| scene.index < MAX_SCENES) {
+            let scene = &mut self.scenes[project_scene.index];
+            for (playheads, project_playheads) in scene.playheads.iter_mut().zip(project_scene.channels.iter()) {
+                for (playhead, project_playhead) in playheads.iter_mut().zip(project_playheads.iter()) {
+                    *playhead = *project_playhead;
+                }
+            }
+        }
+        self.control_loop.set_tempo(project.bpm);
+        self.control_loop.set_length(project.loop_length);
+    }
+
     fn set_clip(&mut self, index: ChannelItemIndex, clip: Clip) {
         self.channels[index.channel_index]
             .clips[index.item_index] = clip;
     }
 
+    // Moving a junction or changing what it does keeps its count, a new
+    // condition or a new junction in the slot starts over
     fn set_junction(&mut self, index: ChannelItemIndex, junction: Junction) {
-        self.channels[index.channel_index]
-            .junctions[index.item_index] = junction;
+        let channel = &mut self.channels[index.channel_index];
+        let previous = channel.junctions[index.item_index];
+        if previous.condition != junction.condition || previous.enabled != junction.enabled {
+            channel.junction_pass_counts[index.item_index] = 0;
+        }
+        channel.junctions[index.item_index] = junction;
+    }
+
+    // Frees the slot so that the interface can reuse it
+    fn remove_clip(&mut self, index: ChannelItemIndex) {
+        self.set_clip(index, Clip::default());
+    }
+
+    fn remove_junction(&mut self, index: ChannelItemIndex) {
+        self.set_junction(index, Junction::default());
     }
 
     fn set_playhead(&mut self, index: ChannelItemIndex, playhead: Playhead) {
-        self.channels[index.channel_index].playhead_override_this_frame = true;
-        self.channels[index.channel_index].playhead = playhead;
+        self.channels[index.channel_index].voices[index.item_index] = Voice {
+            playhead,
+            started_at: self.summary.total_frames_processed,
+            level: 0.0,
+            override_this_frame: true
+        };
+    }
+
+    fn set_num_channels(&mut self, num_channels: usize) {
+        let num_channels = num_channels.clamp(1, MAX_CHANNELS);
+        // Removed channels are cleared so that they come back empty
+        for channel_index in num_channels..self.num_channels {
+            let channel = &mut self.channels[channel_index];
+            channel.clips = Default::default();
+            channel.junctions = Default::default();
+            channel.reset_junction_pass_counts();
+            channel.voices = Default::default();
+            channel.voice_config = VoiceConfig::default();
+            channel.length = DEFAULT_CHANNEL_LENGTH;
+            channel.boundary_mode = BoundaryMode::default();
+            self.pending_launches[channel_index] = PendingLaunches::default();
+            self.mixer.reset(channel_index);
+        }
+        self.num_channels = num_channels;
+    }
+
+    // Stops every voice and the clock, and drops anything about to launch
+    fn stop_all(&mut self) {
+        for channel in &mut self.channels {
+            channel.stop();
+        }
+        for launches in &mut self.pending_launches {
+            launches.len = 0;
+        }
+        self.pending_scene_recall = None;
+        self.control_loop.stop();
+        self.control_loop.rewind();
+        self.set_transport(TransportState::Playing);
+    }
+
+    // Pausing holds the playheads and the clock straight away, the voices
+    // keep sounding the frame they stopped on while the mixer fades out
+    fn set_transport(&mut self, transport: TransportState) {
+        self.transport = transport;
+        self.mixer.set_silenced(transport == TransportState::Paused);
+    }
+
+    // Paused and faded out
+    fn is_halted(&self) -> bool {
+        self.transport == TransportState::Paused && self.mixer.is_silent(self.num_channels)
     }
 
-    fn handle_control_messsages(&mut self) {
+    fn reset_transport(&mut self) {
+        self.stop_all();
+        self.transport_start.restore(&mut self.channels, self.summary.total_frames_processed);
+        if self.transport_start.clock_playing() {
+            self.control_loop.play();
+        }
+    }
+
+    // Messages for now are handled as they arrive.  Scheduled ones wait in
+    // the queue, or are applied early when it is full so that they never hold
+    // up the messages behind them in the ring buffer.
+    fn receive_control_messages(&mut self) {
+        let frame = self.summary.total_frames_processed;
         while let Ok(message) = self.control_message_receiver.pop() {
-            use SequencerControlMessage::*;
-            match message {
-                SyncClip { index, clip } => {
-                    self.set_clip(index, clip);
-                },
-                SyncJunction { index, junction } => {
-                    self.set_junction(index, junction);
+            match message.target_frame {
+                Some(target_frame) if target_frame > frame => {
+                    if let Err(message) = self.control_message_queue.push(message) {
+                        self.summary.early_messages += 1;
+                        self.handle_control_message(message.message);
+                    }
                 },
-                SyncPlayhead { index, playhead } => {
-                    self.set_playhead(index, playhead);
+                _ => self.handle_control_message(message.message)
+            }
+        }
+    }
+
+    fn handle_control_message(&mut self, message: SequencerControlMessage) {
+        use SequencerControlMessage::*;
+        match message {
+            SyncClip { index, clip } => {
+                self.set_clip(index, clip);
+            },
+            SyncJunction { index, junction } => {
+                self.set_junction(index, junction);
+            },
+            SyncPlayhead { index, playhead } => {
+                self.set_playhead(index, playhead);
+            },
+            LaunchPlayhead { channel_index, playhead } => {
+                self.pending_launches[channel_index].push(playhead);
+            },
+            SetChannelVoices { channel_index, voice_config } => {
+                self.channels[channel_index].set_voice_config(voice_config);
+            },
+            RemoveClip { index } => {
+                self.remove_clip(index);
+            },
+            RemoveJunction { index } => {
+                self.remove_junction(index);
+            },
+            SetChannelGain { channel_index, gain_db } => {
+                self.mixer.set_gain(channel_index, gain_db);
+            },
+            SetChannelPan { channel_index, pan } => {
+                self.mixer.set_pan(channel_index, pan);
+            },
+            SetChannelMute { channel_index, mute } => {
+                self.mixer.set_mute(channel_index, mute);
+            },
+            SetChannelSolo { channel_index, solo } => {
+                self.mixer.set_solo(channel_index, solo);
+            },
+            SetRandomSeed { seed } => {
+                self.random = SmallRng::seed_from_u64(seed);
+            },
+            ResetJunctionPassCounts { channel_index } => {
+                match channel_index {
+                    Some(channel_index) => {
+                        self.channels[channel_index].reset_junction_pass_counts();
+                    },
+                    None => {
+                        for channel in &mut self.channels {
+                            channel.reset_junction_pass_counts();
+                        }
+                    }
                 }
+            },
+            SyncTrigger { index, trigger } => {
+                self.control_loop.set_trigger(index, trigger);
+            },
+            SetTempo { bpm } => {
+                self.control_loop.set_tempo(bpm);
+            },
+            SetClockLength { beats } => {
+                self.control_loop.set_length(beats);
+            },
+            SetClockPlaying { playing } => {
+                if playing {
+                    self.control_loop.play();
+                } else {
+                    self.control_loop.stop();
+                }
+            },
+            SetNumChannels { num_channels } => {
+                self.set_num_channels(num_channels);
+            },
+            SetChannelLength { channel_index, length } => {
+                self.channels[channel_index].set_length(length);
+            },
+            SetChannelBoundaryMode { channel_index, boundary_mode } => {
+                self.channels[channel_index].boundary_mode = boundary_mode;
+            },
+            PauseTransport => {
+                self.set_transport(TransportState::Paused);
+            },
+            ResumeTransport => {
+                self.set_transport(TransportState::Playing);
+            },
+            StopAll => {
+                self.stop_all();
+            },
+            SaveTransportStart => {
+                self.transport_start = TransportStart::capture(
+                    &self.channels,
+                    self.control_loop.is_playing()
+                );
+            },
+            ResetTransport => {
+                self.reset_transport();
+            },
+            SyncScene { index, channel_index, playheads } => {
+                self.scenes[index].playheads[channel_index] = playheads;
+            },
+            RecallScene { index, quantize } => {
+                self.pending_scene_recall = Some(SceneRecall { index, quantize });
+            },
+            CaptureScene { index } => {
+                let scene = Scene::capture(&self.channels);
+                self.scenes[index] = scene;
+                self.event_sender.push(SequencerEvent::SceneCaptured { index, scene });
             }
         }
     }
 
+    // Messages that came due go first, they were sent before anything still
+    // in the ring buffer
+    fn handle_control_messages_single_frame(&mut self) {
+        let frame = self.summary.total_frames_processed;
+        while let Some(message) = self.control_message_queue.pop_due(frame) {
+            self.handle_control_message(message);
+        }
+        self.receive_control_messages();
+    }
+
     fn step_playheads_single_frame(&mut self) {
-        for channel in &mut self.channels {
-            channel.step_playhead_single_frame();
+        for channel in &mut self.channels[..self.num_channels] {
+            channel.step_playheads_single_frame();
         }
     }
 
     fn handle_junctions_single_frame(&mut self) {
-        for (channel_index, channel) in self.channels.iter_mut().enumerate() {
-            if let Some(junction) = channel.get_current_junction() {
-                match junction.junction_type {
+        let frame = self.summary.total_frames_processed;
+        for (channel_index, channel) in self.channels[..self.num_channels].iter_mut().enumerate() {
+            for voice_index in 0..MAX_VOICES_PER_CHANNEL {
+                let Some((junction_index, junction_type)) = channel.pass_current_junction(voice_index) else {
+                    continue;
+                };
+                self.event_sender.push(SequencerEvent::JunctionFired {
+                    frame,
+                    index: ChannelItemIndex {
+                        channel_index,
+                        item_index: junction_index
+                    },
+                    voice_index
+                });
+                let voice = &mut channel.voices[voice_index];
+                match junction_type {
                     JunctionType::Jump {
                         destination_channel_index,
                         destination_location,
                         split
                     } => {
-                        self.playhead_mutations[destination_channel_index] = PlayheadMutation {
-                            updated_this_frame: true,
-                            playhead: Playhead { 
-                                state: PlayheadState::Playing,
-                                location: destination_location,
-                                direction: channel.playhead.direction
-                            }
-                        };
+                        self.pending_launches[destination_channel_index].push(Playhead { 
+                            state: PlayheadState::Playing,
+                            location: destination_location,
+                            direction: voice.playhead.direction
+                        });
                         if !split {
-                            channel.stop();
+                            voice.stop();
                         }
                     },
-                    JunctionType::Reflect => {
-                        self.playhead_mutations[channel_index] = PlayheadMutation {
-                            updated_this_frame: true,
-                            playhead: Playhead { 
-                                direction: match channel.playhead.direction {
-                                    PlayheadDirection::Right => PlayheadDirection::Left,
-                                    PlayheadDirection::Left => PlayheadDirection::Right,
-                                },
-                                ..channel.playhead
+                    JunctionType::RandomJump {
+                        destinations,
+                        num_destinations,
+                        split
+                    } => {
+                        let destinations = &destinations[..num_destinations.min(MAX_JUMP_DESTINATIONS)];
+                        if let Some(destination) = choose_destination(destinations, &mut self.random) {
+                            self.pending_launches[destination.channel_index].push(Playhead {
+                                state: PlayheadState::Playing,
+                                location: destination.location,
+                                direction: voice.playhead.direction
+                            });
+                            if !split {
+                                voice.stop();
                             }
                         }
                     },
+                    JunctionType::Reflect => {
+                        voice.playhead.direction = match voice.playhead.direction {
+                            PlayheadDirection::Right => PlayheadDirection::Left,
+                            PlayheadDirection::Left => PlayheadDirection::Right,
+                        };
+                    },
                     JunctionType::Stop => {
-                        channel.stop();
+                        voice.stop();
                     }
                 }
             }
         }
     }
 
-    fn handle_playhead_mutations_single_frame(&mut self) {
-        for (channel, mutation) in self.channels.iter_mut()
-            .zip(self.playhead_mutations.iter_mut())
+    // Returns whether a beat started on this frame
+    fn handle_clock_single_frame(&mut self) -> bool {
+        let Some(beat) = self.control_loop.update() else {
+            return false;
+        };
+        for trigger in self.control_loop.triggers_at(beat) {
+            self.pending_launches[trigger.destination_channel_index].push(Playhead {
+                state: PlayheadState::Playing,
+                location: trigger.destination_location,
+                direction: PlayheadDirection::Right
+            });
+        }
+        true
+    }
+
+    // Quantized recalls wait for the next beat, so while the clock is
+    // stopped they wait for it to start
+    fn handle_scene_recall_single_frame(&mut self, beat_started: bool) {
+        let Some(recall) = self.pending_scene_recall else {
+            return;
+        };
+        if recall.quantize && !beat_started {
+            return;
+        }
+        self.scenes[recall.index].apply(&mut self.channels, self.summary.total_frames_processed);
+        self.pending_scene_recall = None;
+    }
+
+    fn handle_pending_launches_single_frame(&mut self) {
+        // Jumps and triggers into removed channels are dropped here
+        let frame = self.summary.total_frames_processed;
+        for (channel, launches) in self.channels[..self.num_channels].iter_mut()
+            .zip(self.pending_launches.iter_mut())
         {
-            if mutation.updated_this_frame {
-                channel.playhead = mutation.playhead;
-                mutation.updated_this_frame = false;
+            for playhead in &launches.playheads[..launches.len] {
+                channel.launch(*playhead, frame);
+            }
+            launches.len = 0;
+        }
+        for launches in &mut self.pending_launches[self.num_channels..] {
+            launches.len = 0;
+        }
+    }
+
+    // Compares the voices in use against the previous frame's summary, so
+    // that starts and stops are reported whatever caused them.  Voices that
+    // went out of use since the last frame are compared once more so that
+    // they report stopping.
+    fn send_voice_events_single_frame(&mut self) {
+        let frame = self.summary.total_frames_processed;
+        for (channel_index, channel) in self.channels.iter().enumerate() {
+            let in_use = if channel_index < self.num_channels {
+                channel.voice_config.limit
+            } else {
+                0
+            };
+            let num_voices = in_use.max(self.reported_voices[channel_index]);
+            self.reported_voices[channel_index] = in_use;
+            for (voice_index, voice) in channel.voices[..num_voices].iter().enumerate() {
+                let previous = self.summary.playheads[channel_index][voice_index];
+                let was_playing = matches!(previous.state, PlayheadState::Playing);
+                let is_playing = voice.is_playing();
+                // A stolen voice keeps playing but starts over
+                let restarted = is_playing && voice.started_at == frame;
+                let stopped = was_playing && (!is_playing || restarted);
+
+                let previous_clip_index = self.voice_clips[channel_index][voice_index];
+                let clip_index = channel.get_current_clip_index(voice_index);
+                let clip_changed = previous_clip_index != clip_index || restarted;
+                self.voice_clips[channel_index][voice_index] = clip_index;
+
+                // Pushed one at a time, events are large because of Tick
+                if let (true, Some(item_index)) = (clip_changed, previous_clip_index) {
+                    self.event_sender.push(SequencerEvent::ClipExited {
+                        frame,
+                        index: ChannelItemIndex { channel_index, item_index },
+                        voice_index
+                    });
+                }
+                if stopped {
+                    self.event_sender.push(SequencerEvent::PlayheadStopped {
+                        frame,
+                        channel_index,
+                        voice_index,
+                        location: previous.location
+                    });
+                }
+                if is_playing && (!was_playing || restarted) {
+                    self.event_sender.push(SequencerEvent::PlayheadStarted {
+                        frame,
+                        channel_index,
+                        voice_index,
+                        playhead: voice.playhead
+                    });
+                } else if is_playing && previous.direction != voice.playhead.direction {
+                    self.event_sender.push(SequencerEvent::PlayheadReflected {
+                        frame,
+                        channel_index,
+                        voice_index,
+                        playhead: voice.playhead
+                    });
+                }
+                if let (true, Some(item_index)) = (clip_changed, clip_index) {
+                    self.event_sender.push(SequencerEvent::ClipEntered {
+                        frame,
+                        index: ChannelItemIndex { channel_index, item_index },
+                        voice_index
+                    });
+                }
             }
         }
     }
 
     fn update_summary_single_frame(&mut self) {
-        for (channel, playhead) in self.channels.iter().zip(self.summary.playheads.iter_mut()) {
-            *playhead = channel.playhead;
+        for (channel, playheads) in self.channels.iter().zip(self.summary.playheads.iter_mut()) {
+            for (voice, playhead) in channel.voices.iter().zip(playheads.iter_mut()) {
+                *playhead = voice.playhead;
+            }
         }
+        self.summary.mixer = self.mixer.strips;
+        self.summary.num_channels = self.num_channels;
+        self.summary.clock = self.control_loop.summary();
+        self.summary.transport = self.transport;
+        self.summary.dropped_events = self.event_sender.dropped_events;
+        self.summary.dropped_summaries = self.event_sender.dropped_summaries;
         self.summary.total_frames_processed += 1;
     }
 
     fn update_single_frame(&mut self) {
         self.sound_bank.update();
-        self.step_playheads_single_frame();
-        self.handle_junctions_single_frame();
-        self.handle_playhead_mutations_single_frame();
+        let playing = self.transport == TransportState::Playing;
+        let mut beat_started = false;
+        if playing {
+            self.step_playheads_single_frame();
+            self.handle_junctions_single_frame();
+            beat_started = self.handle_clock_single_frame();
+        }
+        // Scenes can be recalled while paused, launches wait for the
+        // transport and layer on top of a recalled scene
+        self.handle_scene_recall_single_frame(beat_started);
+        if playing {
+            self.handle_pending_launches_single_frame();
+        }
+        self.send_voice_events_single_frame();
         self.update_summary_single_frame();        
     }
 
     fn sum_output_single_frame(&mut self) -> StereoFrame<Float> {
         let mut out_frame = StereoFrame::zero();
-        for channel in &self.channels {
-            out_frame += channel.get_current_sound_bank_index()
-                                .and_then(
-                                    |index| self.sound_bank.get_frame(index)
-                                )
-                                .unwrap_or_default();
+        let halted = self.is_halted();
+        for (channel_index, channel) in self.channels[..self.num_channels].iter_mut().enumerate() {
+            // The mixer still runs while paused so that its smoothing keeps up
+            if halted {
+                out_frame += self.mixer.process(channel_index, StereoFrame::zero());
+                continue;
+            }
+            let mut channel_frame = StereoFrame::zero();
+            for voice_index in 0..MAX_VOICES_PER_CHANNEL {
+                let frame = channel.get_current_sound_bank_index(voice_index)
+                                   .and_then(
+                                       |index| self.sound_bank.get_frame(index)
+                                   )
+                                   .unwrap_or_default();
+                channel.voices[voice_index].record_level(frame);
+                channel_frame += frame;
+            }
+            out_frame += self.mixer.process(channel_index, channel_frame);
         }
         out_frame
     }
 
     fn send_summary(&mut self) {
-        self.event_sender.push(SequencerEvent::Tick(self.summary)).unwrap();
+        // Nobody drains the event buffer when rendering offline, so a full
+        // buffer just means this summary is dropped
+        self.event_sender.push_summary(self.summary);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.channels[..self.num_channels].iter().any(Channel::is_playing)
     }
 }
 
+fn choose_destination(destinations: &[JumpDestination], random: &mut SmallRng) -> Option<JumpDestination> {
+    let total_weight: Float = destinations.iter()
+        .map(|destination| destination.weight.max(0.0))
+        .sum();
+    if total_weight <= 0.0 {
+        return None;
+    }
+    let mut choice = random.gen::<Float>() * total_weight;
+    for destination in destinations {
+        choice -= destination.weight.max(0.0);
+        if choice < 0.0 {
+            return Some(*destination);
+        }
+    }
+    destinations.iter().rev().find(|destination| destination.weight > 0.0).copied()
+}
+
 impl StereoFrameGenerator<Float> for Sequencer {
     fn next_frame(&mut self) -> StereoFrame<Float> {
+        self.handle_control_messages_single_frame();
         if self.summary.total_frames_processed % SYNC_INTERVAL == 0 {
-            self.handle_control_messsages();
             self.send_summary();
         }
         self.update_single_frame();
         self.sum_output_single_frame()
     }
-}
\ No newline at end of file
+
+    // A running clock with triggers can always start something new
+    fn is_finished(&self) -> bool {
+        !self.is_playing()
+            && !(self.control_loop.is_playing() && self.control_loop.has_triggers())
+            && self.control_message_queue.is_empty()
+            && self.control_message_receiver.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    // An engine without any sounds, and its controller
+    fn engine() -> (SequencerController, Sequencer) {
+        let (_, sound_bank) = SoundBank::new(Vec::new());
+        Sequencer::new(sound_bank, SAMPLE_RATE)
+    }
+
+    // Hands over whatever the controller has backed up before every frame
+    fn run(controller: &mut SequencerController, sequencer: &mut Sequencer, frames: u64) {
+        for _ in 0..frames {
+            controller.flush();
+            sequencer.next_frame();
+        }
+    }
+
+    // A controller whose ring buffer only has room for capacity messages,
+    // with the audio thread end of it
+    fn controller(capacity: usize) -> (SequencerController, Consumer<ScheduledControlMessage>) {
+        let (control_message_sender, control_message_receiver) = RingBuffer::new(capacity);
+        let (_, event_receiver) = RingBuffer::new(1);
+        let controller = SequencerController {
+            control_message_sender,
+            event_receiver,
+            pending: Vec::new()
+        };
+        (controller, control_message_receiver)
+    }
+
+    fn label(message: &ScheduledControlMessage) -> String {
+        use SequencerControlMessage::*;
+        let label = match message.message {
+            SetChannelGain { channel_index, gain_db } => format!("gain {} {}", channel_index, gain_db),
+            SetTempo { bpm } => format!("tempo {}", bpm),
+            SetNumChannels { num_channels } => format!("channels {}", num_channels),
+            message => panic!("unexpected message {:?}", message)
+        };
+        match message.target_frame {
+            Some(frame) => format!("{} at {}", label, frame),
+            None => label
+        }
+    }
+
+    fn pending(controller: &SequencerController) -> Vec<String> {
+        controller.pending.iter().map(label).collect()
+    }
+
+    fn received(receiver: &mut Consumer<ScheduledControlMessage>) -> Vec<String> {
+        let mut received = Vec::new();
+        while let Ok(message) = receiver.pop() {
+            received.push(label(&message));
+        }
+        received
+    }
+
+    fn gain(channel_index: usize, gain_db: Float) -> SequencerControlMessage {
+        SequencerControlMessage::SetChannelGain { channel_index, gain_db }
+    }
+
+    fn tempo(bpm: u32) -> SequencerControlMessage {
+        SequencerControlMessage::SetTempo { bpm }
+    }
+
+    #[test]
+    fn messages_go_straight_through_while_there_is_room() {
+        let (mut controller, mut receiver) = controller(4);
+        controller.send(gain(0, -1.0)).unwrap();
+        controller.send(gain(0, -2.0)).unwrap();
+        controller.send_at(10, tempo(100)).unwrap();
+        assert_eq!(controller.pending_messages(), 0);
+        assert_eq!(received(&mut receiver), ["gain 0 -1", "gain 0 -2", "tempo 100 at 10"]);
+    }
+
+    #[test]
+    fn backlog_drains_in_order_on_flush() {
+        let (mut controller, mut receiver) = controller(2);
+        for num_channels in 1..=5 {
+            controller.send(SequencerControlMessage::SetNumChannels { num_channels }).unwrap();
+        }
+        assert_eq!(controller.pending_messages(), 3);
+
+        // Nothing moves until the audio thread makes room
+        controller.flush();
+        assert_eq!(controller.pending_messages(), 3);
+        assert_eq!(received(&mut receiver), ["channels 1", "channels 2"]);
+        controller.flush();
+        assert_eq!(received(&mut receiver), ["channels 3", "channels 4"]);
+        controller.flush();
+        assert_eq!(received(&mut receiver), ["channels 5"]);
+        assert_eq!(controller.pending_messages(), 0);
+    }
+
+    #[test]
+    fn backed_up_settings_are_coalesced_and_move_to_the_back() {
+        let (mut controller, mut receiver) = controller(1);
+        controller.send(tempo(90)).unwrap();
+        controller.send(gain(0, -1.0)).unwrap();
+        controller.send(gain(1, -1.0)).unwrap();
+        controller.send(tempo(100)).unwrap();
+        controller.send(gain(0, -2.0)).unwrap();
+        assert_eq!(pending(&controller), ["gain 1 -1", "tempo 100", "gain 0 -2"]);
+
+        // Whatever already reached the ring buffer is left alone
+        controller.send(tempo(110)).unwrap();
+        assert_eq!(pending(&controller), ["gain 1 -1", "gain 0 -2", "tempo 110"]);
+        assert_eq!(received(&mut receiver), ["tempo 90"]);
+    }
+
+    #[test]
+    fn scheduled_messages_are_never_coalesced() {
+        let (mut controller, _receiver) = controller(1);
+        controller.send(tempo(90)).unwrap();
+        controller.send_at(10, tempo(100)).unwrap();
+        controller.send_at(20, tempo(110)).unwrap();
+        controller.send(tempo(120)).unwrap();
+        controller.send(tempo(130)).unwrap();
+        assert_eq!(pending(&controller), ["tempo 100 at 10", "tempo 110 at 20", "tempo 130"]);
+    }
+
+    #[test]
+    fn structural_messages_are_never_coalesced() {
+        let (mut controller, _receiver) = controller(1);
+        controller.send(tempo(90)).unwrap();
+        for num_channels in [3, 4, 3] {
+            controller.send(SequencerControlMessage::SetNumChannels { num_channels }).unwrap();
+        }
+        assert_eq!(pending(&controller), ["channels 3", "channels 4", "channels 3"]);
+    }
+
+    #[test]
+    fn sends_fail_once_the_backlog_is_full() {
+        let (mut controller, mut receiver) = controller(1);
+        controller.send(tempo(90)).unwrap();
+        for num_channels in 0..MAX_PENDING_MESSAGES {
+            controller.send(SequencerControlMessage::SetNumChannels { num_channels }).unwrap();
+        }
+        let error = controller.send(gain(0, -1.0)).unwrap_err();
+        assert_eq!(label(&error.message), "gain 0 -1");
+        assert_eq!(controller.pending_messages(), MAX_PENDING_MESSAGES);
+
+        // Room again once the audio thread takes something
+        assert_eq!(received(&mut receiver), ["tempo 90"]);
+        controller.flush();
+        controller.send(gain(0, -1.0)).unwrap();
+    }
+
+    #[test]
+    fn scheduled_messages_apply_on_their_frame() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send_at(5, tempo(90)).unwrap();
+        run(&mut controller, &mut sequencer, 5);
+        assert_ne!(sequencer.summary.clock.bpm, 90);
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(sequencer.summary.clock.bpm, 90);
+    }
+
+    #[test]
+    fn a_full_schedule_does_not_hold_up_immediate_messages() {
+        let (mut controller, mut sequencer) = engine();
+        for _ in 0..RING_BUFFER_CAPACITY {
+            controller.send_at(1_000_000, tempo(60)).unwrap();
+        }
+        run(&mut controller, &mut sequencer, 1);
+        assert!(sequencer.control_message_queue.is_full());
+
+        controller.send_at(500_000, tempo(70)).unwrap();
+        controller.send(SequencerControlMessage::SetNumChannels { num_channels: 3 }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(sequencer.summary.num_channels, 3);
+        // No room left to wait in, so it is applied early
+        assert_eq!(sequencer.summary.clock.bpm, 70);
+        assert_eq!(sequencer.summary.early_messages, 1);
+    }
+
+    fn launch(channel_index: usize, location: u64) -> SequencerControlMessage {
+        SequencerControlMessage::LaunchPlayhead {
+            channel_index,
+            playhead: Playhead {
+                state: PlayheadState::Playing,
+                location,
+                direction: PlayheadDirection::Right
+            }
+        }
+    }
+
+    fn locations(sequencer: &Sequencer, channel_index: usize) -> Vec<u64> {
+        sequencer.channels[channel_index].voices.iter()
+            .filter(|voice| voice.is_playing())
+            .map(|voice| voice.playhead.location)
+            .collect()
+    }
+
+    #[test]
+    fn pause_holds_playheads_and_the_clock_straight_away() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(launch(0, 0)).unwrap();
+        controller.send(launch(1, 100)).unwrap();
+        controller.send(SequencerControlMessage::SetClockPlaying { playing: true }).unwrap();
+        run(&mut controller, &mut sequencer, 10);
+
+        controller.send(SequencerControlMessage::PauseTransport).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        let held = (locations(&sequencer, 0), locations(&sequencer, 1), sequencer.control_loop.summary().beat);
+        assert!(!sequencer.is_halted());
+
+        // Long enough for the fade to finish
+        run(&mut controller, &mut sequencer, SAMPLE_RATE as u64);
+        assert_eq!((locations(&sequencer, 0), locations(&sequencer, 1), sequencer.control_loop.summary().beat), held);
+        assert!(sequencer.is_halted());
+
+        controller.send(SequencerControlMessage::ResumeTransport).unwrap();
+        run(&mut controller, &mut sequencer, 10);
+        assert!(!sequencer.is_halted());
+        assert_eq!(locations(&sequencer, 0), vec![held.0[0] + 10]);
+    }
+
+    #[test]
+    fn pause_fades_out_with_fewer_than_all_channels() {
+        let (mut controller, mut sequencer) = engine();
+        assert!(sequencer.num_channels < MAX_CHANNELS);
+        controller.send(SequencerControlMessage::PauseTransport).unwrap();
+        run(&mut controller, &mut sequencer, SAMPLE_RATE as u64 / 2);
+        assert!(sequencer.is_halted());
+    }
+
+    fn destination(channel_index: usize, weight: Float) -> JumpDestination {
+        JumpDestination {
+            channel_index,
+            location: 0,
+            weight
+        }
+    }
+
+    #[test]
+    fn no_destination_without_weight() {
+        let mut random = SmallRng::seed_from_u64(1);
+        assert!(choose_destination(&[], &mut random).is_none());
+        assert!(choose_destination(&[destination(0, 0.0), destination(1, -1.0)], &mut random).is_none());
+    }
+
+    #[test]
+    fn only_weighted_destinations_are_chosen() {
+        let mut random = SmallRng::seed_from_u64(2);
+        let destinations = [destination(0, 0.0), destination(1, 2.0), destination(2, -3.0)];
+        for _ in 0..1000 {
+            assert_eq!(choose_destination(&destinations, &mut random).unwrap().channel_index, 1);
+        }
+    }
+
+    #[test]
+    fn destinations_are_chosen_by_weight() {
+        let mut random = SmallRng::seed_from_u64(3);
+        let destinations = [destination(0, 1.0), destination(1, 3.0)];
+        let draws = 10_000;
+        let second = (0..draws)
+            .filter(|_| choose_destination(&destinations, &mut random).unwrap().channel_index == 1)
+            .count();
+        let share = second as f64 / draws as f64;
+        assert!((share - 0.75).abs() < 0.02, "{}", share);
+    }
+
+    #[test]
+    fn the_same_seed_makes_the_same_choices() {
+        let destinations = [destination(0, 1.0), destination(1, 1.0), destination(2, 1.0)];
+        let choices = |seed| {
+            let mut random = SmallRng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| choose_destination(&destinations, &mut random).unwrap().channel_index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(choices(4), choices(4));
+        assert_ne!(choices(4), choices(5));
+    }
+
+    #[test]
+    fn quantized_recall_waits_for_the_clock() {
+        let (mut controller, mut sequencer) = engine();
+        sequencer.scenes[0].playheads[0][0] = Playhead {
+            state: PlayheadState::Playing,
+            location: 100,
+            direction: PlayheadDirection::Right
+        };
+        controller.send(SequencerControlMessage::RecallScene { index: 0, quantize: true }).unwrap();
+        run(&mut controller, &mut sequencer, 100);
+        assert!(locations(&sequencer, 0).is_empty());
+
+        // The clock starts on a beat, so the scene comes in on the first frame
+        controller.send(SequencerControlMessage::SetClockPlaying { playing: true }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(locations(&sequencer, 0), [100]);
+    }
+
+    #[test]
+    fn unquantized_recall_applies_straight_away() {
+        let (mut controller, mut sequencer) = engine();
+        sequencer.scenes[0].playheads[0][0] = Playhead {
+            state: PlayheadState::Playing,
+            location: 100,
+            direction: PlayheadDirection::Right
+        };
+        controller.send(SequencerControlMessage::RecallScene { index: 0, quantize: false }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(locations(&sequencer, 0), [100]);
+    }
+
+    #[test]
+    fn capture_scene_takes_the_playheads_of_its_frame() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(launch(1, 40)).unwrap();
+        run(&mut controller, &mut sequencer, 10);
+        controller.send(SequencerControlMessage::CaptureScene { index: 3 }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+
+        // Handled before the frame steps the playheads
+        assert_eq!(sequencer.scenes[3].playheads[1][0].location, 49);
+        let mut captured = None;
+        while let Ok(event) = controller.event_receiver.pop() {
+            if let SequencerEvent::SceneCaptured { index, scene } = event {
+                captured = Some((index, scene.playheads[1][0].location));
+            }
+        }
+        assert_eq!(captured, Some((3, 49)));
+    }
+
+    #[test]
+    fn pass_counts_survive_everything_but_a_new_condition() {
+        let (mut controller, mut sequencer) = engine();
+        let index = ChannelItemIndex { channel_index: 0, item_index: 0 };
+        let junction = Junction {
+            enabled: true,
+            location: 100,
+            junction_type: JunctionType::Reflect,
+            condition: JunctionCondition::EveryNth { n: 4 }
+        };
+        controller.send(SequencerControlMessage::SyncJunction { index, junction }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        sequencer.channels[0].junction_pass_counts[0] = 3;
+
+        let moved = Junction { location: 200, junction_type: JunctionType::Stop, ..junction };
+        controller.send(SequencerControlMessage::SyncJunction { index, junction: moved }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(sequencer.channels[0].junction_pass_counts[0], 3);
+
+        let recounted = Junction { condition: JunctionCondition::FirstN { n: 4 }, ..moved };
+        controller.send(SequencerControlMessage::SyncJunction { index, junction: recounted }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(sequencer.channels[0].junction_pass_counts[0], 0);
+
+        sequencer.channels[0].junction_pass_counts[0] = 3;
+        controller.send(SequencerControlMessage::RemoveJunction { index }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(sequencer.channels[0].junction_pass_counts[0], 0);
+    }
+
+    #[test]
+    fn triggers_launch_on_their_beat() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(SequencerControlMessage::SetTempo { bpm: 6000 }).unwrap();
+        let trigger = Trigger {
+            enabled: true,
+            location: 1,
+            destination_channel_index: 1,
+            destination_location: 50
+        };
+        controller.send(SequencerControlMessage::SyncTrigger { index: 0, trigger }).unwrap();
+        // Neither of these can fire
+        controller.send(SequencerControlMessage::SyncTrigger { index: MAX_TRIGGERS, trigger }).unwrap();
+        controller.send(SequencerControlMessage::SyncTrigger {
+            index: 1,
+            trigger: Trigger { destination_channel_index: MAX_CHANNELS, ..trigger }
+        }).unwrap();
+        controller.send(SequencerControlMessage::SetClockPlaying { playing: true }).unwrap();
+
+        // 480 frames per beat, beat 1 starts on the 481st frame
+        run(&mut controller, &mut sequencer, 480);
+        assert!(locations(&sequencer, 1).is_empty());
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(locations(&sequencer, 1), [50]);
+    }
+
+    // Where channel 0's one voice is on each of the given number of frames,
+    // after launching it on a channel of length 10
+    fn steps(boundary_mode: BoundaryMode, location: u64, direction: PlayheadDirection, frames: usize)
+        -> Vec<Option<(u64, PlayheadDirection)>>
+    {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(SequencerControlMessage::SetChannelLength { channel_index: 0, length: 10 }).unwrap();
+        controller.send(SequencerControlMessage::SetChannelBoundaryMode { channel_index: 0, boundary_mode }).unwrap();
+        controller.send(SequencerControlMessage::LaunchPlayhead {
+            channel_index: 0,
+            playhead: Playhead { state: PlayheadState::Playing, location, direction }
+        }).unwrap();
+        (0..frames).map(|_| {
+            run(&mut controller, &mut sequencer, 1);
+            let voice = &sequencer.channels[0].voices[0];
+            voice.is_playing().then_some((voice.playhead.location, voice.playhead.direction))
+        }).collect()
+    }
+
+    #[test]
+    fn stop_ends_the_voice_at_either_end() {
+        use PlayheadDirection::*;
+        assert_eq!(steps(BoundaryMode::Stop, 7, Right, 5), [Some((7, Right)), Some((8, Right)), Some((9, Right)), None, None]);
+        assert_eq!(steps(BoundaryMode::Stop, 1, Left, 3), [Some((1, Left)), Some((0, Left)), None]);
+    }
+
+    #[test]
+    fn wrap_starts_over_from_the_other_end() {
+        use PlayheadDirection::*;
+        assert_eq!(
+            steps(BoundaryMode::Wrap, 7, Right, 5),
+            [Some((7, Right)), Some((8, Right)), Some((9, Right)), Some((0, Right)), Some((1, Right))]
+        );
+        assert_eq!(steps(BoundaryMode::Wrap, 1, Left, 3), [Some((1, Left)), Some((0, Left)), Some((9, Left))]);
+    }
+
+    #[test]
+    fn bounce_turns_back_at_either_end() {
+        use PlayheadDirection::*;
+        assert_eq!(
+            steps(BoundaryMode::Bounce, 7, Right, 5),
+            [Some((7, Right)), Some((8, Right)), Some((9, Right)), Some((8, Left)), Some((7, Left))]
+        );
+        assert_eq!(steps(BoundaryMode::Bounce, 1, Left, 3), [Some((1, Left)), Some((0, Left)), Some((1, Right))]);
+    }
+
+    fn voices(channel_index: usize, limit: usize, steal_policy: VoiceStealPolicy) -> SequencerControlMessage {
+        SequencerControlMessage::SetChannelVoices {
+            channel_index,
+            voice_config: VoiceConfig { limit, steal_policy }
+        }
+    }
+
+    #[test]
+    fn oldest_voice_is_stolen() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(voices(0, 2, VoiceStealPolicy::Oldest)).unwrap();
+        controller.send(launch(0, 100)).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        controller.send(launch(0, 200)).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        controller.send(launch(0, 300)).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(locations(&sequencer, 0), [300, 201]);
+    }
+
+    #[test]
+    fn quietest_voice_is_stolen() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(voices(0, 2, VoiceStealPolicy::Quietest)).unwrap();
+        controller.send(launch(0, 100)).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        controller.send(launch(0, 200)).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        sequencer.channels[0].voices[0].level = 0.5;
+        sequencer.channels[0].voices[1].level = 0.1;
+        controller.send(launch(0, 300)).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(locations(&sequencer, 0), [102, 300]);
+    }
+
+    #[test]
+    fn launches_of_one_frame_past_the_limit_steal_each_other() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(voices(0, 2, VoiceStealPolicy::Oldest)).unwrap();
+        for location in [100, 200, 300] {
+            controller.send(launch(0, location)).unwrap();
+        }
+        run(&mut controller, &mut sequencer, 1);
+        // Started on the same frame, the first voice counts as the oldest
+        assert_eq!(locations(&sequencer, 0), [300, 200]);
+    }
+
+    #[test]
+    fn pending_launches_stop_at_the_voice_count() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(voices(0, MAX_VOICES_PER_CHANNEL, VoiceStealPolicy::Oldest)).unwrap();
+        controller.send(SequencerControlMessage::PauseTransport).unwrap();
+        for location in 0..MAX_VOICES_PER_CHANNEL as u64 + 2 {
+            controller.send(launch(0, location * 100)).unwrap();
+        }
+        run(&mut controller, &mut sequencer, 1);
+        // Launches wait for the transport, the extras are dropped
+        assert_eq!(sequencer.pending_launches[0].len, MAX_VOICES_PER_CHANNEL);
+        assert!(locations(&sequencer, 0).is_empty());
+
+        controller.send(SequencerControlMessage::ResumeTransport).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        let expected: Vec<u64> = (0..MAX_VOICES_PER_CHANNEL as u64).map(|location| location * 100).collect();
+        assert_eq!(locations(&sequencer, 0), expected);
+        assert_eq!(sequencer.pending_launches[0].len, 0);
+    }
+
+    // Voice events of the last frames, without the summaries
+    fn voice_events(controller: &mut SequencerController) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Ok(event) = controller.event_receiver.pop() {
+            events.push(match event {
+                SequencerEvent::PlayheadStarted { frame, channel_index, voice_index, playhead } =>
+                    format!("{} started {}/{} at {}", frame, channel_index, voice_index, playhead.location),
+                SequencerEvent::PlayheadStopped { frame, channel_index, voice_index, location } =>
+                    format!("{} stopped {}/{} at {}", frame, channel_index, voice_index, location),
+                SequencerEvent::PlayheadReflected { frame, channel_index, voice_index, playhead } =>
+                    format!("{} reflected {}/{} at {}", frame, channel_index, voice_index, playhead.location),
+                SequencerEvent::ClipEntered { frame, index, voice_index } =>
+                    format!("{} entered {}/{} by {}", frame, index.channel_index, index.item_index, voice_index),
+                SequencerEvent::ClipExited { frame, index, voice_index } =>
+                    format!("{} exited {}/{} by {}", frame, index.channel_index, index.item_index, voice_index),
+                _ => continue
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn voice_events_carry_the_frame_they_happened_on() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(SequencerControlMessage::SetChannelLength { channel_index: 1, length: 10 }).unwrap();
+        controller.send(SequencerControlMessage::SetChannelBoundaryMode {
+            channel_index: 1,
+            boundary_mode: BoundaryMode::Bounce
+        }).unwrap();
+        let clip = Clip {
+            enabled: true,
+            source_index: 0,
+            channel_location_start: 8,
+            channel_location_end: 10,
+            source_scale: 1.0,
+            source_shift: 0
+        };
+        let index = ChannelItemIndex { channel_index: 1, item_index: 2 };
+        controller.send(SequencerControlMessage::SyncClip { index, clip }).unwrap();
+        run(&mut controller, &mut sequencer, 5);
+        controller.send(launch(1, 6)).unwrap();
+        run(&mut controller, &mut sequencer, 10);
+        controller.send(SequencerControlMessage::StopAll).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(voice_events(&mut controller), [
+            "5 started 1/0 at 6",
+            "7 entered 1/2 by 0",
+            "9 reflected 1/0 at 8",
+            "10 exited 1/2 by 0",
+            "15 stopped 1/0 at 3"
+        ]);
+    }
+
+    #[test]
+    fn voices_going_out_of_use_report_stopping() {
+        let (mut controller, mut sequencer) = engine();
+        controller.send(launch(0, 0)).unwrap();
+        controller.send(launch(0, 100)).unwrap();
+        controller.send(launch(3, 300)).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        voice_events(&mut controller);
+
+        controller.send(voices(0, 1, VoiceStealPolicy::Oldest)).unwrap();
+        controller.send(SequencerControlMessage::SetNumChannels { num_channels: 3 }).unwrap();
+        run(&mut controller, &mut sequencer, 1);
+        assert_eq!(voice_events(&mut controller), ["1 stopped 0/1 at 100", "1 stopped 3/0 at 300"]);
+        assert_eq!(sequencer.reported_voices[0], 1);
+        assert_eq!(sequencer.reported_voices[3], 0);
+
+        // Nothing more to report once they have
+        run(&mut controller, &mut sequencer, 1);
+        assert!(voice_events(&mut controller).is_empty());
+        assert!(matches!(sequencer.summary.playheads[3][0].state, PlayheadState::Stopped));
+    }
+}
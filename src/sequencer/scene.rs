@@ -0,0 +1,109 @@
+use crate::sequencer::{Channel, Voice, Playhead, MAX_CHANNELS, MAX_VOICES_PER_CHANNEL};
+
+
+pub const MAX_SCENES: usize = 8;
+
+// Every voice of every channel, recalled all at once
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Scene {
+    pub playheads: [[Playhead; MAX_VOICES_PER_CHANNEL]; MAX_CHANNELS]
+}
+
+impl Scene {
+    // Every voice as it is on this frame
+    pub fn capture(channels: &[Channel; MAX_CHANNELS]) -> Self {
+        let mut scene = Scene::default();
+        for (playheads, channel) in scene.playheads.iter_mut().zip(channels.iter()) {
+            for (playhead, voice) in playheads.iter_mut().zip(channel.voices.iter()) {
+                *playhead = voice.playhead;
+            }
+        }
+        scene
+    }
+
+    // Recalled voices count as launched on the given frame
+    pub fn apply(&self, channels: &mut [Channel; MAX_CHANNELS], frame: u64) {
+        for (channel, playheads) in channels.iter_mut().zip(self.playheads.iter()) {
+            for (voice, playhead) in channel.voices.iter_mut().zip(playheads.iter()) {
+                *voice = Voice {
+                    playhead: *playhead,
+                    started_at: frame,
+                    level: 0.0,
+                    override_this_frame: false
+                };
+            }
+        }
+    }
+}
+
+// A recall waiting to be applied, quantized recalls wait for the next beat
+// and so for the clock to play
+#[derive(Debug, Clone, Copy)]
+pub struct SceneRecall {
+    pub index: usize,
+    pub quantize: bool
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::{PlayheadState, PlayheadDirection};
+
+    fn playing(location: u64) -> Playhead {
+        Playhead {
+            state: PlayheadState::Playing,
+            location,
+            direction: PlayheadDirection::Right
+        }
+    }
+
+    #[test]
+    fn apply_replaces_every_voice() {
+        let mut channels: [Channel; MAX_CHANNELS] = Default::default();
+        channels[0].voices[1] = Voice {
+            playhead: playing(500),
+            started_at: 3,
+            level: 0.5,
+            override_this_frame: true
+        };
+        channels[2].voices[0].playhead = playing(700);
+
+        let mut scene = Scene::default();
+        scene.playheads[0][0] = playing(10);
+        scene.playheads[1][3] = playing(20);
+        scene.apply(&mut channels, 100);
+
+        assert_eq!(channels[0].voices[0].playhead.location, 10);
+        assert_eq!(channels[1].voices[3].playhead.location, 20);
+        // Anything the scene does not hold is stopped
+        assert!(!channels[0].voices[1].is_playing());
+        assert!(!channels[2].voices[0].is_playing());
+        for voice in channels.iter().flat_map(|channel| channel.voices.iter()) {
+            assert_eq!(voice.started_at, 100);
+            assert_eq!(voice.level, 0.0);
+            assert!(!voice.override_this_frame);
+        }
+    }
+
+    #[test]
+    fn capture_then_apply_restores_the_playheads() {
+        let mut channels: [Channel; MAX_CHANNELS] = Default::default();
+        channels[0].voices[0].playhead = playing(10);
+        channels[MAX_CHANNELS - 1].voices[MAX_VOICES_PER_CHANNEL - 1].playhead = playing(30);
+        let scene = Scene::capture(&channels);
+
+        let mut recalled: [Channel; MAX_CHANNELS] = Default::default();
+        recalled[1].voices[0].playhead = playing(20);
+        scene.apply(&mut recalled, 0);
+        let locations = |channels: &[Channel; MAX_CHANNELS]| -> Vec<(usize, usize, u64)> {
+            channels.iter().enumerate().flat_map(|(channel_index, channel)| {
+                channel.voices.iter().enumerate()
+                    .filter(|(_, voice)| voice.is_playing())
+                    .map(move |(voice_index, voice)| (channel_index, voice_index, voice.playhead.location))
+            }).collect()
+        };
+        assert_eq!(locations(&recalled), locations(&channels));
+        assert_eq!(locations(&recalled), [(0, 0, 10), (MAX_CHANNELS - 1, MAX_VOICES_PER_CHANNEL - 1, 30)]);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::sequencer::{ScheduledControlMessage, SequencerControlMessage};
+
+
+// Holds messages that have been received from the controller but whose target
+// frame has not been reached yet.  Fixed size and allocated up front so that
+// the audio thread never allocates.  Messages stay in their slot until popped,
+// only the small heap entries pointing at them are moved around.
+pub struct ControlMessageQueue<const N: usize> {
+    slots: Box<[Option<SequencerControlMessage>]>,
+    free_slots: Box<[usize]>,
+    num_free_slots: usize,
+    // Min-heap on (target_frame, sequence), so that messages due on the same
+    // frame keep the order they were pushed in
+    heap: Box<[HeapEntry]>,
+    len: usize,
+    next_sequence: u64
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct HeapEntry {
+    target_frame: u64,
+    sequence: u64,
+    slot: usize
+}
+
+impl HeapEntry {
+    fn key(&self) -> (u64, u64) {
+        (self.target_frame, self.sequence)
+    }
+}
+
+impl<const N: usize> Default for ControlMessageQueue<N> {
+    fn default() -> Self {
+        Self {
+            slots: vec![None; N].into_boxed_slice(),
+            free_slots: (0..N).rev().collect(),
+            num_free_slots: N,
+            heap: vec![HeapEntry::default(); N].into_boxed_slice(),
+            len: 0,
+            next_sequence: 0
+        }
+    }
+}
+
+impl<const N: usize> ControlMessageQueue<N> {
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn push(&mut self, message: ScheduledControlMessage) -> Result<(), ScheduledControlMessage> {
+        if self.is_full() {
+            return Err(message);
+        }
+        self.num_free_slots -= 1;
+        let slot = self.free_slots[self.num_free_slots];
+        self.slots[slot] = Some(message.message);
+        self.heap[self.len] = HeapEntry {
+            target_frame: message.target_frame.unwrap_or(0),
+            sequence: self.next_sequence,
+            slot
+        };
+        self.next_sequence += 1;
+        self.len += 1;
+        self.sift_up(self.len - 1);
+        Ok(())
+    }
+
+    // Returns the due message with the earliest target frame, messages due on
+    // the same frame in the order they were pushed
+    pub fn pop_due(&mut self, frame: u64) -> Option<SequencerControlMessage> {
+        if self.is_empty() || self.heap[0].target_frame > frame {
+            return None;
+        }
+        let entry = self.heap[0];
+        self.len -= 1;
+        self.heap[0] = self.heap[self.len];
+        self.sift_down(0);
+
+        self.free_slots[self.num_free_slots] = entry.slot;
+        self.num_free_slots += 1;
+        self.slots[entry.slot].take()
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.heap[parent].key() <= self.heap[index].key() {
+                break;
+            }
+            self.heap.swap(parent, index);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut smallest = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.len && self.heap[child].key() < self.heap[smallest].key() {
+                    smallest = child;
+                }
+            }
+            if smallest == index {
+                break;
+            }
+            self.heap.swap(smallest, index);
+            index = smallest;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Messages are told apart by their tempo
+    fn tempo_at(target_frame: Option<u64>, bpm: u32) -> ScheduledControlMessage {
+        ScheduledControlMessage {
+            target_frame,
+            message: SequencerControlMessage::SetTempo { bpm }
+        }
+    }
+
+    fn pop_all_due<const N: usize>(queue: &mut ControlMessageQueue<N>, frame: u64) -> Vec<u32> {
+        let mut popped = Vec::new();
+        while let Some(message) = queue.pop_due(frame) {
+            match message {
+                SequencerControlMessage::SetTempo { bpm } => popped.push(bpm),
+                message => panic!("unexpected message {:?}", message)
+            }
+        }
+        popped
+    }
+
+    #[test]
+    fn same_frame_messages_keep_their_order() {
+        let mut queue = ControlMessageQueue::<8>::default();
+        for bpm in [1, 2, 3] {
+            queue.push(tempo_at(Some(10), bpm)).unwrap();
+        }
+        assert_eq!(pop_all_due(&mut queue, 9), Vec::<u32>::new());
+        assert_eq!(pop_all_due(&mut queue, 10), vec![1, 2, 3]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn late_messages_come_out_by_target_frame() {
+        let mut queue = ControlMessageQueue::<8>::default();
+        queue.push(tempo_at(Some(20), 1)).unwrap();
+        queue.push(tempo_at(Some(10), 2)).unwrap();
+        queue.push(tempo_at(None, 3)).unwrap();
+        assert_eq!(pop_all_due(&mut queue, 0), vec![3]);
+        assert_eq!(pop_all_due(&mut queue, 10), vec![2]);
+        queue.push(tempo_at(Some(15), 4)).unwrap();
+        assert_eq!(pop_all_due(&mut queue, 30), vec![4, 1]);
+    }
+
+    #[test]
+    fn past_messages_are_due_straight_away() {
+        let mut queue = ControlMessageQueue::<8>::default();
+        queue.push(tempo_at(Some(5), 1)).unwrap();
+        queue.push(tempo_at(Some(0), 2)).unwrap();
+        assert_eq!(pop_all_due(&mut queue, 1000), vec![2, 1]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn far_future_messages_wait_without_holding_up_others() {
+        let mut queue = ControlMessageQueue::<8>::default();
+        queue.push(tempo_at(Some(u64::MAX), 1)).unwrap();
+        queue.push(tempo_at(Some(10), 2)).unwrap();
+        assert_eq!(pop_all_due(&mut queue, 10), vec![2]);
+        assert_eq!(pop_all_due(&mut queue, u64::MAX - 1), Vec::<u32>::new());
+        assert!(!queue.is_empty());
+        assert_eq!(pop_all_due(&mut queue, u64::MAX), vec![1]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn many_messages_come_out_by_frame_then_push_order() {
+        let mut queue = ControlMessageQueue::<64>::default();
+        let targets: Vec<u64> = (0..64).map(|i| (i * 7) % 13).collect();
+        for (bpm, &target) in targets.iter().enumerate() {
+            queue.push(tempo_at(Some(target), bpm as u32)).unwrap();
+        }
+        let mut expected: Vec<(u64, u32)> = targets.iter().enumerate()
+            .map(|(bpm, &target)| (target, bpm as u32))
+            .collect();
+        expected.sort();
+        let mut popped = Vec::new();
+        for frame in 0..13 {
+            popped.extend(pop_all_due(&mut queue, frame).into_iter().map(|bpm| (frame, bpm)));
+        }
+        assert_eq!(popped, expected);
+        assert!(queue.is_empty());
+
+        // Freed slots are reused
+        for bpm in 0..64 {
+            queue.push(tempo_at(Some(5), bpm)).unwrap();
+        }
+        assert_eq!(pop_all_due(&mut queue, 5), (0..64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn full_queue_hands_the_message_back() {
+        let mut queue = ControlMessageQueue::<2>::default();
+        queue.push(tempo_at(Some(10), 1)).unwrap();
+        queue.push(tempo_at(Some(10), 2)).unwrap();
+        assert!(queue.is_full());
+        assert!(queue.push(tempo_at(None, 3)).is_err());
+        assert_eq!(pop_all_due(&mut queue, 10), vec![1, 2]);
+        queue.push(tempo_at(None, 3)).unwrap();
+        assert_eq!(pop_all_due(&mut queue, 10), vec![3]);
+    }
+}
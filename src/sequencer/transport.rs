@@ -0,0 +1,49 @@
+use crate::sequencer::{Channel, Voice, MAX_CHANNELS, MAX_VOICES_PER_CHANNEL};
+
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransportState {
+    #[default] Playing,
+    // Playheads and the clock hold their position and the output fades out
+    Paused
+}
+
+// What ResetTransport returns to, captured by SaveTransportStart.  Defaults to
+// everything stopped.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TransportStart {
+    voices: [[Voice; MAX_VOICES_PER_CHANNEL]; MAX_CHANNELS],
+    clock_playing: bool
+}
+
+impl TransportStart {
+    pub fn capture(channels: &[Channel; MAX_CHANNELS], clock_playing: bool) -> Self {
+        let mut voices = [[Voice::default(); MAX_VOICES_PER_CHANNEL]; MAX_CHANNELS];
+        for (channel, voices) in channels.iter().zip(voices.iter_mut()) {
+            *voices = channel.voices;
+        }
+        Self {
+            voices,
+            clock_playing
+        }
+    }
+
+    // Restored voices count as launched on the given frame
+    pub fn restore(&self, channels: &mut [Channel; MAX_CHANNELS], frame: u64) {
+        for (channel, voices) in channels.iter_mut().zip(self.voices.iter()) {
+            for (voice, start) in channel.voices.iter_mut().zip(voices.iter()) {
+                *voice = Voice {
+                    started_at: frame,
+                    level: 0.0,
+                    override_this_frame: false,
+                    ..*start
+                };
+            }
+            channel.reset_junction_pass_counts();
+        }
+    }
+
+    pub fn clock_playing(&self) -> bool {
+        self.clock_playing
+    }
+}
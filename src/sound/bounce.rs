@@ -0,0 +1,284 @@
+use std::io::{Write, Seek};
+use std::path::Path;
+
+use dasp::Sample;
+use dasp::sample::types::I24;
+use hound::{WavSpec, WavWriter, SampleFormat};
+use serde::Deserialize;
+
+use crate::sound::{StereoFrame, StereoFrameGenerator, Float};
+
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BounceFormat {
+    Int16,
+    Int24,
+    Int32,
+    Float32
+}
+
+impl BounceFormat {
+    pub fn spec(&self, sample_rate: usize) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            BounceFormat::Int16 => (16, SampleFormat::Int),
+            BounceFormat::Int24 => (24, SampleFormat::Int),
+            BounceFormat::Int32 => (32, SampleFormat::Int),
+            BounceFormat::Float32 => (32, SampleFormat::Float)
+        };
+        WavSpec {
+            channels: 2,
+            sample_rate: sample_rate as u32,
+            bits_per_sample,
+            sample_format
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BounceLength {
+    Frames(u64),
+
+    // Render until the generator reports that it is finished, but never more
+    // than max_frames (a patch can loop forever)
+    UntilFinished {
+        max_frames: u64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BounceConfig {
+    pub sample_rate: usize,
+    pub format: BounceFormat,
+    pub length: BounceLength
+}
+
+pub fn bounce_to_wav_file<T, P>(
+    frame_generator: &mut T,
+    path: P,
+    config: &BounceConfig
+) -> hound::Result<u64>
+where
+    T: StereoFrameGenerator<Float>,
+    P: AsRef<Path>
+{
+    let writer = WavWriter::create(path, config.format.spec(config.sample_rate))?;
+    bounce(frame_generator, writer, config)
+}
+
+// Returns the number of frames written
+pub fn bounce<T, W>(
+    frame_generator: &mut T,
+    mut writer: WavWriter<W>,
+    config: &BounceConfig
+) -> hound::Result<u64>
+where
+    T: StereoFrameGenerator<Float>,
+    W: Write + Seek
+{
+    let (max_frames, until_finished) = match config.length {
+        BounceLength::Frames(frames) => (frames, false),
+        BounceLength::UntilFinished { max_frames } => (max_frames, true)
+    };
+
+    let mut frames_written = 0;
+    while frames_written < max_frames {
+        let frame = frame_generator.next_frame();
+        write_frame(&mut writer, frame, config.format)?;
+        frames_written += 1;
+        if until_finished && frame_generator.is_finished() {
+            break;
+        }
+    }
+    writer.finalize()?;
+
+    Ok(frames_written)
+}
+
+pub fn write_frame<W>(
+    writer: &mut WavWriter<W>,
+    frame: StereoFrame<Float>,
+    format: BounceFormat
+) -> hound::Result<()>
+where
+    W: Write + Seek
+{
+    for sample in [frame.left(), frame.right()] {
+        // The integer conversions overflow at exactly 1.0
+        let int_sample = sample.clamp(-1.0, 1.0 - Float::EPSILON);
+        match format {
+            BounceFormat::Int16 => writer.write_sample(int_sample.to_sample::<i16>())?,
+            BounceFormat::Int24 => writer.write_sample(int_sample.to_sample::<I24>().inner())?,
+            BounceFormat::Int32 => writer.write_sample(int_sample.to_sample::<i32>())?,
+            BounceFormat::Float32 => writer.write_sample(sample.clamp(-1.0, 1.0).to_sample::<f32>())?
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use hound::WavReader;
+
+    use super::*;
+    use crate::sound::{Sound, SoundMetadata, SoundBank};
+    use crate::sequencer::{
+        Sequencer, SequencerControlMessage, ChannelItemIndex, Clip, Playhead, PlayheadState, PlayheadDirection
+    };
+
+    const SAMPLE_RATE: usize = 48000;
+
+    // Plays back the given frames, then reports that it is finished
+    struct Frames {
+        frames: Vec<(Float, Float)>,
+        position: usize
+    }
+
+    impl StereoFrameGenerator<Float> for Frames {
+        fn next_frame(&mut self) -> StereoFrame<Float> {
+            let (left, right) = self.frames.get(self.position).copied().unwrap_or_default();
+            self.position += 1;
+            StereoFrame(left, right)
+        }
+
+        fn is_finished(&self) -> bool {
+            self.position >= self.frames.len()
+        }
+    }
+
+    fn bounce_to_frames<T>(frame_generator: &mut T, format: BounceFormat, length: BounceLength) -> Vec<(Float, Float)>
+    where
+        T: StereoFrameGenerator<Float>
+    {
+        let config = BounceConfig {
+            sample_rate: SAMPLE_RATE,
+            format,
+            length
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        let writer = WavWriter::new(&mut buffer, format.spec(SAMPLE_RATE)).unwrap();
+        let frames_written = bounce(frame_generator, writer, &config).unwrap();
+
+        buffer.set_position(0);
+        let reader = WavReader::new(buffer).unwrap();
+        let spec = reader.spec();
+        let samples: Vec<Float> = match spec.sample_format {
+            SampleFormat::Float => reader.into_samples::<f32>().collect::<Result<_, _>>().unwrap(),
+            SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as Float;
+                reader.into_samples::<i32>().map(|sample| sample.unwrap() as Float * scale).collect()
+            }
+        };
+        let frames: Vec<_> = samples.chunks(2).map(|frame| (frame[0], frame[1])).collect();
+        assert_eq!(frames.len() as u64, frames_written);
+        frames
+    }
+
+    fn assert_frames(actual: &[(Float, Float)], expected: &[(Float, Float)], tolerance: Float) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual.0 - expected.0).abs() <= tolerance && (actual.1 - expected.1).abs() <= tolerance,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn fixed_length_pads_past_the_end_of_the_generator() {
+        let mut frames = Frames {
+            frames: vec![(0.5, -0.5), (0.25, 0.0)],
+            position: 0
+        };
+        let bounced = bounce_to_frames(&mut frames, BounceFormat::Float32, BounceLength::Frames(4));
+        assert_frames(&bounced, &[(0.5, -0.5), (0.25, 0.0), (0.0, 0.0), (0.0, 0.0)], 0.0);
+    }
+
+    #[test]
+    fn until_finished_stops_with_the_generator() {
+        let mut frames = Frames {
+            frames: vec![(0.5, -0.5), (0.25, 0.0), (0.0, 0.25)],
+            position: 0
+        };
+        let bounced = bounce_to_frames(&mut frames, BounceFormat::Float32, BounceLength::UntilFinished { max_frames: 100 });
+        assert_frames(&bounced, &[(0.5, -0.5), (0.25, 0.0), (0.0, 0.25)], 0.0);
+
+        let mut frames = Frames {
+            frames: vec![(0.5, -0.5); 10],
+            position: 0
+        };
+        let bounced = bounce_to_frames(&mut frames, BounceFormat::Float32, BounceLength::UntilFinished { max_frames: 4 });
+        assert_eq!(bounced.len(), 4);
+    }
+
+    #[test]
+    fn float_keeps_full_scale() {
+        let mut frames = Frames {
+            frames: vec![(1.0, -1.0), (2.0, -2.0)],
+            position: 0
+        };
+        let bounced = bounce_to_frames(&mut frames, BounceFormat::Float32, BounceLength::Frames(2));
+        assert_frames(&bounced, &[(1.0, -1.0), (1.0, -1.0)], 0.0);
+    }
+
+    #[test]
+    fn integer_formats_clip_at_full_scale() {
+        for format in [BounceFormat::Int16, BounceFormat::Int24, BounceFormat::Int32] {
+            let mut frames = Frames {
+                frames: vec![(0.5, -0.25), (2.0, -2.0)],
+                position: 0
+            };
+            let bounced = bounce_to_frames(&mut frames, format, BounceLength::Frames(2));
+            assert_frames(&bounced, &[(0.5, -0.25), (1.0, -1.0)], 1e-4);
+        }
+    }
+
+    #[test]
+    fn bounces_a_clip_played_by_the_sequencer() {
+        let sound: Vec<_> = (1..=8).map(|i| StereoFrame(i as Float / 16.0, -(i as Float) / 16.0)).collect();
+        let sound = Sound {
+            metadata: SoundMetadata {
+                name: String::from("ramp"),
+                length: sound.len(),
+                path: PathBuf::new()
+            },
+            data: sound.into_boxed_slice()
+        };
+        let (_sound_bank_controller, sound_bank) = SoundBank::new(vec![sound]);
+        let (mut controller, mut sequencer) = Sequencer::new(sound_bank, SAMPLE_RATE);
+        controller.send(SequencerControlMessage::SyncClip {
+            index: ChannelItemIndex { channel_index: 0, item_index: 0 },
+            clip: Clip {
+                enabled: true,
+                source_index: 0,
+                channel_location_start: 0,
+                channel_location_end: 8,
+                source_scale: 1.0,
+                source_shift: 0
+            }
+        }).unwrap();
+        controller.send(SequencerControlMessage::LaunchPlayhead {
+            channel_index: 0,
+            playhead: Playhead {
+                state: PlayheadState::Playing,
+                location: 0,
+                direction: PlayheadDirection::Right
+            }
+        }).unwrap();
+        controller.flush();
+
+        let bounced = bounce_to_frames(&mut sequencer, BounceFormat::Float32, BounceLength::Frames(12));
+        // A centred channel plays at its own level on each side
+        let mut expected: Vec<_> = (1..=8)
+            .map(|i| (i as Float / 16.0, -(i as Float) / 16.0))
+            .collect();
+        expected.resize(12, (0.0, 0.0));
+        assert_frames(&bounced, &expected, 1e-6);
+    }
+}
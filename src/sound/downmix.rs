@@ -0,0 +1,79 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use crate::sound::{StereoFrame, Float};
+
+
+// Centre and surround channels are mixed in at -3 dB
+const SURROUND_GAIN: Float = FRAC_1_SQRT_2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Speaker {
+    Mono,
+    Left,
+    Right,
+    Centre,
+    LowFrequency,
+    SurroundLeft,
+    SurroundRight
+}
+
+impl Speaker {
+    fn gains(&self) -> (Float, Float) {
+        match self {
+            Speaker::Mono => (1.0, 1.0),
+            Speaker::Left => (1.0, 0.0),
+            Speaker::Right => (0.0, 1.0),
+            Speaker::Centre => (SURROUND_GAIN, SURROUND_GAIN),
+            // Too easily too loud on small speakers, and left out of most
+            // stereo downmixes
+            Speaker::LowFrequency => (0.0, 0.0),
+            Speaker::SurroundLeft => (SURROUND_GAIN, 0.0),
+            Speaker::SurroundRight => (0.0, SURROUND_GAIN)
+        }
+    }
+}
+
+// The left and right gain of each channel of a file with this many channels.
+// Mono plays at full level on both sides.  Plain WAV files don't say where
+// their channels go, so the default WAVE_FORMAT_EXTENSIBLE layouts are
+// assumed:
+//
+//   3  L R C
+//   4  L R BL BR
+//   5  L R C BL BR
+//   6  L R C LFE BL BR
+//   7  L R C LFE BC SL SR
+//   8  L R C LFE BL BR SL SR
+//
+// Any channels past the eighth alternate between the left and right
+// surrounds.  Nothing is scaled down to make room, the master bus catches
+// whatever ends up too loud.
+pub fn downmix_gains(channels: usize) -> Vec<(Float, Float)> {
+    use Speaker::*;
+    let layout: &[Speaker] = match channels {
+        0 => &[],
+        1 => &[Mono],
+        2 => &[Left, Right],
+        3 => &[Left, Right, Centre],
+        4 => &[Left, Right, SurroundLeft, SurroundRight],
+        5 => &[Left, Right, Centre, SurroundLeft, SurroundRight],
+        6 => &[Left, Right, Centre, LowFrequency, SurroundLeft, SurroundRight],
+        7 => &[Left, Right, Centre, LowFrequency, Centre, SurroundLeft, SurroundRight],
+        _ => &[Left, Right, Centre, LowFrequency, SurroundLeft, SurroundRight, SurroundLeft, SurroundRight]
+    };
+    let mut gains: Vec<(Float, Float)> = layout.iter().map(Speaker::gains).collect();
+    for channel in gains.len()..channels {
+        let speaker = if channel % 2 == 0 { SurroundLeft } else { SurroundRight };
+        gains.push(speaker.gains());
+    }
+    gains
+}
+
+// One frame of a file, one sample per channel
+pub fn downmix(samples: &[Float], gains: &[(Float, Float)]) -> StereoFrame<Float> {
+    let mut frame = StereoFrame::zero();
+    for (sample, (left, right)) in samples.iter().zip(gains) {
+        frame += StereoFrame(sample * left, sample * right);
+    }
+    frame
+}
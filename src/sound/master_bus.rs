@@ -0,0 +1,381 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::sound::{StereoFrame, StereoFrameGenerator, Float};
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct LimiterConfig {
+    pub ceiling_db: Float,
+    pub lookahead_ms: Float,
+    pub release_ms: Float
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            ceiling_db: -0.3,
+            lookahead_ms: 5.0,
+            release_ms: 100.0
+        }
+    }
+}
+
+// The soft clipper runs after the limiter (if any) and catches whatever is
+// still above the ceiling, e.g. when the limiter is disabled.  With the
+// limiter on it leaves everything up to the limiter ceiling untouched.
+#[derive(Debug, Clone, Copy)]
+pub struct MasterBusConfig {
+    pub limiter: Option<LimiterConfig>,
+    pub soft_clip: bool,
+    pub soft_clip_ceiling_db: Float
+}
+
+impl Default for MasterBusConfig {
+    fn default() -> Self {
+        Self {
+            limiter: Some(LimiterConfig::default()),
+            soft_clip: true,
+            soft_clip_ceiling_db: 0.0
+        }
+    }
+}
+
+// Shared with the UI, holds the lowest limiter gain applied since it was last
+// read.  Gains are positive so the bit patterns of the f32s order the same way
+// as the values, which lets the audio thread use fetch_min.
+#[derive(Debug, Clone)]
+pub struct MasterBusMeter {
+    min_gain: Arc<AtomicU32>
+}
+
+impl Default for MasterBusMeter {
+    fn default() -> Self {
+        Self {
+            min_gain: Arc::new(AtomicU32::new((1.0 as Float).to_bits()))
+        }
+    }
+}
+
+impl MasterBusMeter {
+    fn record(&self, gain: Float) {
+        self.min_gain.fetch_min(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    // Gain reduction (positive, in dB) since the last call
+    pub fn take_gain_reduction_db(&self) -> Float {
+        let min_gain = Float::from_bits(
+            self.min_gain.swap((1.0 as Float).to_bits(), Ordering::Relaxed)
+        );
+        -amplitude_to_db(min_gain)
+    }
+}
+
+// Look-ahead brickwall limiter.  The gain needed for each incoming frame is
+// run through a sliding minimum and then a moving average over the same
+// window, and the audio is delayed so that the averaged gain has fully ramped
+// down by the time a peak reaches the output.
+struct Limiter {
+    ceiling: Float,
+    release_coefficient: Float,
+    delay: Box<[StereoFrame<Float>]>,
+    delay_position: usize,
+    required_gains: Box<[Float]>,
+    smoothed_gains: Box<[Float]>,
+    window_position: usize,
+    smoothed_gain_sum: f64,
+    released_gain: Float
+}
+
+impl Limiter {
+    fn new(config: &LimiterConfig, sample_rate: usize) -> Self {
+        let lookahead = ((config.lookahead_ms / 1000.0 * sample_rate as Float).round() as usize).max(1);
+        let release_frames = (config.release_ms / 1000.0 * sample_rate as Float).max(1.0);
+        let window = lookahead + 1;
+        Self {
+            ceiling: db_to_amplitude(config.ceiling_db),
+            release_coefficient: 1.0 / release_frames,
+            delay: vec![StereoFrame::zero(); lookahead].into_boxed_slice(),
+            delay_position: 0,
+            required_gains: vec![1.0; window].into_boxed_slice(),
+            smoothed_gains: vec![1.0; window].into_boxed_slice(),
+            window_position: 0,
+            smoothed_gain_sum: window as f64,
+            released_gain: 1.0
+        }
+    }
+
+    fn process(&mut self, frame: StereoFrame<Float>) -> (StereoFrame<Float>, Float) {
+        let peak = frame.left().abs().max(frame.right().abs());
+        let required_gain = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+
+        let position = self.window_position;
+        self.required_gains[position] = required_gain;
+        let min_gain = self.required_gains.iter().copied().fold(1.0, Float::min);
+
+        self.released_gain = min_gain.min(
+            self.released_gain + self.release_coefficient * (1.0 - self.released_gain)
+        );
+        self.smoothed_gain_sum += self.released_gain as f64 - self.smoothed_gains[position] as f64;
+        self.smoothed_gains[position] = self.released_gain;
+        self.window_position = (position + 1) % self.smoothed_gains.len();
+
+        let gain = ((self.smoothed_gain_sum / self.smoothed_gains.len() as f64) as Float).min(1.0);
+
+        let delayed = std::mem::replace(&mut self.delay[self.delay_position], frame);
+        self.delay_position = (self.delay_position + 1) % self.delay.len();
+
+        (StereoFrame(gain * delayed.left(), gain * delayed.right()), gain)
+    }
+}
+
+pub struct MasterBus<T> where T: StereoFrameGenerator<Float> {
+    frame_generator: T,
+    limiter: Option<Limiter>,
+    soft_clip: Option<SoftClip>,
+    meter: MasterBusMeter,
+    // Frames pulled since the generator reported that it is finished, the
+    // limiter still holds its look-ahead worth of them
+    frames_since_finished: usize
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SoftClip {
+    knee: Float,
+    ceiling: Float
+}
+
+impl<T> MasterBus<T> where T: StereoFrameGenerator<Float> {
+    pub fn new(
+        frame_generator: T,
+        config: &MasterBusConfig,
+        sample_rate: usize,
+        meter: MasterBusMeter
+    ) -> Self {
+        let soft_clip = config.soft_clip.then(|| {
+            let ceiling = db_to_amplitude(config.soft_clip_ceiling_db);
+            let knee = match config.limiter {
+                Some(limiter) => db_to_amplitude(limiter.ceiling_db).min(ceiling),
+                None => 0.8 * ceiling
+            };
+            SoftClip { knee, ceiling }
+        });
+        Self {
+            frame_generator,
+            limiter: config.limiter.map(|limiter| Limiter::new(&limiter, sample_rate)),
+            soft_clip,
+            meter,
+            frames_since_finished: 0
+        }
+    }
+}
+
+impl<T> StereoFrameGenerator<Float> for MasterBus<T> where T: StereoFrameGenerator<Float> {
+    fn next_frame(&mut self) -> StereoFrame<Float> {
+        let mut frame = self.frame_generator.next_frame();
+        if self.frame_generator.is_finished() {
+            self.frames_since_finished = self.frames_since_finished.saturating_add(1);
+        } else {
+            self.frames_since_finished = 0;
+        }
+        if let Some(limiter) = &mut self.limiter {
+            let (limited, gain) = limiter.process(frame);
+            self.meter.record(gain);
+            frame = limited;
+        }
+        if let Some(soft_clip) = self.soft_clip {
+            frame = StereoFrame(soft_clip.apply(frame.left()), soft_clip.apply(frame.right()));
+        }
+        frame
+    }
+
+    fn is_finished(&self) -> bool {
+        let latency = self.limiter.as_ref().map_or(0, |limiter| limiter.delay.len());
+        self.frame_generator.is_finished() && self.frames_since_finished > latency
+    }
+}
+
+impl SoftClip {
+    // Linear up to the knee, then approaches the ceiling asymptotically with
+    // a continuous slope
+    fn apply(&self, sample: Float) -> Float {
+        let magnitude = sample.abs();
+        if magnitude <= self.knee {
+            return sample;
+        }
+        let range = self.ceiling - self.knee;
+        if range <= 0.0 {
+            return self.ceiling.copysign(sample);
+        }
+        let clipped = self.knee + range * ((magnitude - self.knee) / range).tanh();
+        clipped.copysign(sample)
+    }
+}
+
+pub fn db_to_amplitude(db: Float) -> Float {
+    (10.0 as Float).powf(db / 20.0)
+}
+
+pub fn amplitude_to_db(amplitude: Float) -> Float {
+    20.0 * amplitude.max(Float::MIN_POSITIVE).log10()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    // Plays back the given samples on both sides, then silence
+    struct Samples {
+        samples: Vec<Float>,
+        position: usize
+    }
+
+    impl StereoFrameGenerator<Float> for Samples {
+        fn next_frame(&mut self) -> StereoFrame<Float> {
+            let sample = self.samples.get(self.position).copied().unwrap_or_default();
+            self.position += 1;
+            StereoFrame(sample, sample)
+        }
+
+        fn is_finished(&self) -> bool {
+            self.position >= self.samples.len()
+        }
+    }
+
+    // Limiter only, so that the soft clipper can't hide anything
+    fn limit(samples: Vec<Float>, config: LimiterConfig, frames: usize) -> Vec<Float> {
+        let config = MasterBusConfig {
+            limiter: Some(config),
+            soft_clip: false,
+            ..Default::default()
+        };
+        let samples = Samples { samples, position: 0 };
+        let mut master_bus = MasterBus::new(samples, &config, SAMPLE_RATE, MasterBusMeter::default());
+        (0..frames)
+            .map(|_| {
+                let frame = master_bus.next_frame();
+                assert_eq!(frame.left(), frame.right());
+                frame.left()
+            })
+            .collect()
+    }
+
+    fn lookahead_frames(config: &LimiterConfig) -> usize {
+        (config.lookahead_ms / 1000.0 * SAMPLE_RATE as Float).round() as usize
+    }
+
+    #[test]
+    fn transients_never_pass_the_ceiling() {
+        let config = LimiterConfig::default();
+        let ceiling = db_to_amplitude(config.ceiling_db);
+        for peak in [1.0, 2.0, 10.0, 1000.0] {
+            let mut samples = vec![0.5; 1000];
+            samples[300] = peak;
+            samples[301] = -peak;
+            samples[700] = peak;
+            let output = limit(samples, config, 2000);
+            let loudest = output.iter().copied().map(Float::abs).fold(0.0, Float::max);
+            assert!(loudest <= ceiling * (1.0 + 1e-6), "a peak of {} came out at {}", peak, loudest);
+        }
+    }
+
+    #[test]
+    fn quiet_input_comes_out_unchanged_after_the_lookahead() {
+        let config = LimiterConfig::default();
+        let lookahead = lookahead_frames(&config);
+        let samples: Vec<Float> = (0..500).map(|i| (i as Float * 0.1).sin() * 0.5).collect();
+        let output = limit(samples.clone(), config, 500 + lookahead);
+        assert!(output[..lookahead].iter().all(|&sample| sample == 0.0));
+        assert_eq!(&output[lookahead..], &samples[..]);
+    }
+
+    #[test]
+    fn gain_comes_down_ahead_of_a_peak() {
+        let config = LimiterConfig::default();
+        let lookahead = lookahead_frames(&config);
+        let ceiling = db_to_amplitude(config.ceiling_db);
+        let peak_index = 1000;
+        let mut samples = vec![0.5; 2000];
+        samples[peak_index] = 4.0;
+        let output = limit(samples, config, 2000 + lookahead);
+
+        // The peak reaches the output lookahead frames late, fully limited
+        let peak_output = peak_index + lookahead;
+        assert!((output[peak_output] - ceiling).abs() < 1e-4, "{}", output[peak_output]);
+        // Untouched until the peak enters the look-ahead window, ramping down
+        // from there on
+        assert_eq!(output[peak_output - lookahead - 1], 0.5);
+        assert!(output[peak_output - lookahead / 2] < 0.5);
+        assert!(output[peak_output - 1] < output[peak_output - lookahead / 2]);
+    }
+
+    #[test]
+    fn gain_recovers_after_a_peak() {
+        let config = LimiterConfig::default();
+        let release_frames = (config.release_ms / 1000.0 * SAMPLE_RATE as Float) as usize;
+        let mut samples = vec![0.5; 100 + 10 * release_frames];
+        samples[100] = 4.0;
+        let output = limit(samples, config, 100 + 10 * release_frames);
+        let after_peak = output[100 + lookahead_frames(&config) + 1];
+        assert!(after_peak < 0.5 * 0.5, "{}", after_peak);
+        assert!((output.last().unwrap() - 0.5).abs() < 1e-3, "{}", output.last().unwrap());
+    }
+
+    // Pulls frames the way an offline render does
+    fn render_until_finished(samples: Vec<Float>, config: &MasterBusConfig) -> Vec<Float> {
+        let samples = Samples { samples, position: 0 };
+        let mut master_bus = MasterBus::new(samples, config, SAMPLE_RATE, MasterBusMeter::default());
+        let mut output = Vec::new();
+        loop {
+            output.push(master_bus.next_frame().left());
+            if master_bus.is_finished() || output.len() > SAMPLE_RATE {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn finishes_once_the_lookahead_has_drained() {
+        let config = MasterBusConfig::default();
+        let lookahead = lookahead_frames(&config.limiter.unwrap());
+        let output = render_until_finished(vec![0.1, 0.2, 0.3], &config);
+        assert_eq!(output.len(), 3 + lookahead);
+        assert_eq!(&output[lookahead..], &[0.1, 0.2, 0.3]);
+
+        let config = MasterBusConfig {
+            limiter: None,
+            ..Default::default()
+        };
+        assert_eq!(render_until_finished(vec![0.1, 0.2, 0.3], &config), [0.1, 0.2, 0.3]);
+    }
+
+    #[test]
+    fn soft_clip_leaves_limited_audio_alone() {
+        let config = MasterBusConfig::default();
+        let lookahead = lookahead_frames(&config.limiter.unwrap());
+        let ceiling = db_to_amplitude(config.limiter.unwrap().ceiling_db);
+        // Between the old knee and the limiter ceiling
+        let samples = vec![0.85, -0.9, 0.95, ceiling];
+        let output = render_until_finished(samples.clone(), &config);
+        assert_eq!(&output[lookahead..], &samples[..]);
+    }
+
+    #[test]
+    fn soft_clip_without_limiter_keeps_below_its_ceiling() {
+        let config = MasterBusConfig {
+            limiter: None,
+            ..Default::default()
+        };
+        let output = render_until_finished(vec![0.5, 0.9, 2.0, -10.0], &config);
+        assert_eq!(output[0], 0.5);
+        assert!(output[1] < 0.9 && output[1] > 0.8);
+        assert!(output[2..].iter().all(|sample| sample.abs() <= 1.0));
+    }
+}
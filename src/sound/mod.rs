@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use dasp::Sample;
 use hound::SampleFormat;
@@ -6,11 +6,19 @@ use hound::WavReader;
 
 mod sound_bank;
 mod output;
+mod timer_output;
 mod interpolator;
+mod bounce;
+mod master_bus;
+mod downmix;
 
 pub use sound_bank::*;
 pub use output::*;
+pub use timer_output::*;
 pub use interpolator::*;
+pub use bounce::*;
+pub use master_bus::*;
+pub use downmix::*;
 
 
 pub const MAX_SOUNDS: usize = 32;
@@ -27,49 +35,131 @@ pub struct Sound<S> where S: OutputSample {
 pub struct SoundMetadata {
     pub name: String,
     pub length: usize,
+    // Where the sound was loaded from, projects refer to sounds by path
+    pub path: PathBuf,
     // TODO: downsampled waveform
 }
 
 impl<S> Sound<S> where S: OutputSample {
-    pub fn from_wav_file(path: &str, format: &OutputConfig) -> Sound<S> {
-        let path = PathBuf::from(path);
-        let name = path.file_stem().unwrap()
-                               .to_str().unwrap()
-                               .to_owned();
-        let wav = WavReader::open(path).unwrap();
-        let sample_rate = wav.spec().sample_rate;
-
-        // TODO: handle stereo wav files
-        // TODO: better interpolation
-        let data: Vec<StereoFrame<S>> = match wav.spec().sample_format {
-            SampleFormat::Float => {
-                let data = wav.into_samples::<f32>()
-                   .map(|r| r.unwrap())
-                   .map(|s| s.to_sample::<S>());
-                MonoToStereoFrame::new(LinearInterpolator::new(
-                    data,
-                    (format.sample_rate as Float) / (sample_rate as Float)
-                )).collect()
-            },
+    // Resampled to output_sample_rate on load
+    pub fn from_wav_file(path: impl AsRef<Path>, output_sample_rate: usize) -> Result<Sound<S>, hound::Error> {
+        let path = path.as_ref().to_path_buf();
+        let name = path.file_stem()
+                       .map(|stem| stem.to_string_lossy().into_owned())
+                       .unwrap_or_default();
+        let wav = WavReader::open(&path)?;
+        let spec = wav.spec();
+        let channels = spec.channels as usize;
+
+        // Every format is read as floats from -1 to 1, one sample per channel
+        let samples: Vec<Float> = match spec.sample_format {
+            SampleFormat::Float => wav.into_samples::<f32>().collect::<Result<_, _>>()?,
             SampleFormat::Int => {
-                let data = wav.into_samples::<i16>()
-                   .map(|r| r.unwrap())
-                   .map(|s| s.to_sample::<S>());
-                MonoToStereoFrame::new(LinearInterpolator::new(
-                    data,
-                    (format.sample_rate as Float) / (sample_rate as Float)
-                )).collect()
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as Float;
+                wav.into_samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as Float * scale))
+                    .collect::<Result<_, _>>()?
             }
         };
-        
+        let gains = downmix_gains(channels);
+        let frames: Vec<StereoFrame<Float>> = samples.chunks_exact(channels.max(1))
+            .map(|samples| downmix(samples, &gains))
+            .collect();
+
+        // Both sides are resampled alike, so they stay aligned
+        // TODO: better interpolation
+        let ratio = (output_sample_rate as Float) / (spec.sample_rate as Float);
+        let left = LinearInterpolator::new(frames.iter().map(StereoFrame::left), ratio);
+        let right = LinearInterpolator::new(frames.iter().map(StereoFrame::right), ratio);
+        let data: Vec<StereoFrame<S>> = left.zip(right)
+            .map(|(left, right)| StereoFrame(left.to_sample::<S>(), right.to_sample::<S>()))
+            .collect();
+
         let metadata = SoundMetadata {
             name,
             length: data.len(),
+            path
         };
-        Sound {
+        Ok(Sound {
             metadata,
             data: data.into_boxed_slice()
-        }
+        })
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    use super::*;
+
+    const FIXTURE_SAMPLE_RATE: usize = 48000;
+
+    fn load(name: &str, sample_rate: usize) -> Vec<(Float, Float)> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        let sound = Sound::<Float>::from_wav_file(path, sample_rate).unwrap();
+        assert_eq!(sound.metadata.length, sound.data.len());
+        sound.data.iter().map(|frame| (frame.left(), frame.right())).collect()
+    }
+
+    fn assert_frames(actual: &[(Float, Float)], expected: &[(Float, Float)]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    // The stereo fixtures hold these frames in each sample format
+    const STEREO: [(Float, Float); 4] = [(0.5, -0.5), (0.25, 0.0), (0.0, 0.25), (-0.25, 0.5)];
+
+    // The multichannel fixtures play 0.5 on one channel per frame, in file
+    // order
+    const M: Float = 0.5 * FRAC_1_SQRT_2;
+
+    #[test]
+    fn mono_plays_on_both_sides() {
+        let frames = load("mono_int16.wav", FIXTURE_SAMPLE_RATE);
+        assert_frames(&frames, &[(0.5, 0.5), (-0.25, -0.25), (0.25, 0.25), (0.0, 0.0)]);
+    }
+
+    #[test]
+    fn stereo_keeps_its_image() {
+        for name in ["stereo_int16.wav", "stereo_int24.wav", "stereo_float32.wav"] {
+            assert_frames(&load(name, FIXTURE_SAMPLE_RATE), &STEREO);
+        }
+    }
+
+    #[test]
+    fn quad_mixes_back_channels_to_their_side() {
+        let frames = load("quad_int16.wav", FIXTURE_SAMPLE_RATE);
+        assert_frames(&frames, &[(0.5, 0.0), (0.0, 0.5), (M, 0.0), (0.0, M)]);
+    }
+
+    #[test]
+    fn surround_5_1_mixes_centre_to_both_sides_and_drops_lfe() {
+        let frames = load("surround_5_1_int16.wav", FIXTURE_SAMPLE_RATE);
+        assert_frames(&frames, &[(0.5, 0.0), (0.0, 0.5), (M, M), (0.0, 0.0), (M, 0.0), (0.0, M)]);
+    }
+
+    #[test]
+    fn surround_7_1_mixes_back_and_side_channels_to_their_side() {
+        let frames = load("surround_7_1_int16.wav", FIXTURE_SAMPLE_RATE);
+        assert_frames(&frames, &[
+            (0.5, 0.0), (0.0, 0.5), (M, M), (0.0, 0.0), (M, 0.0), (0.0, M), (M, 0.0), (0.0, M)
+        ]);
+    }
+
+    #[test]
+    fn resampling_keeps_frames_whole() {
+        let frames = load("mono_int16_24k.wav", FIXTURE_SAMPLE_RATE);
+        let expected = [0.5, 0.25, 0.0, -0.25, -0.5, -0.25, 0.0, 0.0];
+        let expected: Vec<_> = expected.iter().map(|&sample| (sample, sample)).collect();
+        assert_frames(&frames, &expected);
+    }
+}
@@ -1,38 +1,335 @@
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use dasp::{Sample, sample::{FromSample, ToSample}};
-use cpal::{Stream, StreamConfig, SampleFormat};
+use cpal::{
+    Stream, StreamConfig, SampleFormat, SampleRate, BufferSize, SupportedBufferSize, SupportedStreamConfigRange,
+    SupportedStreamConfig, Device, Host, StreamError
+};
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
 use assert_no_alloc::*;
+use serde::Deserialize;
 
-use crate::sound::Float;
+use hound::WavWriter;
+
+use crate::sound::{Float, MasterBus, MasterBusConfig, MasterBusMeter, BounceFormat, TimerOutput};
 
 #[cfg(debug_assertions)]
 #[global_allocator]
 static A: AllocDisabler = AllocDisabler;
 
 
+// Used by the null and WAV backends unless asked otherwise
+const TIMER_SAMPLE_RATE: usize = 48000;
+const TIMER_BUFFER_SIZE: u32 = 512;
+const MAX_TIMER_SAMPLE_RATE: u32 = 384000;
+const MAX_TIMER_BUFFER_SIZE: u32 = 65536;
+
+// Between attempts to reopen a device after the stream failed
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Stream errors within this long of the first are counted together, this
+// many of them rebuild the stream as if the device was lost
+const STREAM_ERROR_WINDOW: Duration = Duration::from_secs(1);
+const REPEATED_STREAM_ERRORS: u32 = 3;
+
+
 pub struct OutputConfig {
+    pub backend: OutputBackend,
+    // None for the host's default device
+    pub device_name: Option<String>,
     pub channels: usize,
     pub output_channels: (usize, usize),
     pub sample_rate: usize,
     pub sample_format: SampleFormat,
-    pub stream_config: StreamConfig
+    pub stream_config: StreamConfig,
+    pub master_bus: MasterBusConfig
 }
 
 impl Default for OutputConfig {
     fn default() -> Self {
+        Self::new(&OutputPreferences::default()).unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum OutputBackend {
+    Device,
+    // Runs the patch at real-time pace without making a sound
+    Null,
+    // The same, writing what would have been played to a WAV file
+    Wav {
+        path: PathBuf,
+        format: BounceFormat
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputBackendKind {
+    #[default] Device,
+    Null,
+    Wav
+}
+
+// What the user asked of the output, anything left out is taken from the
+// device's default config
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputPreferences {
+    pub backend: OutputBackendKind,
+    pub device: Option<String>,
+    pub sample_rate: Option<usize>,
+    // In frames
+    pub buffer_size: Option<u32>,
+    // Device channels that the left and right outputs are written to
+    pub output_channels: Option<(usize, usize)>,
+    // Only for the WAV backend
+    pub wav_path: Option<PathBuf>,
+    pub wav_format: Option<BounceFormat>
+}
+
+// An output device as listed to the user
+pub struct OutputDeviceInfo {
+    pub name: String,
+    pub is_default: bool,
+    pub default_config: Option<SupportedStreamConfig>,
+    pub configs: Vec<SupportedStreamConfigRange>
+}
+
+#[derive(Debug)]
+pub enum OutputConfigError {
+    NoDefaultDevice,
+    DeviceNotFound {
+        name: String,
+        available: Vec<String>
+    },
+    Device(String),
+    SampleRate {
+        sample_rate: usize,
+        supported: Vec<(u32, u32)>
+    },
+    BufferSize {
+        buffer_size: u32,
+        min: u32,
+        max: u32
+    },
+    OutputChannels {
+        output_channels: (usize, usize),
+        channels: usize
+    },
+    MissingWavPath
+}
+
+impl fmt::Display for OutputConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputConfigError::NoDefaultDevice => write!(f, "there is no default output device"),
+            OutputConfigError::DeviceNotFound { name, available } => write!(
+                f,
+                "no output device named \"{}\", available devices: {}",
+                name,
+                if available.is_empty() { String::from("none") } else { available.join(", ") }
+            ),
+            OutputConfigError::Device(error) => write!(f, "could not query the output device: {}", error),
+            OutputConfigError::SampleRate { sample_rate, supported } => {
+                let supported: Vec<String> = supported.iter()
+                    .map(|&(min, max)| if min == max { format!("{}", min) } else { format!("{}-{}", min, max) })
+                    .collect();
+                write!(
+                    f,
+                    "the output device does not support a sample rate of {}, supported rates: {}",
+                    sample_rate,
+                    supported.join(", ")
+                )
+            },
+            OutputConfigError::BufferSize { buffer_size, min, max } => write!(
+                f,
+                "buffer size {} is outside the range the output device supports ({} to {})",
+                buffer_size,
+                min,
+                max
+            ),
+            OutputConfigError::OutputChannels { output_channels, channels } => write!(
+                f,
+                "output channels ({}, {}) must be two different channels below {}, the device's channel count",
+                output_channels.0,
+                output_channels.1,
+                channels
+            ),
+            OutputConfigError::MissingWavPath => write!(f, "the wav backend needs a wav_path to write to")
+        }
+    }
+}
+
+impl std::error::Error for OutputConfigError {}
+
+impl OutputConfig {
+    pub fn new(preferences: &OutputPreferences) -> Result<Self, OutputConfigError> {
+        match preferences.backend {
+            OutputBackendKind::Device => match Self::for_device(preferences) {
+                // Without a sound card keep running silently rather than give
+                // up, unless a particular device was asked for
+                Err(err @ (OutputConfigError::NoDefaultDevice | OutputConfigError::Device(_)))
+                    if preferences.device.is_none() =>
+                {
+                    println!("No usable output device, running without sound ({})", err);
+                    Self::for_timer(preferences, OutputBackend::Null)
+                },
+                result => result
+            },
+            OutputBackendKind::Null => Self::for_timer(preferences, OutputBackend::Null),
+            OutputBackendKind::Wav => {
+                let path = preferences.wav_path.clone().ok_or(OutputConfigError::MissingWavPath)?;
+                let format = preferences.wav_format.unwrap_or(BounceFormat::Int24);
+                Self::for_timer(preferences, OutputBackend::Wav { path, format })
+            }
+        }
+    }
+
+    fn for_device(preferences: &OutputPreferences) -> Result<Self, OutputConfigError> {
         let host = cpal::default_host();
-        let device = host.default_output_device().unwrap();
-        let supported_config = device.default_output_config().unwrap();
-        Self { 
-            channels: supported_config.channels() as usize,
-            output_channels: (0, 1),
+        let device = find_output_device(&host, preferences.device.as_deref())?;
+        let default_config = device.default_output_config().map_err(device_error)?;
+
+        let supported_config = match preferences.sample_rate {
+            Some(sample_rate) if sample_rate != default_config.sample_rate().0 as usize => {
+                let ranges: Vec<_> = device.supported_output_configs().map_err(device_error)?.collect();
+                let rate = SampleRate(sample_rate as u32);
+                // Keep the default format and channel count where the device allows it
+                ranges.iter()
+                    .filter(|range| range.min_sample_rate() <= rate && rate <= range.max_sample_rate())
+                    .max_by_key(|range| (
+                        range.sample_format() == default_config.sample_format(),
+                        range.channels() == default_config.channels()
+                    ))
+                    .map(|range| range.clone().with_sample_rate(rate))
+                    .ok_or_else(|| {
+                        let mut supported: Vec<_> = ranges.iter()
+                            .map(|range| (range.min_sample_rate().0, range.max_sample_rate().0))
+                            .collect();
+                        supported.sort();
+                        supported.dedup();
+                        OutputConfigError::SampleRate { sample_rate, supported }
+                    })?
+            },
+            _ => default_config
+        };
+
+        let mut stream_config = supported_config.config();
+        if let Some(buffer_size) = preferences.buffer_size {
+            let (min, max) = match *supported_config.buffer_size() {
+                SupportedBufferSize::Range { min, max } => (min, max),
+                SupportedBufferSize::Unknown => (1, u32::MAX)
+            };
+            if buffer_size < min.max(1) || buffer_size > max {
+                return Err(OutputConfigError::BufferSize { buffer_size, min, max });
+            }
+            stream_config.buffer_size = BufferSize::Fixed(buffer_size);
+        }
+
+        let channels = supported_config.channels() as usize;
+        Ok(Self {
+            backend: OutputBackend::Device,
+            device_name: preferences.device.clone(),
+            channels,
+            output_channels: output_channels(preferences, channels)?,
             sample_rate: supported_config.sample_rate().0 as usize,
             sample_format: supported_config.sample_format(),
-            stream_config: supported_config.config()
+            stream_config,
+            master_bus: MasterBusConfig::default()
+        })
+    }
+
+    // Stereo, with no device to ask what it supports
+    fn for_timer(preferences: &OutputPreferences, backend: OutputBackend) -> Result<Self, OutputConfigError> {
+        let sample_rate = preferences.sample_rate.unwrap_or(TIMER_SAMPLE_RATE);
+        if sample_rate == 0 || sample_rate > MAX_TIMER_SAMPLE_RATE as usize {
+            return Err(OutputConfigError::SampleRate {
+                sample_rate,
+                supported: vec![(1, MAX_TIMER_SAMPLE_RATE)]
+            });
         }
+        let buffer_size = preferences.buffer_size.unwrap_or(TIMER_BUFFER_SIZE);
+        if buffer_size == 0 || buffer_size > MAX_TIMER_BUFFER_SIZE {
+            return Err(OutputConfigError::BufferSize {
+                buffer_size,
+                min: 1,
+                max: MAX_TIMER_BUFFER_SIZE
+            });
+        }
+        let channels = 2;
+        Ok(Self {
+            backend,
+            device_name: None,
+            channels,
+            output_channels: output_channels(preferences, channels)?,
+            sample_rate,
+            sample_format: SampleFormat::F32,
+            stream_config: StreamConfig {
+                channels: channels as u16,
+                sample_rate: SampleRate(sample_rate as u32),
+                buffer_size: BufferSize::Fixed(buffer_size)
+            },
+            master_bus: MasterBusConfig::default()
+        })
+    }
+}
+
+// The default pair folds onto a mono device, a chosen pair has to exist
+fn output_channels(preferences: &OutputPreferences, channels: usize) -> Result<(usize, usize), OutputConfigError> {
+    match preferences.output_channels {
+        Some((left, right)) if left >= channels || right >= channels || left == right => {
+            Err(OutputConfigError::OutputChannels { output_channels: (left, right), channels })
+        },
+        Some(output_channels) => Ok(output_channels),
+        None => Ok((0, 1))
+    }
+}
+
+// Every output device of the default host.  Devices that fail to report
+// their configs are still listed.
+pub fn output_devices() -> Result<Vec<OutputDeviceInfo>, OutputConfigError> {
+    let host = cpal::default_host();
+    let default_name = host.default_output_device().and_then(|device| device.name().ok());
+    let mut devices = Vec::new();
+    for device in host.output_devices().map_err(device_error)? {
+        let Ok(name) = device.name() else {
+            continue;
+        };
+        devices.push(OutputDeviceInfo {
+            is_default: default_name.as_ref() == Some(&name),
+            name,
+            default_config: device.default_output_config().ok(),
+            configs: device.supported_output_configs().map(Iterator::collect).unwrap_or_default()
+        });
     }
+    Ok(devices)
+}
+
+// The default device when no name is given
+pub fn find_output_device(host: &Host, name: Option<&str>) -> Result<Device, OutputConfigError> {
+    let Some(name) = name else {
+        return host.default_output_device().ok_or(OutputConfigError::NoDefaultDevice);
+    };
+    let mut available = Vec::new();
+    for device in host.output_devices().map_err(device_error)? {
+        match device.name() {
+            Ok(device_name) if device_name == name => return Ok(device),
+            Ok(device_name) => available.push(device_name),
+            Err(_) => {}
+        }
+    }
+    Err(OutputConfigError::DeviceNotFound {
+        name: String::from(name),
+        available
+    })
+}
+
+fn device_error(error: impl fmt::Display) -> OutputConfigError {
+    OutputConfigError::Device(error.to_string())
 }
 
 pub trait OutputSample:
@@ -95,6 +392,15 @@ impl<S> StereoFrame<S> where S: OutputSample {
     pub fn right(&self) -> S {
         self.1
     }
+
+    pub fn lerp(&self, other: StereoFrame<S>, t: Float) -> StereoFrame<S> {
+        let lerp = |a: S, b: S| {
+            let a = a.to_sample::<Float>();
+            let b = b.to_sample::<Float>();
+            ((1.0 - t) * a + t * b).to_sample::<S>()
+        };
+        StereoFrame(lerp(self.0, other.0), lerp(self.1, other.1))
+    }
 }
 
 impl<S> std::ops::AddAssign for StereoFrame<S> where S: OutputSample {
@@ -106,71 +412,249 @@ impl<S> std::ops::AddAssign for StereoFrame<S> where S: OutputSample {
 
 pub trait StereoFrameGenerator<S> where S: OutputSample {
     fn next_frame(&mut self) -> StereoFrame<S>;
+
+    // Only consulted when rendering offline, a live stream runs until dropped
+    fn is_finished(&self) -> bool {
+        false
+    }
 }
 
-pub struct MonoToStereoFrame<I> where I: Iterator {
-    iterator: I,
+pub struct Output {
+    output_config: OutputConfig,
+    output_stream: Option<Stream>,
+    // Drives the null and WAV backends instead of a device stream
+    timer_output: Option<TimerOutput>,
+    // Shared with whichever device stream is running, so that a replacement
+    // carries on where a failed stream stopped
+    frame_generator: Option<SharedFrameGenerator>,
+    // The device asked for, kept while a fallback to the default one plays
+    preferred_device: Option<String>,
+    stream_errors: Arc<Mutex<StreamErrors>>,
+    last_reconnect: Option<Instant>,
+    status: OutputStatus,
+    master_bus_meter: MasterBusMeter
 }
 
-impl<I> MonoToStereoFrame<I> where I: Iterator {
-    pub fn new(iterator: I) -> Self {
-        Self {
-            iterator,
-        }
-    }
+type SharedFrameGenerator = Arc<Mutex<dyn StereoFrameGenerator<Float> + Send>>;
+
+// Errors reported by the running stream.  Backends also report passing
+// trouble such as an underrun this way, so only a lost device or errors
+// that keep coming have the stream rebuilt.
+#[derive(Debug, Default)]
+struct StreamErrors {
+    device_lost: bool,
+    count: u32,
+    first_at: Option<Instant>,
+    last: Option<String>
 }
 
-impl<I> Iterator for MonoToStereoFrame<I> where I: Iterator, I::Item: OutputSample {
-    type Item = StereoFrame<I::Item>;
+impl StreamErrors {
+    fn report(&mut self, error: &StreamError, now: Instant) {
+        self.device_lost |= matches!(error, StreamError::DeviceNotAvailable);
+        self.count += 1;
+        self.first_at.get_or_insert(now);
+        self.last = Some(error.to_string());
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(sample) = self.iterator.next() {
-            return Some(StereoFrame(sample, sample));
+    // The error to rebuild the stream for, if any.  Errors that stopped
+    // coming are forgotten once the window has passed.
+    fn take_fatal(&mut self, now: Instant) -> Option<String> {
+        if self.device_lost || self.count >= REPEATED_STREAM_ERRORS {
+            return std::mem::take(self).last;
+        }
+        if self.first_at.is_some_and(|first_at| now.duration_since(first_at) >= STREAM_ERROR_WINDOW) {
+            *self = Self::default();
         }
         None
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputStatus {
+    Stopped,
+    Playing {
+        device: String
+    },
+    Silent,
+    Recording {
+        path: PathBuf
+    },
+    // The stream failed and no device could be opened since
+    Reconnecting {
+        error: String
+    }
+}
 
-pub struct Output {
-    output_config: OutputConfig,
-    output_stream: Option<Stream>
+impl fmt::Display for OutputStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputStatus::Stopped => write!(f, "output stopped"),
+            OutputStatus::Playing { device } => write!(f, "output {}", device),
+            OutputStatus::Silent => write!(f, "no output, running silently"),
+            OutputStatus::Recording { path } => write!(f, "recording to {}", path.display()),
+            OutputStatus::Reconnecting { error } => write!(f, "output lost ({}), reconnecting", error)
+        }
+    }
 }
 
 impl Output {
     pub fn new(output_config: OutputConfig) -> Self {
         Self {
+            preferred_device: output_config.device_name.clone(),
             output_config,
-            output_stream: None
+            output_stream: None,
+            timer_output: None,
+            frame_generator: None,
+            stream_errors: Arc::new(Mutex::new(StreamErrors::default())),
+            last_reconnect: None,
+            status: OutputStatus::Stopped,
+            master_bus_meter: MasterBusMeter::default()
         }
     }
 
+    pub fn config(&self) -> &OutputConfig {
+        &self.output_config
+    }
+
+    pub fn master_bus_meter(&self) -> &MasterBusMeter {
+        &self.master_bus_meter
+    }
+
+    pub fn status(&self) -> &OutputStatus {
+        &self.status
+    }
+
     pub fn start<T: 'static + StereoFrameGenerator<Float> + Send>(&mut self, frame_generator: T) {
+        let frame_generator = MasterBus::new(
+            frame_generator,
+            &self.output_config.master_bus,
+            self.output_config.sample_rate,
+            self.master_bus_meter.clone()
+        );
+
+        let writer = match &self.output_config.backend {
+            OutputBackend::Device => {
+                self.frame_generator = Some(Arc::new(Mutex::new(frame_generator)));
+                // A device that fails right away is retried like one that
+                // fails later
+                if let Err(err) = self.open_stream() {
+                    println!("{}", err);
+                    self.status = OutputStatus::Reconnecting { error: err.to_string() };
+                    self.last_reconnect = Some(Instant::now());
+                }
+                return;
+            },
+            OutputBackend::Null => None,
+            // Playing on without the recording beats not playing at all
+            OutputBackend::Wav { path, format } => {
+                match WavWriter::create(path, format.spec(self.output_config.sample_rate)) {
+                    Ok(writer) => Some((writer, *format)),
+                    Err(err) => {
+                        println!("could not record to {}: {}", path.display(), err);
+                        None
+                    }
+                }
+            }
+        };
+        self.status = match (&self.output_config.backend, &writer) {
+            (OutputBackend::Wav { path, .. }, Some(_)) => OutputStatus::Recording { path: path.clone() },
+            _ => OutputStatus::Silent
+        };
+        let buffer_size = match self.output_config.stream_config.buffer_size {
+            BufferSize::Fixed(buffer_size) => buffer_size as usize,
+            BufferSize::Default => TIMER_BUFFER_SIZE as usize
+        };
+        self.timer_output = Some(TimerOutput::start(
+            frame_generator,
+            self.output_config.sample_rate,
+            buffer_size,
+            writer
+        ));
+    }
+
+    // Picks up stream errors and keeps trying to reopen a device until one
+    // plays.  Called regularly from the UI thread.
+    pub fn update(&mut self) {
+        let error = self.stream_errors.lock().ok().and_then(|mut errors| errors.take_fatal(Instant::now()));
+        if let Some(error) = error {
+            // Dropping the failed stream also releases the frame generator
+            self.output_stream = None;
+            self.status = OutputStatus::Reconnecting { error };
+            self.last_reconnect = None;
+        }
+
+        if !matches!(self.status, OutputStatus::Reconnecting { .. }) {
+            return;
+        }
+        if self.last_reconnect.is_some_and(|last| last.elapsed() < RECONNECT_INTERVAL) {
+            return;
+        }
+        self.last_reconnect = Some(Instant::now());
+
+        let mut last_error = None;
+        for device in reconnect_devices(&self.preferred_device) {
+            match reopen_config(&self.output_config, device, OutputConfig::for_device) {
+                Ok(config) => {
+                    let previous_config = std::mem::replace(&mut self.output_config, config);
+                    match self.open_stream() {
+                        Ok(()) => {
+                            println!("{}", self.status);
+                            return;
+                        },
+                        Err(err) => {
+                            self.output_config = previous_config;
+                            last_error = Some(err);
+                        }
+                    }
+                },
+                Err(err) => last_error = Some(err)
+            }
+        }
+        if let Some(err) = last_error {
+            self.status = OutputStatus::Reconnecting { error: err.to_string() };
+        }
+    }
+
+    fn open_stream(&mut self) -> Result<(), OutputConfigError> {
+        let Some(frame_generator) = self.frame_generator.clone() else {
+            return Ok(());
+        };
+        let host = cpal::default_host();
+        let device = find_output_device(&host, self.output_config.device_name.as_deref())?;
+
         use SampleFormat::*;
         let stream = match self.output_config.sample_format {
-            I16 => self.build_stream::<i16, _>(frame_generator),
-            U16 => self.build_stream::<u16, _>(frame_generator),
-            F32 => self.build_stream::<f32, _>(frame_generator)
-        };
-        stream.play().unwrap();
+            I16 => self.build_stream::<i16>(&device, frame_generator),
+            U16 => self.build_stream::<u16>(&device, frame_generator),
+            F32 => self.build_stream::<f32>(&device, frame_generator)
+        }?;
+        stream.play().map_err(device_error)?;
 
         self.output_stream = Some(stream);
+        self.status = OutputStatus::Playing {
+            device: device.name().unwrap_or_else(|_| String::from("unknown device"))
+        };
+        Ok(())
     }
 
-    pub fn build_stream<S: OutputSample, T: 'static + StereoFrameGenerator<Float> + Send>(
+    fn build_stream<S: OutputSample>(
         &self,
-        mut frame_generator: T
-    ) -> Stream {
-        let host = cpal::default_host();
-        let device = host.default_output_device().unwrap();
-        
+        device: &Device,
+        frame_generator: SharedFrameGenerator
+    ) -> Result<Stream, OutputConfigError> {
         let channels = self.output_config.channels;
         let output_channels = self.output_config.output_channels;
+        let stream_errors = self.stream_errors.clone();
 
         device.build_output_stream(
             &self.output_config.stream_config,
             move |data: &mut [S], _| {
                 assert_no_alloc(|| {
+                    // Only contended while a replacement stream is being built
+                    let Ok(mut frame_generator) = frame_generator.try_lock() else {
+                        data.fill(S::EQUILIBRIUM);
+                        return;
+                    };
                     for out_frame in data.chunks_mut(channels) {
                         let frame = frame_generator.next_frame();
                         for (i, out_sample) in out_frame.iter_mut().enumerate() {
@@ -187,7 +671,202 @@ impl Output {
             },
             move |err| {
                 println!{"{}", err};
+                if let Ok(mut stream_errors) = stream_errors.lock() {
+                    stream_errors.report(&err, Instant::now());
+                }
+            },
+        ).map_err(device_error)
+    }
+}
+
+// The same device first, then whichever is the default now
+fn reconnect_devices(preferred_device: &Option<String>) -> Vec<Option<String>> {
+    let mut devices = vec![preferred_device.clone()];
+    if preferred_device.is_some() {
+        devices.push(None);
+    }
+    devices
+}
+
+// The sequencer keeps running at the same sample rate, settings that only
+// suited the old device are dropped before giving up on a new one
+fn reopen_config(
+    previous: &OutputConfig,
+    device: Option<String>,
+    mut for_device: impl FnMut(&OutputPreferences) -> Result<OutputConfig, OutputConfigError>
+) -> Result<OutputConfig, OutputConfigError> {
+    let mut preferences = OutputPreferences {
+        device,
+        sample_rate: Some(previous.sample_rate),
+        buffer_size: match previous.stream_config.buffer_size {
+            BufferSize::Fixed(buffer_size) => Some(buffer_size),
+            BufferSize::Default => None
+        },
+        output_channels: Some(previous.output_channels),
+        ..Default::default()
+    };
+    loop {
+        match for_device(&preferences) {
+            Err(OutputConfigError::OutputChannels { .. }) if preferences.output_channels.is_some() => {
+                preferences.output_channels = None;
+            },
+            Err(OutputConfigError::BufferSize { .. }) if preferences.buffer_size.is_some() => {
+                preferences.buffer_size = None;
+            },
+            Ok(mut config) => {
+                config.master_bus = previous.master_bus;
+                return Ok(config);
             },
-        ).unwrap()
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts<S: OutputSample>(frame: StereoFrame<S>) -> (S, S) {
+        (frame.left(), frame.right())
+    }
+
+    #[test]
+    fn lerp_between_frames() {
+        let a: StereoFrame<Float> = StereoFrame(0.0, 1.0);
+        let b = StereoFrame(1.0, -1.0);
+        assert_eq!(parts(a.lerp(b, 0.0)), (0.0, 1.0));
+        assert_eq!(parts(a.lerp(b, 1.0)), (1.0, -1.0));
+        assert_eq!(parts(a.lerp(b, 0.25)), (0.25, 0.5));
+    }
+
+    #[test]
+    fn lerp_converts_integer_samples() {
+        let a: StereoFrame<i16> = StereoFrame(0, -16384);
+        let b = StereoFrame(16384, 16384);
+        assert_eq!(parts(a.lerp(b, 0.5)), (8192, 0));
+    }
+
+    fn backend_error() -> StreamError {
+        StreamError::BackendSpecific {
+            err: cpal::BackendSpecificError { description: String::from("underrun") }
+        }
+    }
+
+    #[test]
+    fn lost_devices_rebuild_the_stream_straight_away() {
+        let now = Instant::now();
+        let mut errors = StreamErrors::default();
+        assert_eq!(errors.take_fatal(now), None);
+        errors.report(&StreamError::DeviceNotAvailable, now);
+        assert!(errors.take_fatal(now).is_some());
+        assert_eq!(errors.take_fatal(now), None);
+    }
+
+    #[test]
+    fn only_repeated_backend_errors_rebuild_the_stream() {
+        let now = Instant::now();
+        let mut errors = StreamErrors::default();
+        for _ in 1..REPEATED_STREAM_ERRORS {
+            errors.report(&backend_error(), now);
+            assert_eq!(errors.take_fatal(now), None);
+        }
+        errors.report(&backend_error(), now);
+        assert_eq!(errors.take_fatal(now), Some(backend_error().to_string()));
+        assert_eq!(errors.count, 0);
+    }
+
+    #[test]
+    fn scattered_backend_errors_are_forgotten() {
+        let now = Instant::now();
+        let mut errors = StreamErrors::default();
+        for second in 0..REPEATED_STREAM_ERRORS as u64 * 2 {
+            let at = now + Duration::from_secs(second) + STREAM_ERROR_WINDOW / 2;
+            errors.report(&backend_error(), at);
+            assert_eq!(errors.take_fatal(at + STREAM_ERROR_WINDOW / 2), None);
+        }
+    }
+
+    #[test]
+    fn reconnects_try_the_same_device_then_the_default() {
+        assert_eq!(reconnect_devices(&Some(String::from("usb"))), [Some(String::from("usb")), None]);
+        assert_eq!(reconnect_devices(&None), [None]);
+    }
+
+    fn device_config(device_name: Option<String>, buffer_size: BufferSize) -> OutputConfig {
+        OutputConfig {
+            backend: OutputBackend::Device,
+            device_name,
+            channels: 4,
+            output_channels: (2, 3),
+            sample_rate: 44100,
+            sample_format: SampleFormat::F32,
+            stream_config: StreamConfig {
+                channels: 4,
+                sample_rate: SampleRate(44100),
+                buffer_size
+            },
+            master_bus: MasterBusConfig::default()
+        }
+    }
+
+    // What reopen_config asked for on each try, as (device, rate, buffer
+    // size, output channels)
+    type Tries = Vec<(Option<String>, Option<usize>, Option<u32>, Option<(usize, usize)>)>;
+
+    fn reopen(
+        device: Option<String>,
+        mut for_device: impl FnMut(&OutputPreferences) -> Result<OutputConfig, OutputConfigError>
+    ) -> (Result<OutputConfig, OutputConfigError>, Tries) {
+        let previous = device_config(Some(String::from("usb")), BufferSize::Fixed(256));
+        let mut tries = Vec::new();
+        let result = reopen_config(&previous, device, |preferences| {
+            tries.push((
+                preferences.device.clone(),
+                preferences.sample_rate,
+                preferences.buffer_size,
+                preferences.output_channels
+            ));
+            for_device(preferences)
+        });
+        (result, tries)
+    }
+
+    #[test]
+    fn reopening_keeps_every_setting_the_device_allows() {
+        let (result, tries) = reopen(Some(String::from("usb")), |preferences| {
+            Ok(device_config(preferences.device.clone(), BufferSize::Fixed(256)))
+        });
+        assert_eq!(result.unwrap().device_name.as_deref(), Some("usb"));
+        assert_eq!(tries, [(Some(String::from("usb")), Some(44100), Some(256), Some((2, 3)))]);
+    }
+
+    #[test]
+    fn reopening_drops_output_channels_then_buffer_size() {
+        let (result, tries) = reopen(None, |preferences| {
+            if let Some(output_channels) = preferences.output_channels {
+                return Err(OutputConfigError::OutputChannels { output_channels, channels: 2 });
+            }
+            if let Some(buffer_size) = preferences.buffer_size {
+                return Err(OutputConfigError::BufferSize { buffer_size, min: 512, max: 4096 });
+            }
+            Ok(device_config(None, BufferSize::Default))
+        });
+        assert!(result.is_ok());
+        assert_eq!(tries, [
+            (None, Some(44100), Some(256), Some((2, 3))),
+            (None, Some(44100), Some(256), None),
+            (None, Some(44100), None, None)
+        ]);
+    }
+
+    #[test]
+    fn reopening_never_gives_up_the_sample_rate() {
+        let (result, tries) = reopen(None, |preferences| Err(OutputConfigError::SampleRate {
+            sample_rate: preferences.sample_rate.unwrap(),
+            supported: vec![(48000, 48000)]
+        }));
+        assert!(matches!(result, Err(OutputConfigError::SampleRate { sample_rate: 44100, .. })));
+        assert_eq!(tries.len(), 1);
     }
 }
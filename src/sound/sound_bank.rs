@@ -1,6 +1,6 @@
 use rtrb::{RingBuffer, Producer, Consumer};
 
-use crate::sound::{Sound, SoundMetadata, OutputSample, StereoFrame, MAX_SOUNDS};
+use crate::sound::{Sound, SoundMetadata, OutputSample, StereoFrame, Float, MAX_SOUNDS};
 
 
 enum SoundBankControlMessage<S> where S: OutputSample {
@@ -12,7 +12,12 @@ enum SoundBankControlMessage<S> where S: OutputSample {
 
 pub struct SoundBankController<S> where S: OutputSample {
     pub metadata: [Option<SoundMetadata>; MAX_SOUNDS],
-    producer: Producer<SoundBankControlMessage<S>>
+    producer: Producer<SoundBankControlMessage<S>>,
+    // Changes that did not fit in the ring buffer, e.g. while no stream is
+    // running to drain it.  At most one per slot.
+    pending: Vec<SoundBankControlMessage<S>>,
+    // Sounds replaced on the audio thread come back here to be dropped
+    released: Consumer<Sound<S>>
 }
 
 impl<S> SoundBankController<S> where S: OutputSample {
@@ -21,15 +26,50 @@ impl<S> SoundBankController<S> where S: OutputSample {
             if slot.is_none() {
                 let metadata = sound.metadata.clone();
                 *slot = Some(metadata);
-                self.producer.push(SoundBankControlMessage::Set {
+                self.send(SoundBankControlMessage::Set {
                     index: i,
                     sound: Some(sound)
-                }).unwrap();
+                });
                 return;
             }
         }
     }
 
+    // Replaces or clears a slot, whatever was there is dropped off the audio
+    // thread on a later call to update
+    pub fn set_sound(&mut self, index: usize, sound: Option<Sound<S>>) {
+        self.update();
+        self.metadata[index] = sound.as_ref().map(|sound| sound.metadata.clone());
+        self.send(SoundBankControlMessage::Set {
+            index,
+            sound
+        });
+    }
+
+    // A newer change to a slot replaces one still pending, the replaced
+    // sound never reached the audio thread so it is dropped here
+    fn send(&mut self, message: SoundBankControlMessage<S>) {
+        let SoundBankControlMessage::Set { index, .. } = message;
+        self.pending.retain(|SoundBankControlMessage::Set { index: pending_index, .. }| *pending_index != index);
+        self.pending.push(message);
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        let count = self.producer.slots().min(self.pending.len());
+        for message in self.pending.drain(..count) {
+            // Cannot fail, there are at least count free slots
+            let _ = self.producer.push(message);
+        }
+    }
+
+    // Drops released sounds and sends whatever is pending, should be called
+    // regularly from the UI thread
+    pub fn update(&mut self) {
+        while self.released.pop().is_ok() {}
+        self.flush();
+    }
+
     pub fn get(&self, index: usize) -> Option<&SoundMetadata> {
         let slot = self.metadata.get(index)?;
         if let Some(sound_metadata) = slot {
@@ -42,25 +82,34 @@ impl<S> SoundBankController<S> where S: OutputSample {
 
 pub struct SoundBankIndex {
     pub source_index: usize,
-    pub frame_index: usize
+    pub frame_index: usize,
+    // Position between frame_index and frame_index + 1, in [0, 1)
+    pub frame_fraction: Float
 }
 
 pub struct SoundBank<S> where S: OutputSample {
     sounds: [Option<Sound<S>>; MAX_SOUNDS],
-    consumer: Consumer<SoundBankControlMessage<S>>
+    consumer: Consumer<SoundBankControlMessage<S>>,
+    released: Producer<Sound<S>>
 }
 
 impl<S> SoundBank<S> where S: OutputSample {
     pub fn new(sounds: Vec<Sound<S>>) -> (SoundBankController<S>, SoundBank<S>) {
         let (producer, consumer) = RingBuffer::new(MAX_SOUNDS);
+        // Every slot can be replaced while the previous round is still
+        // waiting to be dropped
+        let (released_producer, released_consumer) = RingBuffer::new(2 * MAX_SOUNDS);
         
         let mut sound_bank_metadata = SoundBankController {
             metadata: Default::default(),
-            producer
+            producer,
+            pending: Vec::new(),
+            released: released_consumer
         };
         let mut sound_bank = SoundBank {
             sounds: Default::default(),
-            consumer
+            consumer,
+            released: released_producer
         };
         
         for sound in sounds {
@@ -76,7 +125,12 @@ impl<S> SoundBank<S> where S: OutputSample {
             use SoundBankControlMessage::*;
             match item {
                 Set { index, sound } => {
-                    self.sounds[index] = sound;
+                    let previous = std::mem::replace(&mut self.sounds[index], sound);
+                    if let Some(previous) = previous {
+                        // Only dropped here if the controller has fallen far
+                        // behind
+                        let _ = self.released.push(previous);
+                    }
                 }
             }
         }
@@ -84,7 +138,14 @@ impl<S> SoundBank<S> where S: OutputSample {
 
     pub fn get_frame(&self, index: SoundBankIndex) -> Option<StereoFrame<S>> {
         if let Some(sound) = &self.sounds[index.source_index] {
-            return sound.data.get(index.frame_index).copied();
+            let frame = *sound.data.get(index.frame_index)?;
+            if index.frame_fraction == 0.0 {
+                return Some(frame);
+            }
+            let next_frame = sound.data.get(index.frame_index + 1)
+                                       .copied()
+                                       .unwrap_or_default();
+            return Some(frame.lerp(next_frame, index.frame_fraction));
         }
         None
     }
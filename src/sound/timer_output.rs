@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use assert_no_alloc::*;
+use hound::WavWriter;
+
+use crate::sound::{StereoFrame, StereoFrameGenerator, Float, BounceFormat, write_frame};
+
+
+// The frame generator is moved onto the thread's stack, and a sequencer is
+// large in a debug build
+const THREAD_STACK_SIZE: usize = 8 << 20;
+
+// Drives a frame generator from its own thread at real-time pace, for when
+// there is no device to do it.  What would have been played is thrown away
+// or written to a WAV file.
+pub struct TimerOutput {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl TimerOutput {
+    pub fn start<T: 'static + StereoFrameGenerator<Float> + Send>(
+        mut frame_generator: T,
+        sample_rate: usize,
+        buffer_size: usize,
+        mut writer: Option<(WavWriter<BufWriter<File>>, BounceFormat)>
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+
+        let thread = thread::Builder::new().stack_size(THREAD_STACK_SIZE).spawn(move || {
+            let mut buffer = vec![StereoFrame::default(); buffer_size];
+            let start = Instant::now();
+            let mut frames_played: u64 = 0;
+            while !thread_stop.load(Ordering::Relaxed) {
+                assert_no_alloc(|| {
+                    for frame in buffer.iter_mut() {
+                        *frame = frame_generator.next_frame();
+                    }
+                });
+
+                // A failed write stops the recording, not the playback
+                if let Some((wav_writer, format)) = &mut writer {
+                    let result = buffer.iter().try_for_each(|&frame| write_frame(wav_writer, frame, *format));
+                    if let Err(err) = result {
+                        println!("{}", err);
+                        writer = None;
+                    }
+                }
+
+                frames_played += buffer_size as u64;
+                let due = start + Duration::from_secs_f64(frames_played as f64 / sample_rate as f64);
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+
+            if let Some((wav_writer, _)) = writer {
+                if let Err(err) = wav_writer.finalize() {
+                    println!("{}", err);
+                }
+            }
+        }).expect("could not start the timer output thread");
+
+        Self {
+            stop,
+            thread: Some(thread)
+        }
+    }
+}
+
+impl Drop for TimerOutput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
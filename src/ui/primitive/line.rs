@@ -5,7 +5,8 @@ use crate::ui::{Depth, TransformInstance, Transform};
 use crate::util::color_to_f32_array;
 
 
-const INSTANCE_BUFFER_SIZE: usize = 128;
+// Instances the buffer starts with room for, it grows to fit busier frames
+const INSTANCE_BUFFER_SIZE: usize = 1024;
 
 pub struct Line {
     pub from: (f32, f32),
@@ -69,9 +70,10 @@ impl LineInstance {
 
 pub struct LineHandler {
     render_pipeline: RenderPipeline,
-    // instances: [LineInstance; INSTANCE_BUFFER_SIZE],
     instance_buffer: Buffer,
-    instance_buffer_index: u32
+    instance_buffer_size: usize,
+    // Written this frame, uploaded all at once before rendering
+    instances: Vec<LineInstance>
 }
 
 impl LineHandler {
@@ -81,19 +83,9 @@ impl LineHandler {
         depth_stencil_state: DepthStencilState,
         multisample_state: MultisampleState
     ) -> LineHandler {
-        use wgpu::util::DeviceExt;
-
         let shader = device.create_shader_module(include_wgsl!("line.wgsl"));
 
-        let instances = [LineInstance::zeroed(); INSTANCE_BUFFER_SIZE];
-
-        let instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Line Instance Buffer"),
-                contents: cast_slice(&instances),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            }
-        );
+        let instance_buffer = create_instance_buffer(device, INSTANCE_BUFFER_SIZE);
 
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Line Render Pipeline Layout"),
@@ -136,20 +128,26 @@ impl LineHandler {
 
         Self {
             render_pipeline,
-            // instances,
             instance_buffer,
-            instance_buffer_index: 0
+            instance_buffer_size: INSTANCE_BUFFER_SIZE,
+            instances: Vec::with_capacity(INSTANCE_BUFFER_SIZE)
         }
     }
 
-    pub fn write(&mut self, line: Line, transform: Transform, queue: &Queue) {
-        let instance: LineInstance = line.instance_with_transform(transform);
-        queue.write_buffer(
-            &self.instance_buffer,
-            (self.instance_buffer_index as u64) * (std::mem::size_of::<LineInstance>() as u64),
-            cast_slice(&[instance])
-        );
-        self.instance_buffer_index += 1;
+    pub fn write(&mut self, line: Line, transform: Transform) {
+        self.instances.push(line.instance_with_transform(transform));
+    }
+
+    // Replaces the instance buffer with a larger one when this frame's
+    // instances don't fit
+    pub fn upload(&mut self, device: &Device, queue: &Queue) {
+        if self.instances.len() > self.instance_buffer_size {
+            self.instance_buffer_size = self.instances.len().next_power_of_two();
+            self.instance_buffer = create_instance_buffer(device, self.instance_buffer_size);
+        }
+        if !self.instances.is_empty() {
+            queue.write_buffer(&self.instance_buffer, 0, cast_slice(&self.instances));
+        }
     }
 
     pub fn render<'a>(&'a mut self, render_pass: &mut RenderPass<'a>) {
@@ -157,8 +155,17 @@ impl LineHandler {
         render_pass.set_vertex_buffer(0, self.instance_buffer.slice(..));
         render_pass.draw(
             0..2,
-            0..self.instance_buffer_index
+            0..self.instances.len() as u32
         );
-        self.instance_buffer_index = 0;
+        self.instances.clear();
     }
 }
+
+fn create_instance_buffer(device: &Device, size: usize) -> Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Line Instance Buffer"),
+        size: (size * std::mem::size_of::<LineInstance>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false
+    })
+}
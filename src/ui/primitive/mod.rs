@@ -83,7 +83,6 @@ impl<'r> Draw<'r> {
 
     pub fn primitive_with_transform(&mut self, primitive: Primitive, transform: Transform) {
         let Renderer {
-            queue,
             quad_handler,
             text_handler,
             line_handler,
@@ -91,13 +90,13 @@ impl<'r> Draw<'r> {
         } = self.renderer;
         match primitive {
             Primitive::Quad(quad) => {
-                quad_handler.write(quad, transform, queue)
+                quad_handler.write(quad, transform)
             },
             Primitive::Text(ref text) => {
                 text_handler.write(text, transform);
             },
             Primitive::Line(line) => {
-                line_handler.write(line, transform, queue);
+                line_handler.write(line, transform);
             },
             Primitive::Mesh => todo!(),
         }
@@ -226,6 +225,8 @@ impl Renderer {
         let mut encoder = self.device.create_command_encoder(&CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
+        self.quad_handler.upload(&self.device, &self.queue);
+        self.line_handler.upload(&self.device, &self.queue);
    
         
         let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
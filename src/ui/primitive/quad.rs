@@ -7,7 +7,8 @@ use crate::ui::primitive::Vertex;
 use crate::util::color_to_f32_array;
 
 
-pub const INSTANCE_BUFFER_SIZE: usize = 128;
+// Instances the buffer starts with room for, it grows to fit busier frames
+pub const INSTANCE_BUFFER_SIZE: usize = 1024;
 
 const QUAD_VERTICES: &[Vertex] = &[
     Vertex { position: [0.0, 0.0] },
@@ -95,9 +96,10 @@ pub struct QuadHandler {
     vertex_buffer: Buffer,
     index_buffer: Buffer,
     instance_buffer: Buffer,
+    instance_buffer_size: usize,
     render_pipeline: RenderPipeline,
-    // instances: [QuadInstance; INSTANCE_BUFFER_SIZE],
-    instance_buffer_index: u32
+    // Written this frame, uploaded all at once before rendering
+    instances: Vec<QuadInstance>
 }
 
 impl QuadHandler {
@@ -126,15 +128,7 @@ impl QuadHandler {
             }
         );
 
-        let instances = [QuadInstance::zeroed(); INSTANCE_BUFFER_SIZE];
-
-        let instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Quad Instance Buffer"),
-                contents: cast_slice(&instances),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            }
-        );
+        let instance_buffer = create_instance_buffer(device, INSTANCE_BUFFER_SIZE);
 
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Quad Pipeline Layout"),
@@ -180,20 +174,26 @@ impl QuadHandler {
             vertex_buffer,
             index_buffer,
             instance_buffer,
+            instance_buffer_size: INSTANCE_BUFFER_SIZE,
             render_pipeline,
-            // instances,
-            instance_buffer_index: 0
+            instances: Vec::with_capacity(INSTANCE_BUFFER_SIZE)
         }
     }
 
-    pub fn write(&mut self, quad: Quad, transform: Transform, queue: &Queue) {
-        let instance: QuadInstance = quad.instance_with_transform(transform);
-        queue.write_buffer(
-            &self.instance_buffer,
-            (self.instance_buffer_index as u64) * (std::mem::size_of::<QuadInstance>() as u64),
-            cast_slice(&[instance])
-        );
-        self.instance_buffer_index += 1;
+    pub fn write(&mut self, quad: Quad, transform: Transform) {
+        self.instances.push(quad.instance_with_transform(transform));
+    }
+
+    // Replaces the instance buffer with a larger one when this frame's
+    // instances don't fit
+    pub fn upload(&mut self, device: &Device, queue: &Queue) {
+        if self.instances.len() > self.instance_buffer_size {
+            self.instance_buffer_size = self.instances.len().next_power_of_two();
+            self.instance_buffer = create_instance_buffer(device, self.instance_buffer_size);
+        }
+        if !self.instances.is_empty() {
+            queue.write_buffer(&self.instance_buffer, 0, cast_slice(&self.instances));
+        }
     }
 
     pub fn render<'a>(&'a mut self, render_pass: &mut RenderPass<'a>) {
@@ -207,8 +207,17 @@ impl QuadHandler {
         render_pass.draw_indexed(
             0..NUM_QUAD_INDICES,
             0,
-            0..self.instance_buffer_index
+            0..self.instances.len() as u32
         );
-        self.instance_buffer_index = 0;
+        self.instances.clear();
     }
+}
+
+fn create_instance_buffer(device: &Device, size: usize) -> Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Quad Instance Buffer"),
+        size: (size * std::mem::size_of::<QuadInstance>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false
+    })
 }
\ No newline at end of file